@@ -0,0 +1,13 @@
+-- Per-turn metrics recorded by the orchestrator, aggregated for session stats
+CREATE TABLE conversation_turn_stats (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    session_id UUID NOT NULL,
+    latency_ms BIGINT NOT NULL,
+    prompt_tokens BIGINT NOT NULL DEFAULT 0,
+    completion_tokens BIGINT NOT NULL DEFAULT 0,
+    total_tokens BIGINT NOT NULL DEFAULT 0,
+    tool_calls INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP DEFAULT NOW()
+);
+
+CREATE INDEX idx_conversation_turn_stats_session ON conversation_turn_stats(session_id);
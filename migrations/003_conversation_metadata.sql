@@ -0,0 +1,4 @@
+-- Free-form conversation metadata; `tags` (a string array) drives retention rules
+ALTER TABLE conversations ADD COLUMN metadata JSONB NOT NULL DEFAULT '{}'::jsonb;
+
+CREATE INDEX idx_conversations_metadata_tags ON conversations USING GIN ((metadata -> 'tags'));
@@ -0,0 +1,15 @@
+-- Tool calls awaiting explicit user confirmation (human-in-the-loop)
+CREATE TABLE pending_actions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    session_id UUID NOT NULL,
+    token UUID NOT NULL UNIQUE DEFAULT gen_random_uuid(),
+    tool_name TEXT NOT NULL,
+    arguments JSONB NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    result TEXT,
+    created_at TIMESTAMP DEFAULT NOW(),
+    resolved_at TIMESTAMP
+);
+
+CREATE INDEX idx_pending_actions_session ON pending_actions(session_id);
+CREATE INDEX idx_pending_actions_created ON pending_actions(created_at);
@@ -0,0 +1,5 @@
+-- Messages queued for deferred embedding are stored with a NULL embedding
+-- until the background indexer fills them in.
+CREATE INDEX idx_conversation_embeddings_unindexed
+    ON conversation_embeddings (created_at)
+    WHERE embedding IS NULL;
@@ -0,0 +1,33 @@
+-- One conversation row per session: history is now updated in place instead
+-- of inserting a new row every turn. Collapse existing duplicates onto the
+-- most recent row, keeping the embeddings that pointed at older rows.
+WITH ranked AS (
+    SELECT id,
+           session_id,
+           FIRST_VALUE(id) OVER (
+               PARTITION BY session_id
+               ORDER BY updated_at DESC NULLS LAST, created_at DESC NULLS LAST
+           ) AS keep_id
+    FROM conversations
+)
+UPDATE conversation_embeddings ce
+SET conversation_id = ranked.keep_id
+FROM ranked
+WHERE ce.conversation_id = ranked.id
+  AND ranked.id <> ranked.keep_id;
+
+DELETE FROM conversations c
+USING (
+    SELECT id,
+           ROW_NUMBER() OVER (
+               PARTITION BY session_id
+               ORDER BY updated_at DESC NULLS LAST, created_at DESC NULLS LAST
+           ) AS rn
+    FROM conversations
+) ranked
+WHERE c.id = ranked.id
+  AND ranked.rn > 1;
+
+DROP INDEX IF EXISTS idx_conversations_session;
+ALTER TABLE conversations
+    ADD CONSTRAINT conversations_session_id_key UNIQUE (session_id);
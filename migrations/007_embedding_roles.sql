@@ -0,0 +1,4 @@
+-- Which message an embedding came from (user, assistant or tool), so RAG can
+-- filter by it. Rows stored before this column were all user messages.
+ALTER TABLE conversation_embeddings
+    ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
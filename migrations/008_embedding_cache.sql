@@ -0,0 +1,13 @@
+-- Embeddings reused across restarts and replicas, keyed by a hash of the
+-- input text. Rows older than EMBEDDING_CACHE_TTL_HOURS are ignored and
+-- periodically evicted.
+CREATE TABLE embedding_cache (
+    text_hash BYTEA NOT NULL,
+    model TEXT NOT NULL,
+    task TEXT NOT NULL,
+    embedding REAL[] NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (text_hash, model, task)
+);
+
+CREATE INDEX idx_embedding_cache_created_at ON embedding_cache (created_at);
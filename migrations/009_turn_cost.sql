@@ -0,0 +1,4 @@
+-- Dollar cost of each turn, from the configured model pricing. NULL when the
+-- model had no pricing entry.
+ALTER TABLE conversation_turn_stats
+    ADD COLUMN cost_usd DOUBLE PRECISION;
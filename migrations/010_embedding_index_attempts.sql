@@ -0,0 +1,4 @@
+-- Failed deferred-embedding attempts per message. The indexer skips messages
+-- that reached its attempt limit, so one bad input can't stall the queue.
+ALTER TABLE conversation_embeddings
+    ADD COLUMN index_attempts INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,139 @@
+use crate::error::AgentError;
+use crate::retry::RetryPolicy;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use reqwest::Client;
+use serde_json::Value;
+
+/// Supplies grounding snippets from outside the conversation store (e.g. a
+/// CRM's booking history), added to the prompt next to RAG context.
+pub trait ContextProvider: Send + Sync {
+    /// `session_metadata` is the session's stored metadata merged with the
+    /// request's, plus its `session_id`.
+    fn fetch_context<'a>(
+        &'a self,
+        session_metadata: &'a Value,
+    ) -> BoxFuture<'a, Result<Vec<String>>>;
+}
+
+/// Provides nothing; the default.
+pub struct NoopContextProvider;
+
+impl ContextProvider for NoopContextProvider {
+    fn fetch_context<'a>(
+        &'a self,
+        _session_metadata: &'a Value,
+    ) -> BoxFuture<'a, Result<Vec<String>>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+}
+
+/// GETs a URL built from a template whose `{key}` placeholders are filled
+/// from session metadata, e.g. `https://crm.example.com/customers/{customer_id}/context`.
+/// The endpoint answers with a JSON array of strings, or an object with one
+/// under `context`. Sessions lacking a placeholder's key get no context.
+pub struct HttpContextProvider {
+    client: Client,
+    url_template: String,
+    retry: RetryPolicy,
+}
+
+impl HttpContextProvider {
+    pub fn new(url_template: String) -> Self {
+        Self {
+            client: Client::new(),
+            url_template,
+            retry: RetryPolicy::new(0),
+        }
+    }
+
+    /// Uses a shared, preconfigured HTTP client (timeouts, connection pool).
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    async fn fetch(&self, session_metadata: &Value) -> Result<Vec<String>> {
+        let Some(url) = fill_template(&self.url_template, session_metadata) else {
+            return Ok(Vec::new());
+        };
+        let response = self
+            .retry
+            .send("Context provider", || self.client.get(&url))
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await?;
+            return Err(AgentError::from_upstream_status(
+                status,
+                format!("Context provider error: {}", error_text),
+            )
+            .into());
+        }
+
+        let body: Value = response.json().await?;
+        let snippets = match body {
+            Value::Object(mut object) => object.remove("context").unwrap_or(Value::Null),
+            body => body,
+        };
+        Ok(match snippets {
+            Value::Array(snippets) => snippets
+                .into_iter()
+                .filter_map(|snippet| match snippet {
+                    Value::String(text) if !text.trim().is_empty() => Some(text),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        })
+    }
+}
+
+impl ContextProvider for HttpContextProvider {
+    fn fetch_context<'a>(
+        &'a self,
+        session_metadata: &'a Value,
+    ) -> BoxFuture<'a, Result<Vec<String>>> {
+        Box::pin(self.fetch(session_metadata))
+    }
+}
+
+/// Replaces each `{key}` with the percent-encoded metadata value, or returns
+/// `None` if a key is missing or not a string, number or bool.
+fn fill_template(template: &str, metadata: &Value) -> Option<String> {
+    let mut url = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        url.push_str(&rest[..start]);
+        let value = match metadata.get(&rest[start + 1..end])? {
+            Value::String(text) => text.clone(),
+            Value::Number(number) => number.to_string(),
+            Value::Bool(flag) => flag.to_string(),
+            _ => return None,
+        };
+        url.push_str(&encode_component(&value));
+        rest = &rest[end + 1..];
+    }
+    url.push_str(rest);
+    Some(url)
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters.
+fn encode_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
@@ -1,57 +1,351 @@
+use crate::error::AgentError;
+use crate::retry::RetryPolicy;
 use anyhow::Result;
+use dashmap::DashMap;
+use lru::LruCache;
+use reqwest::header::HeaderMap;
 use reqwest::Client;
 use serde::Deserialize;
 use serde_json::json;
+use sqlx::PgPool;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::OnceCell;
+use tracing::warn;
+
+type MemoryCache = LruCache<(EmbeddingTask, String), Vec<f32>>;
+type InFlight = DashMap<(EmbeddingTask, String), Arc<OnceCell<Vec<f32>>>>;
+
+const GOOGLE_API_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// The batch endpoint refused the batch itself (malformed, too large, or
+/// answered with the wrong number of vectors), so its texts are worth
+/// retrying one by one. Other failures, such as rate limits or bad
+/// credentials, would fail those calls too.
+#[derive(Debug, Error)]
+#[error("{0}")]
+struct BatchRejected(String);
 
 #[derive(Debug, Clone)]
 pub enum EmbeddingProvider {
     Google,
 }
 
+impl EmbeddingProvider {
+    /// Maximum texts per batch request accepted by the provider.
+    pub fn default_batch_size(&self) -> usize {
+        match self {
+            EmbeddingProvider::Google => 100,
+        }
+    }
+
+    /// Length of the vectors the provider returns.
+    pub fn dimensions(&self) -> usize {
+        match self {
+            EmbeddingProvider::Google => 768,
+        }
+    }
+}
+
+/// What an embedding will be used for. Google tunes vectors differently for
+/// search queries and for the documents being searched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EmbeddingTask {
+    RetrievalQuery,
+    RetrievalDocument,
+}
+
+impl EmbeddingTask {
+    fn as_google_task_type(self) -> &'static str {
+        match self {
+            EmbeddingTask::RetrievalQuery => "RETRIEVAL_QUERY",
+            EmbeddingTask::RetrievalDocument => "RETRIEVAL_DOCUMENT",
+        }
+    }
+}
+
 pub struct EmbeddingService {
     provider: EmbeddingProvider,
     api_key: String,
     model: String,
     client: Client,
+    /// Google API root; only tests point it elsewhere.
+    base_url: String,
+    retry: RetryPolicy,
+    batch_size: usize,
+    extra_headers: HeaderMap,
+    empty_as_zero: bool,
+    /// Share one API call between concurrent requests for the same text.
+    single_flight: bool,
+    /// Embeddings currently being fetched, keyed by task and text. An entry
+    /// lives only while a call is in flight.
+    in_flight: InFlight,
+    /// Postgres table checked before calling the provider, and how long its
+    /// entries stay valid.
+    persistent_cache: Option<(PgPool, Duration)>,
+    /// Recently used embeddings, keyed by task and normalized text.
+    memory_cache: Option<Mutex<MemoryCache>>,
 }
 
 impl EmbeddingService {
     pub fn new(provider: EmbeddingProvider, api_key: String, model: String) -> Self {
         Self {
+            batch_size: provider.default_batch_size(),
             provider,
             api_key,
             model,
             client: Client::new(),
+            base_url: GOOGLE_API_BASE_URL.to_string(),
+            retry: RetryPolicy::new(0),
+            extra_headers: HeaderMap::new(),
+            empty_as_zero: false,
+            single_flight: true,
+            in_flight: DashMap::new(),
+            persistent_cache: None,
+            memory_cache: None,
+        }
+    }
+
+    /// Keep the `capacity` most recently used query embeddings in memory, so
+    /// repeated messages ("hi", "book appointment") skip the API. 0 disables.
+    pub fn with_memory_cache(mut self, capacity: usize) -> Self {
+        self.memory_cache =
+            NonZeroUsize::new(capacity).map(|capacity| Mutex::new(LruCache::new(capacity)));
+        self
+    }
+
+    /// Reuse embeddings stored in the `embedding_cache` table for up to `ttl`.
+    pub fn with_persistent_cache(mut self, pool: PgPool, ttl: Duration) -> Self {
+        self.persistent_cache = Some((pool, ttl));
+        self
+    }
+
+    /// Collapse concurrent embeddings of identical text into one API call.
+    pub fn with_single_flight(mut self, enabled: bool) -> Self {
+        self.single_flight = enabled;
+        self
+    }
+
+    /// Embed empty/whitespace input as a zero vector instead of failing.
+    pub fn with_empty_as_zero(mut self, enabled: bool) -> Self {
+        self.empty_as_zero = enabled;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_base_url(mut self, base_url: String) -> Self {
+        self.base_url = base_url;
+        self
+    }
+
+    /// Handles input the provider would reject anyway, without calling it.
+    fn empty_input_embedding(&self) -> Result<Vec<f32>> {
+        if self.empty_as_zero {
+            Ok(vec![0.0; self.provider.dimensions()])
+        } else {
+            Err(AgentError::BadRequest("Cannot embed empty text".to_string()).into())
+        }
+    }
+
+    pub fn with_extra_headers(mut self, headers: HeaderMap) -> Self {
+        self.extra_headers = headers;
+        self
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Uses a shared, preconfigured HTTP client (timeouts, connection pool).
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub async fn generate_embedding(&self, text: &str, task: EmbeddingTask) -> Result<Vec<f32>> {
+        if text.trim().is_empty() {
+            return self.empty_input_embedding();
+        }
+        let Some(memory_cache) = &self.memory_cache else {
+            return self.generate_uncached_embedding(text, task).await;
+        };
+        let key = (task, normalize_cache_key(text));
+        if let Some(embedding) = memory_cache.lock().unwrap().get(&key) {
+            return Ok(embedding.clone());
+        }
+        let embedding = self.generate_uncached_embedding(text, task).await?;
+        memory_cache.lock().unwrap().put(key, embedding.clone());
+        Ok(embedding)
+    }
+
+    async fn generate_uncached_embedding(
+        &self,
+        text: &str,
+        task: EmbeddingTask,
+    ) -> Result<Vec<f32>> {
+        if !self.single_flight {
+            return self.fetch_embedding(text, task).await;
+        }
+
+        // Callers arriving while a call for the same text is in flight wait
+        // for its result. If it fails, the next waiter makes its own call.
+        let key = (task, text.to_string());
+        let cell = self.in_flight.entry(key.clone()).or_default().clone();
+        let _cleanup = InFlightCleanup {
+            in_flight: &self.in_flight,
+            key,
+            cell: cell.clone(),
+        };
+        cell.get_or_try_init(|| self.fetch_embedding(text, task))
+            .await
+            .cloned()
+    }
+
+    async fn fetch_embedding(&self, text: &str, task: EmbeddingTask) -> Result<Vec<f32>> {
+        let texts = [text.to_string()];
+        if let Some(Some(embedding)) = self.cached_embeddings(&texts, task).await.pop() {
+            return Ok(embedding);
+        }
+
+        let embedding = match self.provider {
+            EmbeddingProvider::Google => self.generate_google_embedding(text, task).await?,
+        };
+        self.cache_embeddings(&texts, std::slice::from_ref(&embedding), task)
+            .await;
+        Ok(embedding)
+    }
+
+    /// Looks `texts` up in the persistent cache, one entry per text (`None`
+    /// on a miss). Cache errors are logged and treated as misses.
+    async fn cached_embeddings(
+        &self,
+        texts: &[String],
+        task: EmbeddingTask,
+    ) -> Vec<Option<Vec<f32>>> {
+        let mut cached = vec![None; texts.len()];
+        let Some((pool, ttl)) = &self.persistent_cache else {
+            return cached;
+        };
+
+        let rows: Result<Vec<(i64, Vec<f32>)>, _> = sqlx::query_as(
+            r#"
+            SELECT input.idx, c.embedding
+            FROM UNNEST($1::text[]) WITH ORDINALITY AS input(text, idx)
+            JOIN embedding_cache c
+              ON c.text_hash = sha256(convert_to(input.text, 'UTF8'))
+             AND c.model = $2
+             AND c.task = $3
+            WHERE c.created_at > NOW() - make_interval(secs => $4)
+            "#,
+        )
+        .bind(texts)
+        .bind(&self.model)
+        .bind(task.as_google_task_type())
+        .bind(ttl.as_secs_f64())
+        .fetch_all(pool)
+        .await;
+
+        match rows {
+            Ok(rows) => {
+                for (idx, embedding) in rows {
+                    if let Some(slot) = cached.get_mut((idx - 1) as usize) {
+                        *slot = Some(embedding);
+                    }
+                }
+            }
+            Err(e) => warn!("Embedding cache lookup failed: {}", e),
         }
+        cached
     }
 
-    pub async fn generate_embedding(&self, text: &str) -> Result<Vec<f32>> {
-        match self.provider {
-            EmbeddingProvider::Google => self.generate_google_embedding(text).await,
+    /// Stores freshly fetched embeddings in the persistent cache, if enabled.
+    async fn cache_embeddings(
+        &self,
+        texts: &[String],
+        embeddings: &[Vec<f32>],
+        task: EmbeddingTask,
+    ) {
+        let Some((pool, _)) = &self.persistent_cache else {
+            return;
+        };
+
+        for (text, embedding) in texts.iter().zip(embeddings) {
+            let result = sqlx::query(
+                r#"
+                INSERT INTO embedding_cache (text_hash, model, task, embedding)
+                VALUES (sha256(convert_to($1, 'UTF8')), $2, $3, $4)
+                ON CONFLICT (text_hash, model, task)
+                DO UPDATE SET embedding = EXCLUDED.embedding, created_at = NOW()
+                "#,
+            )
+            .bind(text)
+            .bind(&self.model)
+            .bind(task.as_google_task_type())
+            .bind(embedding)
+            .execute(pool)
+            .await;
+            if let Err(e) = result {
+                warn!("Failed to store embedding in cache: {}", e);
+                return;
+            }
         }
     }
 
-    async fn generate_google_embedding(&self, text: &str) -> Result<Vec<f32>> {
+    /// Deletes persistent cache entries older than the TTL. Returns the
+    /// number of rows removed (0 when the cache is disabled).
+    pub async fn evict_expired_cache_entries(&self) -> Result<u64> {
+        let Some((pool, ttl)) = &self.persistent_cache else {
+            return Ok(0);
+        };
+        let result = sqlx::query(
+            "DELETE FROM embedding_cache WHERE created_at <= NOW() - make_interval(secs => $1)",
+        )
+        .bind(ttl.as_secs_f64())
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn generate_google_embedding(&self, text: &str, task: EmbeddingTask) -> Result<Vec<f32>> {
         let request = json!({
             "model": self.model,
             "content": {
                 "parts": [{"text": text}]
-            }
+            },
+            "taskType": task.as_google_task_type()
         });
 
         let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:embedContent?key={}",
-            self.model, self.api_key
+            "{}/models/{}:embedContent?key={}",
+            self.base_url, self.model, self.api_key
         );
 
-        let response = self.client.post(&url).json(&request).send().await?;
+        let response = self
+            .retry
+            .send("Google Embeddings API", || {
+                self.client
+                    .post(&url)
+                    .headers(self.extra_headers.clone())
+                    .json(&request)
+            })
+            .await?;
 
         if !response.status().is_success() {
+            let status = response.status();
             let error_text = response.text().await?;
-            return Err(anyhow::anyhow!(
-                "Google Embeddings API error: {}",
-                error_text
-            ));
+            return Err(AgentError::from_upstream_status(
+                status,
+                format!("Google Embeddings API error: {}", error_text),
+            )
+            .into());
         }
 
         #[derive(Deserialize)]
@@ -67,4 +361,390 @@ impl EmbeddingService {
         let result: EmbeddingResponse = response.json().await?;
         Ok(result.embedding.values)
     }
+
+    /// Embeds many texts, splitting them into provider-sized batches. The
+    /// returned vectors are in the same order as `texts`. A batch the batch
+    /// endpoint rejects as malformed or too large is embedded one text at a
+    /// time instead; other errors are returned as they are.
+    pub async fn generate_embeddings_batch(
+        &self,
+        texts: &[String],
+        task: EmbeddingTask,
+    ) -> Result<Vec<Vec<f32>>> {
+        // Rejected up front, before any texts are sent to the provider
+        let empty_embedding = if texts.iter().any(|text| text.trim().is_empty()) {
+            Some(self.empty_input_embedding()?)
+        } else {
+            None
+        };
+        let non_empty: Vec<String> = texts
+            .iter()
+            .filter(|text| !text.trim().is_empty())
+            .cloned()
+            .collect();
+
+        // Only texts missing from the persistent cache go to the provider
+        let cached = self.cached_embeddings(&non_empty, task).await;
+        let misses: Vec<String> = non_empty
+            .iter()
+            .zip(&cached)
+            .filter(|(_, hit)| hit.is_none())
+            .map(|(text, _)| text.clone())
+            .collect();
+
+        let mut fresh = Vec::with_capacity(misses.len());
+        for chunk in misses.chunks(self.batch_size) {
+            let batched = match self.provider {
+                EmbeddingProvider::Google => {
+                    self.generate_google_embeddings_batch(chunk, task).await
+                }
+            };
+            let chunk_embeddings = match batched {
+                Ok(chunk_embeddings) => chunk_embeddings,
+                Err(e) if e.is::<BatchRejected>() => {
+                    warn!(
+                        "Batch embedding of {} texts failed, embedding them one by one: {}",
+                        chunk.len(),
+                        e
+                    );
+                    let mut chunk_embeddings = Vec::with_capacity(chunk.len());
+                    for text in chunk {
+                        chunk_embeddings.push(match self.provider {
+                            EmbeddingProvider::Google => {
+                                self.generate_google_embedding(text, task).await?
+                            }
+                        });
+                    }
+                    chunk_embeddings
+                }
+                Err(e) => return Err(e),
+            };
+            self.cache_embeddings(chunk, &chunk_embeddings, task).await;
+            fresh.extend(chunk_embeddings);
+        }
+
+        let mut fresh = fresh.into_iter();
+        let fetched: Vec<Vec<f32>> = cached
+            .into_iter()
+            .filter_map(|hit| hit.or_else(|| fresh.next()))
+            .collect();
+
+        let mut fetched = fetched.into_iter();
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for text in texts {
+            if text.trim().is_empty() {
+                embeddings.extend(empty_embedding.clone());
+            } else if let Some(embedding) = fetched.next() {
+                embeddings.push(embedding);
+            }
+        }
+        Ok(embeddings)
+    }
+
+    async fn generate_google_embeddings_batch(
+        &self,
+        texts: &[String],
+        task: EmbeddingTask,
+    ) -> Result<Vec<Vec<f32>>> {
+        let requests: Vec<serde_json::Value> = texts
+            .iter()
+            .map(|text| {
+                json!({
+                    "model": format!("models/{}", self.model),
+                    "content": {
+                        "parts": [{"text": text}]
+                    },
+                    "taskType": task.as_google_task_type()
+                })
+            })
+            .collect();
+        let request = json!({ "requests": requests });
+
+        let url = format!(
+            "{}/models/{}:batchEmbedContents?key={}",
+            self.base_url, self.model, self.api_key
+        );
+
+        let response = self
+            .retry
+            .send("Google Embeddings API", || {
+                self.client
+                    .post(&url)
+                    .headers(self.extra_headers.clone())
+                    .json(&request)
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await?;
+            let message = format!("Google Embeddings API error: {}", error_text);
+            if matches!(
+                status,
+                reqwest::StatusCode::BAD_REQUEST
+                    | reqwest::StatusCode::PAYLOAD_TOO_LARGE
+                    | reqwest::StatusCode::UNPROCESSABLE_ENTITY
+            ) {
+                return Err(BatchRejected(message).into());
+            }
+            return Err(AgentError::from_upstream_status(status, message).into());
+        }
+
+        #[derive(Deserialize)]
+        struct BatchEmbeddingResponse {
+            embeddings: Vec<EmbeddingData>,
+        }
+
+        #[derive(Deserialize)]
+        struct EmbeddingData {
+            values: Vec<f32>,
+        }
+
+        let result: BatchEmbeddingResponse = response.json().await?;
+        if result.embeddings.len() != texts.len() {
+            return Err(BatchRejected(format!(
+                "Google Embeddings API returned {} embeddings for {} texts",
+                result.embeddings.len(),
+                texts.len()
+            ))
+            .into());
+        }
+        Ok(result.embeddings.into_iter().map(|e| e.values).collect())
+    }
+}
+
+/// Removes a single-flight entry on drop, including when the caller that
+/// started the call is cancelled, unless a newer call replaced it.
+struct InFlightCleanup<'a> {
+    in_flight: &'a InFlight,
+    key: (EmbeddingTask, String),
+    cell: Arc<OnceCell<Vec<f32>>>,
+}
+
+impl Drop for InFlightCleanup<'_> {
+    fn drop(&mut self) {
+        self.in_flight
+            .remove_if(&self.key, |_, current| Arc::ptr_eq(current, &self.cell));
+    }
+}
+
+/// In-memory cache key: surrounding whitespace trimmed and inner runs of
+/// whitespace collapsed, so trivially different spellings share an entry.
+fn normalize_cache_key(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serve;
+    use axum::http::{StatusCode, Uri};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type Responder =
+        dyn Fn(&str, &serde_json::Value) -> (StatusCode, serde_json::Value) + Send + Sync;
+
+    /// Serves `respond(path, body)` for every request on a local port and
+    /// counts the requests.
+    async fn mock_provider(respond: Box<Responder>) -> (String, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let respond: Arc<Responder> = respond.into();
+        let app = axum::Router::new().fallback(
+            move |uri: Uri, axum::Json(body): axum::Json<serde_json::Value>| {
+                let counter = counter.clone();
+                let respond = respond.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    // Long enough for concurrent callers to pile up
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    let (status, body) = respond(uri.path(), &body);
+                    (status, axum::Json(body))
+                }
+            },
+        );
+        (serve(app).await, calls)
+    }
+
+    fn service(base_url: String) -> EmbeddingService {
+        EmbeddingService::new(
+            EmbeddingProvider::Google,
+            "test-key".to_string(),
+            "text-embedding-004".to_string(),
+        )
+        .with_base_url(base_url)
+    }
+
+    fn embedding_body() -> serde_json::Value {
+        json!({ "embedding": { "values": [0.5, 0.5] } })
+    }
+
+    #[tokio::test]
+    async fn concurrent_identical_requests_share_one_call() {
+        let (base_url, calls) =
+            mock_provider(Box::new(|_, _| (StatusCode::OK, embedding_body()))).await;
+        let service = service(base_url);
+
+        let requests =
+            (0..8).map(|_| service.generate_embedding("hello", EmbeddingTask::RetrievalQuery));
+        let results = futures::future::join_all(requests).await;
+        for result in results {
+            assert_eq!(result.unwrap(), vec![0.5, 0.5]);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(service.in_flight.is_empty());
+    }
+
+    /// A batch response whose vectors encode each input's length, so results
+    /// can be matched back to their texts.
+    fn batch_body(request: &serde_json::Value) -> serde_json::Value {
+        let embeddings: Vec<serde_json::Value> = request["requests"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| {
+                let text = item["content"]["parts"][0]["text"].as_str().unwrap();
+                json!({ "values": [text.len() as f32] })
+            })
+            .collect();
+        json!({ "embeddings": embeddings })
+    }
+
+    #[tokio::test]
+    async fn large_batches_are_split_and_reassembled_in_order() {
+        let (base_url, calls) = mock_provider(Box::new(|path, body| {
+            assert!(path.ends_with(":batchEmbedContents"));
+            assert!(body["requests"].as_array().unwrap().len() <= 3);
+            (StatusCode::OK, batch_body(body))
+        }))
+        .await;
+        let service = service(base_url).with_batch_size(3);
+
+        let texts: Vec<String> = (1..=7).map(|n| "x".repeat(n)).collect();
+        let embeddings = service
+            .generate_embeddings_batch(&texts, EmbeddingTask::RetrievalDocument)
+            .await
+            .unwrap();
+        let lengths: Vec<f32> = embeddings.iter().map(|e| e[0]).collect();
+        assert_eq!(lengths, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn rejected_batches_fall_back_to_single_texts() {
+        let (base_url, calls) = mock_provider(Box::new(|path, _| {
+            if path.ends_with(":batchEmbedContents") {
+                (
+                    StatusCode::BAD_REQUEST,
+                    json!({"error": {"message": "bad batch"}}),
+                )
+            } else {
+                (StatusCode::OK, embedding_body())
+            }
+        }))
+        .await;
+        let service = service(base_url);
+
+        let texts = vec!["a".to_string(), "b".to_string()];
+        let embeddings = service
+            .generate_embeddings_batch(&texts, EmbeddingTask::RetrievalDocument)
+            .await
+            .unwrap();
+        assert_eq!(embeddings.len(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_batches_are_not_retried_per_text() {
+        let (base_url, calls) = mock_provider(Box::new(|_, _| {
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({"error": {"message": "quota"}}),
+            )
+        }))
+        .await;
+        let service = service(base_url);
+
+        let texts = vec!["a".to_string(), "b".to_string()];
+        let error = service
+            .generate_embeddings_batch(&texts, EmbeddingTask::RetrievalDocument)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<AgentError>(),
+            Some(AgentError::RateLimited(_))
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_batch_input_is_rejected_before_calling_the_api() {
+        let (base_url, calls) =
+            mock_provider(Box::new(|_, body| (StatusCode::OK, batch_body(body)))).await;
+
+        let texts = vec!["a".to_string(), "  ".to_string()];
+        let error = service(base_url.clone())
+            .generate_embeddings_batch(&texts, EmbeddingTask::RetrievalDocument)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<AgentError>(),
+            Some(AgentError::BadRequest(_))
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        let embeddings = service(base_url)
+            .with_empty_as_zero(true)
+            .generate_embeddings_batch(&texts, EmbeddingTask::RetrievalDocument)
+            .await
+            .unwrap();
+        assert_eq!(embeddings[0], vec![1.0]);
+        assert_eq!(embeddings[1], vec![0.0; 768]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn memory_cache_answers_repeated_text() {
+        let (base_url, calls) =
+            mock_provider(Box::new(|_, _| (StatusCode::OK, embedding_body()))).await;
+        let service = service(base_url).with_memory_cache(10);
+
+        let first = service
+            .generate_embedding("Book an appointment", EmbeddingTask::RetrievalQuery)
+            .await
+            .unwrap();
+        // Same text up to whitespace
+        let second = service
+            .generate_embedding("  Book an   appointment ", EmbeddingTask::RetrievalQuery)
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Document embeddings are tuned differently, so they aren't shared
+        service
+            .generate_embedding("Book an appointment", EmbeddingTask::RetrievalDocument)
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn cancelled_leader_leaves_no_in_flight_entry() {
+        let (base_url, _) =
+            mock_provider(Box::new(|_, _| (StatusCode::OK, embedding_body()))).await;
+        let service = service(base_url);
+
+        let leader = service.generate_embedding("hello", EmbeddingTask::RetrievalQuery);
+        assert!(tokio::time::timeout(Duration::from_millis(10), leader)
+            .await
+            .is_err());
+        assert!(service.in_flight.is_empty());
+
+        service
+            .generate_embedding("hello", EmbeddingTask::RetrievalQuery)
+            .await
+            .unwrap();
+        assert!(service.in_flight.is_empty());
+    }
 }
@@ -0,0 +1,78 @@
+use crate::agent::{EmbeddingService, EmbeddingTask};
+use crate::vector::VectorService;
+use anyhow::Result;
+use tracing::warn;
+
+/// Embeds messages that were stored without an embedding (deferred
+/// embedding, session imports), in batches, off the request path.
+pub struct EmbeddingIndexer {
+    vector_service: VectorService,
+    embedding_service: EmbeddingService,
+    batch_size: usize,
+    /// Failed attempts after which a message is left unindexed.
+    max_attempts: u32,
+}
+
+impl EmbeddingIndexer {
+    pub fn new(
+        vector_service: VectorService,
+        embedding_service: EmbeddingService,
+        batch_size: usize,
+    ) -> Self {
+        Self {
+            vector_service,
+            embedding_service,
+            batch_size: batch_size.max(1),
+            max_attempts: 5,
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Embeds up to one batch of queued messages. Returns how many were
+    /// indexed; 0 means the queue is empty, or indexing is paused because
+    /// embeddings no longer fit the stored vector dimension. A pause lasts
+    /// until the column is reindexed to a matching dimension.
+    pub async fn run_once(&self) -> Result<usize> {
+        if self.vector_service.reindex_needed() && self.vector_service.recheck_dimensions().await? {
+            return Ok(0);
+        }
+        let pending = self
+            .vector_service
+            .unindexed_embeddings(self.batch_size, self.max_attempts)
+            .await?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let texts: Vec<String> = pending.iter().map(|(_, text)| text.clone()).collect();
+        let embeddings = match self
+            .embedding_service
+            .generate_embeddings_batch(&texts, EmbeddingTask::RetrievalDocument)
+            .await
+        {
+            Ok(embeddings) => embeddings,
+            Err(e) => {
+                let ids: Vec<String> = pending.iter().map(|(id, _)| id.clone()).collect();
+                let exhausted = self
+                    .vector_service
+                    .record_index_failure(&ids, self.max_attempts)
+                    .await?;
+                if exhausted > 0 {
+                    warn!(
+                        "Giving up on {} deferred embeddings after {} failed attempts",
+                        exhausted, self.max_attempts
+                    );
+                }
+                return Err(e);
+            }
+        };
+        for ((id, _), embedding) in pending.iter().zip(&embeddings) {
+            self.vector_service.set_embedding(id, embedding).await?;
+        }
+        Ok(embeddings.len())
+    }
+}
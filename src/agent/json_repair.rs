@@ -0,0 +1,106 @@
+use serde_json::Value;
+
+/// Parses model output that should be JSON, fixing the usual near misses:
+/// surrounding prose or markdown fences, and trailing commas. Returns `None`
+/// when the text can't be salvaged.
+pub fn repair_json(text: &str) -> Option<Value> {
+    if let Ok(value) = serde_json::from_str(text.trim()) {
+        return Some(value);
+    }
+
+    let start = text.find(['{', '['])?;
+    let end = text.rfind(['}', ']'])?;
+    if end < start {
+        return None;
+    }
+    let candidate = strip_trailing_commas(&text[start..=end]);
+    serde_json::from_str(&candidate).ok()
+}
+
+/// Removes commas directly followed (ignoring whitespace) by `}` or `]`,
+/// leaving string contents untouched.
+fn strip_trailing_commas(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, &c) in chars.iter().enumerate() {
+        if in_string {
+            out.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_string = true;
+                out.push(c);
+            }
+            ',' => {
+                let next = chars[i + 1..].iter().find(|c| !c.is_whitespace());
+                if !matches!(next, Some('}') | Some(']')) {
+                    out.push(c);
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn valid_json_is_parsed_as_is() {
+        assert_eq!(repair_json(" [1, 2] \n"), Some(json!([1, 2])));
+    }
+
+    #[test]
+    fn fenced_output_is_unwrapped() {
+        let text = "```json\n{\"salon\": \"Aura\", \"slots\": [\"10:00\"]}\n```";
+        assert_eq!(
+            repair_json(text),
+            Some(json!({"salon": "Aura", "slots": ["10:00"]}))
+        );
+    }
+
+    #[test]
+    fn prose_around_the_json_is_dropped() {
+        let text = "Here is the booking: {\"id\": 7} Let me know if that works.";
+        assert_eq!(repair_json(text), Some(json!({"id": 7})));
+    }
+
+    #[test]
+    fn trailing_commas_are_removed() {
+        let text = "{\"a\": [1, 2, ], \"b\": {\"c\": true,\n},\n}";
+        assert_eq!(
+            repair_json(text),
+            Some(json!({"a": [1, 2], "b": {"c": true}}))
+        );
+    }
+
+    #[test]
+    fn commas_and_escaped_quotes_inside_strings_are_kept() {
+        let text = r#"Sure: {"note": "say \"hi\", }", "tags": ["a,]", ],}"#;
+        assert_eq!(
+            repair_json(text),
+            Some(json!({"note": "say \"hi\", }", "tags": ["a,]"]}))
+        );
+    }
+
+    #[test]
+    fn closing_bracket_before_opening_one_is_unsalvageable() {
+        assert_eq!(repair_json("} text {"), None);
+        assert_eq!(repair_json("no json here"), None);
+    }
+}
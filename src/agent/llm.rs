@@ -1,23 +1,96 @@
+use crate::agent::{SchemaSanitizer, ToolResultShaper};
+use crate::error::AgentError;
 use crate::mcp::{McpClient, McpTool};
-use crate::models::ChatMessage;
+use crate::models::{ChatMessage, FunctionCall, TokenUsage, ToolCall, ToolInvocation};
+use crate::retry::RetryPolicy;
 use anyhow::{anyhow, Result};
-use reqwest::Client;
+use futures::stream::{self, StreamExt};
+use reqwest::header::HeaderMap;
+use reqwest::{Client, RequestBuilder};
 use serde::Deserialize;
 use serde_json::json;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+/// Receives answer text as it is streamed.
+pub type ChunkSink = dyn Fn(&str) + Send + Sync;
 
 #[derive(Debug, Clone)]
 pub enum LlmProvider {
     Groq,
     Google,
+    OpenAi,
+    Claude,
+}
+
+const ANTHROPIC_API_URL: &str = "https://api.anthropic.com/v1";
+/// `anthropic-version` header sent with every Messages API request.
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Final output of a chat turn, plus what it took to produce it.
+#[derive(Debug, Clone, Default)]
+pub struct LlmResponse {
+    pub content: String,
+    pub usage: TokenUsage,
+    pub tool_calls: u32,
+    /// Calls to tools that require user confirmation; not executed.
+    pub deferred_calls: Vec<FunctionCall>,
+    /// Names of the tools offered to the model.
+    pub available_tools: Vec<String>,
+    /// Names of the tools the model called, in order (including deferred
+    /// and unknown ones).
+    pub called_tools: Vec<ToolInvocation>,
+    /// What the tools that reached the MCP server returned, in call order.
+    pub tool_results: Vec<String>,
+    /// Wall-clock time spent executing tool calls, across all rounds.
+    pub tool_time: Duration,
+}
+
+/// Per-request overrides of the client's configured behaviour.
+#[derive(Debug, Clone, Default)]
+pub struct GenerationOptions {
+    /// Groq `reasoning_format` (`parsed`, `hidden` or `raw`).
+    pub reasoning_format: Option<String>,
+    /// Overrides whether the model must call a tool before answering.
+    pub require_tool: Option<bool>,
 }
 
 pub struct LlmClient {
     provider: LlmProvider,
     api_key: String,
     model: String,
+    /// Overrides the Groq/OpenAI API base URL.
+    base_url: Option<String>,
     client: Client,
+    /// Used for streamed answers; has no total timeout.
+    stream_client: Client,
+    stream_idle_timeout: Duration,
     temperature: f32,
     max_tokens: u32,
+    extra_params: serde_json::Value,
+    out_of_scope_instruction: Option<String>,
+    tool_result_shaper: ToolResultShaper,
+    schema_sanitizer: SchemaSanitizer,
+    retry: RetryPolicy,
+    recover_unknown_tools: bool,
+    extra_headers: HeaderMap,
+    confirm_tools: HashSet<String>,
+    max_concurrent_tool_calls: usize,
+    /// Tool rounds allowed per turn before the model is cut off.
+    max_tool_iterations: usize,
+    /// Serialized request body size above which history is dropped before
+    /// sending; `None` sends bodies as they are.
+    max_request_bytes: Option<usize>,
+    reasoning_format: Option<String>,
+    /// Force at least one tool call per turn (`tool_choice: required`).
+    require_tool: bool,
+    /// System note used to answer without tools while MCP is unreachable;
+    /// `None` fails the request instead.
+    mcp_degraded_note: Option<String>,
+    /// Model and token limit for `summarize`; `None` uses the chat settings.
+    summary_model: Option<String>,
+    summary_max_tokens: Option<u32>,
 }
 
 impl LlmClient {
@@ -32,34 +105,704 @@ impl LlmClient {
             provider,
             api_key,
             model,
+            base_url: None,
             client: Client::new(),
+            stream_client: Client::new(),
+            stream_idle_timeout: Duration::from_secs(30),
             temperature,
             max_tokens,
+            extra_params: json!({}),
+            out_of_scope_instruction: None,
+            tool_result_shaper: ToolResultShaper::default(),
+            schema_sanitizer: SchemaSanitizer::default(),
+            retry: RetryPolicy::new(0),
+            recover_unknown_tools: true,
+            extra_headers: HeaderMap::new(),
+            confirm_tools: HashSet::new(),
+            max_concurrent_tool_calls: 4,
+            max_tool_iterations: 10,
+            max_request_bytes: None,
+            reasoning_format: None,
+            require_tool: false,
+            mcp_degraded_note: None,
+            summary_model: None,
+            summary_max_tokens: None,
+        }
+    }
+
+    /// Overrides the model and `max_tokens` used for history summaries, e.g.
+    /// to use a cheaper model.
+    pub fn with_summary_settings(mut self, model: Option<String>, max_tokens: Option<u32>) -> Self {
+        self.summary_model = model;
+        self.summary_max_tokens = max_tokens;
+        self
+    }
+
+    /// Answers as a plain chatbot, prefixed with `note`, when the MCP tool
+    /// list can't be fetched.
+    pub fn with_mcp_degraded_mode(mut self, note: Option<String>) -> Self {
+        self.mcp_degraded_note = note;
+        self
+    }
+
+    pub fn mcp_degraded_mode(&self) -> bool {
+        self.mcp_degraded_note.is_some()
+    }
+
+    /// Makes every turn call at least one tool before answering, unless a
+    /// request overrides it.
+    pub fn with_tool_required(mut self, required: bool) -> Self {
+        self.require_tool = required;
+        self
+    }
+
+    fn tool_required(&self, options: &GenerationOptions) -> bool {
+        options.require_tool.unwrap_or(self.require_tool)
+    }
+
+    /// `tool_choice` for the next OpenAI-format round: `required` until the
+    /// first tool call of a turn that must use one, then `auto` so the model
+    /// can answer with the results.
+    fn openai_tool_choice(
+        &self,
+        options: &GenerationOptions,
+        output: &LlmResponse,
+    ) -> &'static str {
+        if self.tool_required(options) && output.tool_calls == 0 {
+            "required"
+        } else {
+            "auto"
+        }
+    }
+
+    /// Gemini's equivalent of `openai_tool_choice`: function calling mode
+    /// `ANY` forces a call, `AUTO` lets the model decide.
+    fn gemini_tool_config(
+        &self,
+        options: &GenerationOptions,
+        output: &LlmResponse,
+    ) -> serde_json::Value {
+        let mode = if self.tool_required(options) && output.tool_calls == 0 {
+            "ANY"
+        } else {
+            "AUTO"
+        };
+        json!({"functionCallingConfig": {"mode": mode}})
+    }
+
+    /// Fails a turn that must use a tool but was answered without one, which
+    /// some models still do despite `tool_choice`.
+    fn ensure_tool_called(&self, options: &GenerationOptions, output: &LlmResponse) -> Result<()> {
+        if self.tool_required(options) && output.tool_calls == 0 {
+            return Err(AgentError::Upstream(format!(
+                "Model {} answered without calling a tool although tool_choice is required",
+                self.model
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Default Groq `reasoning_format`; only sent to reasoning models.
+    pub fn with_reasoning_format(mut self, reasoning_format: Option<String>) -> Self {
+        self.reasoning_format = reasoning_format;
+        self
+    }
+
+    /// Sets `reasoning_format` on a Groq request body when the model supports
+    /// it; other models reject the parameter.
+    fn apply_reasoning_format(&self, request: &mut serde_json::Value, options: &GenerationOptions) {
+        if !matches!(self.provider, LlmProvider::Groq)
+            || !request["model"]
+                .as_str()
+                .is_some_and(is_groq_reasoning_model)
+        {
+            return;
+        }
+        if let Some(format) = options
+            .reasoning_format
+            .as_ref()
+            .or(self.reasoning_format.as_ref())
+        {
+            request["reasoning_format"] = json!(format);
+        }
+    }
+
+    pub fn provider_name(&self) -> &'static str {
+        match self.provider {
+            LlmProvider::Groq => "groq",
+            LlmProvider::Google => "google",
+            LlmProvider::OpenAi => "openai",
+            LlmProvider::Claude => "claude",
+        }
+    }
+
+    /// Points the OpenAI-compatible path (Groq and OpenAI) at another
+    /// server, e.g. Ollama at `http://localhost:11434/v1`.
+    pub fn with_base_url(mut self, base_url: Option<String>) -> Self {
+        self.base_url = base_url.map(|url| url.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Display name and API base URL of the OpenAI-compatible providers
+    /// (Groq and OpenAI), which share one request format.
+    fn openai_base_url(&self) -> (&'static str, &str) {
+        let (provider, default_url) = match self.provider {
+            LlmProvider::OpenAi => ("OpenAI", "https://api.openai.com/v1"),
+            LlmProvider::Groq => ("Groq", "https://api.groq.com/openai/v1"),
+            LlmProvider::Google | LlmProvider::Claude => {
+                unreachable!("{:?} doesn't use the OpenAI request format", self.provider)
+            }
+        };
+        (provider, self.base_url.as_deref().unwrap_or(default_url))
+    }
+
+    fn chat_completions_endpoint(&self) -> (&'static str, String) {
+        let (provider, base_url) = self.openai_base_url();
+        (provider, format!("{}/chat/completions", base_url))
+    }
+
+    /// Adds the bearer token; local servers configured without a key get
+    /// no `Authorization` header.
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        if self.api_key.is_empty() {
+            request
+        } else {
+            request.bearer_auth(&self.api_key)
+        }
+    }
+
+    /// Anthropic authenticates with `x-api-key` and pins the API version.
+    fn anthropic_request(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+    }
+
+    /// Anthropic's equivalent of `openai_tool_choice`.
+    fn claude_tool_choice(
+        &self,
+        options: &GenerationOptions,
+        output: &LlmResponse,
+    ) -> serde_json::Value {
+        if self.tool_required(options) && output.tool_calls == 0 {
+            json!({"type": "any"})
+        } else {
+            json!({"type": "auto"})
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Caps how many rounds of tool calls one turn may run, so a model that
+    /// keeps requesting tools can't hold the request open forever.
+    pub fn with_max_tool_iterations(mut self, max: usize) -> Self {
+        self.max_tool_iterations = max;
+        self
+    }
+
+    /// Counts a round of tool calls, failing once the model asks for more
+    /// than `max_tool_iterations`.
+    fn next_tool_round(&self, rounds: &mut usize) -> Result<()> {
+        *rounds += 1;
+        if *rounds > self.max_tool_iterations {
+            return Err(AgentError::Upstream(format!(
+                "Model was still requesting tools after {} rounds \
+                 (LLM_MAX_TOOL_ITERATIONS); giving up",
+                self.max_tool_iterations
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Shrinks chat request bodies larger than `max_bytes` before sending,
+    /// instead of letting the provider reject them after tools already ran.
+    pub fn with_max_request_bytes(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_request_bytes = max_bytes;
+        self
+    }
+
+    /// Caps how many tool calls from one model round run at the same time.
+    pub fn with_max_concurrent_tool_calls(mut self, max: usize) -> Self {
+        self.max_concurrent_tool_calls = max.max(1);
+        self
+    }
+
+    /// Tools that are never executed directly; calls to them are returned as
+    /// `deferred_calls` for the caller to confirm with the user.
+    pub fn with_confirmation_required(mut self, tools: HashSet<String>) -> Self {
+        self.confirm_tools = tools;
+        self
+    }
+
+    pub fn with_extra_headers(mut self, headers: HeaderMap) -> Self {
+        self.extra_headers = headers;
+        self
+    }
+
+    pub fn with_unknown_tool_recovery(mut self, enabled: bool) -> Self {
+        self.recover_unknown_tools = enabled;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Uses a shared, preconfigured HTTP client (timeouts, connection pool).
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Client for streamed answers, which must not have a total request
+    /// timeout; instead the stream fails once no data arrives for
+    /// `idle_timeout`.
+    pub fn with_streaming_http_client(mut self, client: Client, idle_timeout: Duration) -> Self {
+        self.stream_client = client;
+        self.stream_idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Simplifies tool input schemas before they are sent to the model.
+    pub fn with_schema_sanitizer(mut self, sanitizer: SchemaSanitizer) -> Self {
+        self.schema_sanitizer = sanitizer;
+        self
+    }
+
+    pub fn with_tool_result_shaper(mut self, shaper: ToolResultShaper) -> Self {
+        self.tool_result_shaper = shaper;
+        self
+    }
+
+    pub fn with_out_of_scope_instruction(mut self, instruction: Option<String>) -> Self {
+        self.out_of_scope_instruction = instruction;
+        self
+    }
+
+    pub fn with_extra_params(mut self, extra_params: serde_json::Value) -> Self {
+        self.extra_params = extra_params;
+        self
+    }
+
+    /// Builds the error for a non-success provider response. Rejections of the
+    /// configured model are logged loudly, since they persist until the
+    /// configuration changes.
+    fn api_error(
+        &self,
+        provider: &str,
+        model: &str,
+        status: reqwest::StatusCode,
+        error_text: String,
+    ) -> anyhow::Error {
+        if is_content_filter_error(&error_text) {
+            return AgentError::ContentFiltered(format!(
+                "{} content filter blocked the request: {}",
+                provider, error_text
+            ))
+            .into();
+        }
+        if is_model_unavailable(status, &error_text) {
+            error!(
+                "{} rejected LLM model '{}' (not found, decommissioned or deprecated); \
+                 update the model setting: {}",
+                provider, model, error_text
+            );
+            return AgentError::ModelUnavailable(format!(
+                "{} model '{}' is unavailable: {}",
+                provider, model, error_text
+            ))
+            .into();
+        }
+        AgentError::from_upstream_status(status, format!("{} API error: {}", provider, error_text))
+            .into()
+    }
+
+    /// Checks that the provider still serves the configured model.
+    pub async fn preflight(&self) -> Result<()> {
+        let (provider, request) = match self.provider {
+            LlmProvider::Groq | LlmProvider::OpenAi => {
+                let (provider, base_url) = self.openai_base_url();
+                (
+                    provider,
+                    self.authorize(
+                        self.client
+                            .get(format!("{}/models/{}", base_url, self.model)),
+                    ),
+                )
+            }
+            LlmProvider::Google => (
+                "Google",
+                self.client.get(format!(
+                    "https://generativelanguage.googleapis.com/v1beta/models/{}?key={}",
+                    self.model, self.api_key
+                )),
+            ),
+            LlmProvider::Claude => (
+                "Anthropic",
+                self.anthropic_request(
+                    self.client
+                        .get(format!("{}/models/{}", ANTHROPIC_API_URL, self.model)),
+                ),
+            ),
+        };
+
+        let response = request.headers(self.extra_headers.clone()).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await?;
+            return Err(self.api_error(provider, &self.model, status, error_text));
         }
+        Ok(())
+    }
+
+    /// Merges the configured extra params into a request body. Applied last so
+    /// user-supplied values override the defaults we set.
+    fn apply_extra_params(&self, request: &mut serde_json::Value) {
+        if let (Some(body), Some(extra)) = (request.as_object_mut(), self.extra_params.as_object())
+        {
+            for (key, value) in extra {
+                body.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    /// Shrinks a chat request body that exceeds `max_request_bytes`. First
+    /// the oldest history entries under `history_key` are dropped, keeping
+    /// the first `pinned` (system prompts) and everything from the latest
+    /// user message on; tool results go together with the call they answer.
+    /// If that isn't enough, descriptions inside tool parameter schemas are
+    /// removed. A body still too large is sent anyway.
+    fn fit_request_size(&self, request: &mut serde_json::Value, history_key: &str, pinned: usize) {
+        let Some(limit) = self.max_request_bytes else {
+            return;
+        };
+        let original = json_size(request);
+        if original <= limit {
+            return;
+        }
+
+        let mut size = original;
+        let mut dropped = 0;
+        if let Some(history) = request[history_key].as_array_mut() {
+            let current_turn = history
+                .iter()
+                .rposition(|entry| entry["role"] == "user" && !is_tool_result_entry(entry))
+                .unwrap_or(history.len());
+            let mut end = pinned.min(current_turn);
+            while end < current_turn && (size > limit || is_tool_result_entry(&history[end])) {
+                size -= json_size(&history[end]) + 1;
+                end += 1;
+            }
+            dropped = end.saturating_sub(pinned);
+            history.drain(pinned.min(end)..end);
+        }
+
+        let mut stripped_schemas = false;
+        if size > limit {
+            strip_parameter_descriptions(&mut request["tools"], false);
+            size = json_size(request);
+            stripped_schemas = true;
+        }
+
+        warn!(
+            "LLM request body of {} bytes exceeds LLM_MAX_REQUEST_BYTES ({}): dropped {} \
+             history entries{}, now {} bytes",
+            original,
+            limit,
+            dropped,
+            if stripped_schemas {
+                " and tool parameter descriptions"
+            } else {
+                ""
+            },
+            size
+        );
     }
 
     pub async fn generate_with_mcp_tools(
         &self,
         messages: &[ChatMessage],
         mcp_client: &McpClient,
-    ) -> Result<String> {
+        options: &GenerationOptions,
+    ) -> Result<LlmResponse> {
+        self.generate(messages, mcp_client, options, None).await
+    }
+
+    /// Like `generate_with_mcp_tools`, but streams answer text to `on_chunk`
+    /// as the provider produces it. Tool rounds still run here; any text the
+    /// model writes alongside a tool call is streamed too, while the returned
+    /// `content` holds only the final answer.
+    pub async fn generate_with_mcp_tools_stream(
+        &self,
+        messages: &[ChatMessage],
+        mcp_client: &McpClient,
+        options: &GenerationOptions,
+        on_chunk: &ChunkSink,
+    ) -> Result<LlmResponse> {
+        self.generate(messages, mcp_client, options, Some(on_chunk))
+            .await
+    }
+
+    async fn generate(
+        &self,
+        messages: &[ChatMessage],
+        mcp_client: &McpClient,
+        options: &GenerationOptions,
+        on_chunk: Option<&ChunkSink>,
+    ) -> Result<LlmResponse> {
         // 1. Get available tools from MCP
-        let tools = mcp_client.list_tools().await?;
+        let tools = match mcp_client.cached_tools().await {
+            Ok(tools) => tools,
+            Err(e) => match &self.mcp_degraded_note {
+                // An answer without tools would defeat tool_choice required
+                Some(note) if !self.tool_required(options) => {
+                    warn!("MCP unavailable, answering without tools: {}", e);
+                    let mut messages = messages.to_vec();
+                    messages.insert(
+                        0,
+                        ChatMessage {
+                            role: "system".to_string(),
+                            content: note.clone(),
+                            tool_calls: None,
+                            tool_call_id: None,
+                        },
+                    );
+                    let (content, usage) = self
+                        .complete(&messages, &self.model, self.max_tokens)
+                        .await?;
+                    if let Some(on_chunk) = on_chunk {
+                        on_chunk(&content);
+                    }
+                    return Ok(LlmResponse {
+                        content,
+                        usage,
+                        ..LlmResponse::default()
+                    });
+                }
+                _ => return Err(e),
+            },
+        };
 
         // 2. Convert MCP tools to LLM function format
         let functions = self.convert_mcp_tools_to_functions(&tools);
 
-        // 3. Send to LLM with function calling
-        match self.provider {
-            LlmProvider::Groq => {
-                self.call_groq_with_functions(messages, &functions, mcp_client)
+        // 3. Optionally tell the model how to handle requests no tool covers
+        let mut messages = messages.to_vec();
+        if let Some(instruction) = &self.out_of_scope_instruction {
+            messages.insert(0, Self::capabilities_message(instruction, &tools));
+        }
+
+        // 4. Send to LLM with function calling
+        let mut response = match (&self.provider, on_chunk) {
+            (LlmProvider::Groq | LlmProvider::OpenAi, None) => {
+                self.call_openai_with_functions(&messages, &functions, &tools, mcp_client, options)
                     .await
             }
-            LlmProvider::Google => {
-                self.call_google_with_functions(messages, &functions, mcp_client)
+            (LlmProvider::Groq | LlmProvider::OpenAi, Some(on_chunk)) => {
+                self.stream_openai_with_functions(
+                    &messages, &functions, &tools, mcp_client, options, on_chunk,
+                )
+                .await
+            }
+            (LlmProvider::Google, None) => {
+                self.call_google_with_functions(&messages, &functions, &tools, mcp_client, options)
                     .await
             }
+            (LlmProvider::Google, Some(on_chunk)) => {
+                self.stream_google_with_functions(
+                    &messages, &functions, &tools, mcp_client, options, on_chunk,
+                )
+                .await
+            }
+            (LlmProvider::Claude, on_chunk) => {
+                // Not streamed yet: the answer arrives as a single chunk
+                let response = self
+                    .call_claude_with_functions(&messages, &tools, mcp_client, options)
+                    .await;
+                if let (Ok(response), Some(on_chunk)) = (&response, on_chunk) {
+                    on_chunk(&response.content);
+                }
+                response
+            }
+        }?;
+        response.available_tools = tools.into_iter().map(|tool| tool.name).collect();
+        Ok(response)
+    }
+
+    /// Builds the out-of-scope system message, listing what the agent can do
+    /// based on the tool descriptions.
+    fn capabilities_message(instruction: &str, tools: &[McpTool]) -> ChatMessage {
+        let capabilities = tools
+            .iter()
+            .map(|tool| format!("- {}: {}", tool.name, tool.description))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        ChatMessage {
+            role: "system".to_string(),
+            content: format!("{}\n\nYou can help with:\n{}", instruction, capabilities),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// Runs a tool the model asked for. Names not in the offered tool list are
+    /// answered with a corrective result (so the model can retry with a real
+    /// tool) rather than being forwarded to the MCP server.
+    async fn execute_tool_call(
+        &self,
+        mcp_client: &McpClient,
+        tools: &[McpTool],
+        name: &str,
+        arguments: &serde_json::Value,
+        deferred: &mut Vec<FunctionCall>,
+    ) -> Result<String> {
+        if !tools.iter().any(|tool| tool.name == name) {
+            let available = tools
+                .iter()
+                .map(|tool| tool.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            if !self.recover_unknown_tools {
+                return Err(anyhow!(
+                    "Model requested unknown tool '{}' (available: {})",
+                    name,
+                    available
+                ));
+            }
+            warn!("Model requested unknown tool '{}'", name);
+            return Ok(format!(
+                "Error: tool '{}' does not exist. Available tools are: {}",
+                name, available
+            ));
+        }
+
+        if self.confirm_tools.contains(name) {
+            deferred.push(FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.clone(),
+            });
+            return Ok(format!(
+                "The '{}' action has not been executed yet: it requires the user's \
+                 explicit confirmation. Summarize what will happen and tell the user \
+                 it is awaiting their confirmation.",
+                name
+            ));
+        }
+
+        let result = mcp_client.call_tool_text(name, arguments).await?;
+        Ok(self.tool_result_shaper.shape(name, result))
+    }
+
+    /// Whether `execute_tool_call` forwards a call to `name` to the MCP
+    /// server, i.e. its result is real tool output.
+    fn forwards_tool_call(&self, tools: &[McpTool], name: &str) -> bool {
+        tools.iter().any(|tool| tool.name == name) && !self.confirm_tools.contains(name)
+    }
+
+    /// `execute_tool_call` for one of several concurrent calls: returns the
+    /// call's index alongside its outcome.
+    async fn execute_indexed_tool_call(
+        &self,
+        mcp_client: &McpClient,
+        tools: &[McpTool],
+        index: usize,
+        call: &FunctionCall,
+    ) -> (usize, Result<(String, Vec<FunctionCall>)>) {
+        let mut deferred = Vec::new();
+        let result = self
+            .execute_tool_call(
+                mcp_client,
+                tools,
+                &call.name,
+                &call.arguments,
+                &mut deferred,
+            )
+            .await;
+        (index, result.map(|content| (content, deferred)))
+    }
+
+    /// Executes one round of tool calls concurrently, bounded so a large
+    /// fan-out doesn't overwhelm the MCP server, and records them in
+    /// `output`. Results are returned in call order, since providers match
+    /// them to calls by position.
+    async fn run_tool_calls(
+        &self,
+        mcp_client: &McpClient,
+        tools: &[McpTool],
+        calls: &[&FunctionCall],
+        output: &mut LlmResponse,
+    ) -> Result<Vec<String>> {
+        output.tool_calls += calls.len() as u32;
+        output
+            .called_tools
+            .extend(calls.iter().map(|call| ToolInvocation {
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+            }));
+        let round_started = Instant::now();
+        let pending: Vec<_> = calls
+            .iter()
+            .enumerate()
+            .map(|(index, call)| self.execute_indexed_tool_call(mcp_client, tools, index, call))
+            .collect();
+        let mut results: Vec<_> = stream::iter(pending)
+            .buffer_unordered(self.max_concurrent_tool_calls)
+            .collect()
+            .await;
+        output.tool_time += round_started.elapsed();
+        results.sort_by_key(|(index, _)| *index);
+
+        let mut tool_results = Vec::with_capacity(calls.len());
+        for ((_, result), call) in results.into_iter().zip(calls) {
+            let (tool_result, deferred) = result?;
+            output.deferred_calls.extend(deferred);
+            if self.forwards_tool_call(tools, &call.name) {
+                output.tool_results.push(tool_result.clone());
+            }
+            tool_results.push(tool_result);
+        }
+        Ok(tool_results)
+    }
+
+    /// Runs one round of OpenAI-format tool calls, then appends the assistant
+    /// turn and the tool results (in call order) to `messages`.
+    async fn run_openai_tool_round(
+        &self,
+        mcp_client: &McpClient,
+        tools: &[McpTool],
+        content: String,
+        tool_calls: Vec<ToolCall>,
+        messages: &mut Vec<ChatMessage>,
+        output: &mut LlmResponse,
+    ) -> Result<()> {
+        let calls: Vec<&FunctionCall> = tool_calls.iter().map(|call| &call.function).collect();
+        let tool_results = self
+            .run_tool_calls(mcp_client, tools, &calls, output)
+            .await?;
+
+        let ids: Vec<String> = tool_calls.iter().map(|call| call.id.clone()).collect();
+        messages.push(ChatMessage {
+            role: "assistant".to_string(),
+            content,
+            tool_calls: Some(tool_calls),
+            tool_call_id: None,
+        });
+        for (tool_result, id) in tool_results.into_iter().zip(ids) {
+            // Add tool result message
+            messages.push(ChatMessage {
+                role: "tool".to_string(),
+                content: tool_result,
+                tool_calls: None,
+                tool_call_id: Some(id),
+            });
         }
+        Ok(())
     }
 
     fn convert_mcp_tools_to_functions(&self, tools: &[McpTool]) -> Vec<serde_json::Value> {
@@ -71,62 +814,71 @@ impl LlmClient {
                     "function": {
                         "name": tool.name,
                         "description": tool.description,
-                        "parameters": tool.input_schema
+                        "parameters": self.schema_sanitizer.sanitize(&tool.input_schema)
                     }
                 })
             })
             .collect()
     }
 
-    async fn call_groq_with_functions(
+    async fn call_openai_with_functions(
         &self,
         messages: &[ChatMessage],
         functions: &[serde_json::Value],
+        tools: &[McpTool],
         mcp_client: &McpClient,
-    ) -> Result<String> {
+        options: &GenerationOptions,
+    ) -> Result<LlmResponse> {
+        let (provider, url) = self.chat_completions_endpoint();
+        let label = format!("{} API", provider);
         let mut current_messages = messages.to_vec();
+        let mut output = LlmResponse::default();
+        let mut rounds = 0;
+        let pinned = leading_system_messages(messages);
 
         loop {
-            let request = json!({
+            let mut request = json!({
                 "model": self.model,
-                "messages": current_messages.iter().map(|m| {
-                    json!({
-                        "role": m.role,
-                        "content": m.content
-                    })
-                }).collect::<Vec<_>>(),
+                "messages": current_messages.iter().map(openai_message).collect::<Vec<_>>(),
                 "tools": functions,
-                "tool_choice": "auto",
+                "tool_choice": self.openai_tool_choice(options, &output),
                 "temperature": self.temperature,
                 "max_tokens": self.max_tokens,
             });
+            self.apply_reasoning_format(&mut request, options);
+            self.apply_extra_params(&mut request);
+            self.fit_request_size(&mut request, "messages", pinned);
 
             let response = self
-                .client
-                .post("https://api.groq.com/openai/v1/chat/completions")
-                .header("Authorization", format!("Bearer {}", self.api_key))
-                .header("Content-Type", "application/json")
-                .json(&request)
-                .send()
+                .retry
+                .send(&label, || {
+                    self.authorize(self.client.post(&url))
+                        .header("Content-Type", "application/json")
+                        .headers(self.extra_headers.clone())
+                        .json(&request)
+                })
                 .await?;
 
             if !response.status().is_success() {
+                let status = response.status();
                 let error_text = response.text().await?;
-                return Err(anyhow!("Groq API error: {}", error_text));
+                return Err(self.api_error(provider, &self.model, status, error_text));
             }
 
             #[derive(Deserialize)]
-            struct GroqResponse {
-                choices: Vec<GroqChoice>,
+            struct CompletionResponse {
+                choices: Vec<CompletionChoice>,
+                usage: Option<TokenUsage>,
             }
 
             #[derive(Deserialize)]
-            struct GroqChoice {
-                message: GroqMessage,
+            struct CompletionChoice {
+                message: CompletionMessage,
+                finish_reason: Option<String>,
             }
 
             #[derive(Deserialize)]
-            struct GroqMessage {
+            struct CompletionMessage {
                 content: Option<String>,
                 tool_calls: Option<Vec<ToolCallResponse>>,
             }
@@ -144,55 +896,53 @@ impl LlmClient {
                 arguments: String,
             }
 
-            let result: GroqResponse = response.json().await?;
+            let result: CompletionResponse = response.json().await?;
+            if let Some(usage) = result.usage {
+                output.usage.add(usage);
+            }
+            if result.choices[0].finish_reason.as_deref() == Some("content_filter") {
+                return Err(AgentError::ContentFiltered(format!(
+                    "{} content filter blocked the response",
+                    provider
+                ))
+                .into());
+            }
             let message = &result.choices[0].message;
 
             // Check if LLM wants to call a tool
             if let Some(tool_calls) = &message.tool_calls {
                 if !tool_calls.is_empty() {
-                    // Add assistant message with tool calls
-                    current_messages.push(ChatMessage {
-                        role: "assistant".to_string(),
-                        content: message.content.clone().unwrap_or_default(),
-                        tool_calls: Some(
-                            tool_calls
-                                .iter()
-                                .map(|tc| crate::models::ToolCall {
-                                    id: tc.id.clone(),
-                                    r#type: tc.r#type.clone(),
-                                    function: crate::models::FunctionCall {
-                                        name: tc.function.name.clone(),
-                                        arguments: serde_json::from_str(&tc.function.arguments)
-                                            .unwrap_or_default(),
-                                    },
-                                })
-                                .collect(),
-                        ),
-                    });
-
-                    // Execute each tool call
-                    for tool_call in tool_calls {
-                        let arguments: serde_json::Value =
-                            serde_json::from_str(&tool_call.function.arguments).unwrap_or_default();
-
-                        let tool_result = mcp_client
-                            .call_tool(&tool_call.function.name, &arguments)
-                            .await?;
-
-                        // Add tool result message
-                        current_messages.push(ChatMessage {
-                            role: "tool".to_string(),
-                            content: tool_result,
-                            tool_calls: None,
-                        });
-                    }
+                    let tool_calls = tool_calls
+                        .iter()
+                        .map(|tc| ToolCall {
+                            id: tc.id.clone(),
+                            r#type: tc.r#type.clone(),
+                            function: FunctionCall {
+                                name: tc.function.name.clone(),
+                                arguments: serde_json::from_str(&tc.function.arguments)
+                                    .unwrap_or_default(),
+                            },
+                        })
+                        .collect();
+                    self.next_tool_round(&mut rounds)?;
+                    self.run_openai_tool_round(
+                        mcp_client,
+                        tools,
+                        message.content.clone().unwrap_or_default(),
+                        tool_calls,
+                        &mut current_messages,
+                        &mut output,
+                    )
+                    .await?;
                     // Continue loop to process tool results
                     continue;
                 }
             }
 
             // No tool calls, return the response
-            return Ok(message.content.clone().unwrap_or_default());
+            self.ensure_tool_called(options, &output)?;
+            output.content = message.content.clone().unwrap_or_default();
+            return Ok(output);
         }
     }
 
@@ -200,24 +950,16 @@ impl LlmClient {
         &self,
         messages: &[ChatMessage],
         functions: &[serde_json::Value],
+        tools: &[McpTool],
         mcp_client: &McpClient,
-    ) -> Result<String> {
+        options: &GenerationOptions,
+    ) -> Result<LlmResponse> {
+        let mut output = LlmResponse::default();
+        let mut rounds = 0;
+        let pinned = leading_system_messages(messages);
+
         // Convert messages to Gemini format
-        let mut contents: Vec<serde_json::Value> = messages
-            .iter()
-            .map(|m| {
-                let role = match m.role.as_str() {
-                    "user" => "user",
-                    "assistant" => "model",
-                    "tool" => "function",
-                    _ => "user",
-                };
-                json!({
-                    "role": role,
-                    "parts": [{"text": m.content}]
-                })
-            })
-            .collect();
+        let mut contents = gemini_contents(messages);
 
         // Convert functions to Gemini format
         let function_declarations: Vec<serde_json::Value> = functions
@@ -233,136 +975,1442 @@ impl LlmClient {
             .collect();
 
         loop {
-            let request = json!({
+            let mut request = json!({
                 "contents": contents,
                 "tools": [{
                     "functionDeclarations": function_declarations
                 }],
+                "toolConfig": self.gemini_tool_config(options, &output),
                 "generationConfig": {
                     "temperature": self.temperature,
                     "maxOutputTokens": self.max_tokens,
                 }
             });
+            self.apply_extra_params(&mut request);
+            self.fit_request_size(&mut request, "contents", pinned);
 
             let url = format!(
                 "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
                 self.model, self.api_key
             );
 
-            let response = self.client.post(&url).json(&request).send().await?;
+            let response = self
+                .retry
+                .send("Google API", || {
+                    self.client
+                        .post(&url)
+                        .headers(self.extra_headers.clone())
+                        .json(&request)
+                })
+                .await?;
 
             if !response.status().is_success() {
+                let status = response.status();
                 let error_text = response.text().await?;
-                return Err(anyhow!("Google API error: {}", error_text));
+                return Err(self.api_error("Google", &self.model, status, error_text));
             }
 
-            #[derive(Deserialize)]
-            struct GeminiResponse {
-                candidates: Vec<GeminiCandidate>,
+            let result: serde_json::Value = response.json().await?;
+            if let Some(reason) = gemini_block_reason(&result) {
+                return Err(AgentError::ContentFiltered(format!(
+                    "Gemini safety filter blocked the response: {}",
+                    reason
+                ))
+                .into());
             }
-
-            #[derive(Deserialize)]
-            struct GeminiCandidate {
-                content: GeminiContent,
+            let result: GeminiResponse = serde_json::from_value(result)?;
+            if let Some(usage) = result.usage_metadata {
+                output.usage.add(usage.into());
             }
 
-            #[derive(Deserialize)]
-            struct GeminiContent {
-                parts: Vec<serde_json::Value>,
-            }
-
-            let result: GeminiResponse = response.json().await?;
-
-            // Check for function calls
-            if let Some(candidate) = result.candidates.first() {
-                let mut found_function_call = false;
-
-                for part in &candidate.content.parts {
-                    if let Some(function_call) = part.get("functionCall") {
-                        found_function_call = true;
-                        let func_name = function_call["name"].as_str().unwrap();
-                        let func_args = &function_call["args"];
-
-                        let tool_result = mcp_client.call_tool(func_name, func_args).await?;
-
-                        // Add model response with function call
-                        contents.push(json!({
-                            "role": "model",
-                            "parts": [{"functionCall": function_call}]
-                        }));
-
-                        // Add function response
-                        contents.push(json!({
-                            "role": "function",
-                            "parts": [{
-                                "functionResponse": {
-                                    "name": func_name,
-                                    "response": json!({"result": tool_result})
-                                }
-                            }]
-                        }));
-
-                        // Continue loop to process function result
-                        break;
-                    }
-                }
-
-                if !found_function_call {
-                    // Return text response
-                    if let Some(part) = candidate.content.parts.first() {
-                        if let Some(text) = part.get("text") {
-                            return Ok(text.as_str().unwrap().to_string());
-                        }
-                    }
-                }
-            } else {
+            let Some(candidate) = result.candidates.into_iter().next() else {
                 return Err(anyhow!("No candidates in Gemini response"));
+            };
+            let calls = gemini_function_calls(&candidate.content.parts);
+            if calls.is_empty() {
+                self.ensure_tool_called(options, &output)?;
+                output.content = gemini_text(&candidate.content.parts);
+                return Ok(output);
             }
-        }
-    }
 
-    #[allow(dead_code)]
-    pub async fn generate_embedding(&self, text: &str) -> Result<Vec<f32>> {
-        match self.provider {
-            LlmProvider::Google => self.generate_google_embedding(text).await,
-            LlmProvider::Groq => Err(anyhow!(
-                "Groq does not support embeddings. Use Google AI Studio for embeddings."
-            )),
+            // Gemini can ask for several calls in one turn, interleaved with
+            // text; all of them run before the next round
+            self.next_tool_round(&mut rounds)?;
+            let call_refs: Vec<&FunctionCall> = calls.iter().collect();
+            let tool_results = self
+                .run_tool_calls(mcp_client, tools, &call_refs, &mut output)
+                .await?;
+
+            contents.push(json!({
+                "role": "model",
+                "parts": candidate.content.parts
+            }));
+            contents.push(gemini_function_responses(&calls, tool_results));
         }
     }
 
-    #[allow(dead_code)]
-    async fn generate_google_embedding(&self, text: &str) -> Result<Vec<f32>> {
-        let request = json!({
-            "model": "text-embedding-004",
-            "content": {
-                "parts": [{"text": text}]
-            }
-        });
-
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/text-embedding-004:embedContent?key={}",
-            self.api_key
-        );
-
-        let response = self.client.post(&url).json(&request).send().await?;
-
-        if !response.status().is_success() {
-            let error_text = response.text().await?;
-            return Err(anyhow!("Google Embeddings API error: {}", error_text));
-        }
+    /// Tool-calling loop over Anthropic's Messages API. Tools are declared
+    /// with an `input_schema`, the model answers with `tool_use` blocks and
+    /// their results go back as `tool_result` blocks in a user message.
+    async fn call_claude_with_functions(
+        &self,
+        messages: &[ChatMessage],
+        tools: &[McpTool],
+        mcp_client: &McpClient,
+        options: &GenerationOptions,
+    ) -> Result<LlmResponse> {
+        let mut output = LlmResponse::default();
+        let mut rounds = 0;
+        let (system, mut history) = claude_messages(messages);
+        let url = format!("{}/messages", ANTHROPIC_API_URL);
 
-        #[derive(Deserialize)]
-        struct EmbeddingResponse {
-            embedding: EmbeddingData,
-        }
+        let claude_tools: Vec<serde_json::Value> = tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "name": tool.name,
+                    "description": tool.description,
+                    "input_schema": self.schema_sanitizer.sanitize(&tool.input_schema)
+                })
+            })
+            .collect();
 
         #[derive(Deserialize)]
-        struct EmbeddingData {
-            values: Vec<f32>,
+        struct MessagesResponse {
+            #[serde(default)]
+            content: Vec<serde_json::Value>,
+            stop_reason: Option<String>,
+            usage: Option<ClaudeUsage>,
         }
 
-        let result: EmbeddingResponse = response.json().await?;
+        loop {
+            let mut request = json!({
+                "model": self.model,
+                "max_tokens": self.max_tokens,
+                "temperature": self.temperature,
+                "messages": history,
+                "tools": claude_tools,
+                "tool_choice": self.claude_tool_choice(options, &output),
+            });
+            if !system.is_empty() {
+                request["system"] = json!(system);
+            }
+            self.apply_extra_params(&mut request);
+            self.fit_request_size(&mut request, "messages", 0);
+
+            let response = self
+                .retry
+                .send("Anthropic API", || {
+                    self.anthropic_request(self.client.post(&url))
+                        .headers(self.extra_headers.clone())
+                        .json(&request)
+                })
+                .await?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let error_text = response.text().await?;
+                return Err(self.api_error("Anthropic", &self.model, status, error_text));
+            }
+
+            let result: MessagesResponse = response.json().await?;
+            if let Some(usage) = result.usage {
+                output.usage.add(usage.into());
+            }
+            if result.stop_reason.as_deref() == Some("refusal") {
+                return Err(AgentError::ContentFiltered(
+                    "Anthropic declined to answer the request".to_string(),
+                )
+                .into());
+            }
+
+            let uses: Vec<(String, FunctionCall)> = result
+                .content
+                .iter()
+                .filter(|block| block["type"] == "tool_use")
+                .map(|block| {
+                    (
+                        block["id"].as_str().unwrap_or_default().to_string(),
+                        FunctionCall {
+                            name: block["name"].as_str().unwrap_or_default().to_string(),
+                            arguments: block["input"].clone(),
+                        },
+                    )
+                })
+                .collect();
+            if uses.is_empty() {
+                self.ensure_tool_called(options, &output)?;
+                output.content = claude_text(&result.content);
+                return Ok(output);
+            }
+
+            self.next_tool_round(&mut rounds)?;
+            let call_refs: Vec<&FunctionCall> = uses.iter().map(|(_, call)| call).collect();
+            let tool_results = self
+                .run_tool_calls(mcp_client, tools, &call_refs, &mut output)
+                .await?;
+            let results: Vec<serde_json::Value> = uses
+                .iter()
+                .zip(tool_results)
+                .map(|((id, _), tool_result)| {
+                    json!({
+                        "type": "tool_result",
+                        "tool_use_id": id,
+                        "content": tool_result
+                    })
+                })
+                .collect();
+
+            history.push(json!({"role": "assistant", "content": result.content}));
+            history.push(json!({"role": "user", "content": results}));
+        }
+    }
+
+    /// Streaming variant of `call_openai_with_functions`. Tool call arguments
+    /// arrive in fragments and are reassembled by their index.
+    async fn stream_openai_with_functions(
+        &self,
+        messages: &[ChatMessage],
+        functions: &[serde_json::Value],
+        tools: &[McpTool],
+        mcp_client: &McpClient,
+        options: &GenerationOptions,
+        on_chunk: &ChunkSink,
+    ) -> Result<LlmResponse> {
+        let (provider, url) = self.chat_completions_endpoint();
+        let label = format!("{} API", provider);
+        let mut current_messages = messages.to_vec();
+        let mut output = LlmResponse::default();
+        let mut rounds = 0;
+        let pinned = leading_system_messages(messages);
+
+        #[derive(Deserialize)]
+        struct CompletionChunk {
+            #[serde(default)]
+            choices: Vec<CompletionChunkChoice>,
+            usage: Option<TokenUsage>,
+            x_groq: Option<GroqChunkMeta>,
+        }
+
+        #[derive(Deserialize)]
+        struct GroqChunkMeta {
+            usage: Option<TokenUsage>,
+        }
+
+        #[derive(Deserialize)]
+        struct CompletionChunkChoice {
+            #[serde(default)]
+            delta: CompletionDelta,
+            finish_reason: Option<String>,
+        }
+
+        #[derive(Deserialize, Default)]
+        struct CompletionDelta {
+            content: Option<String>,
+            tool_calls: Option<Vec<ToolCallDelta>>,
+        }
+
+        #[derive(Deserialize)]
+        struct ToolCallDelta {
+            index: usize,
+            id: Option<String>,
+            function: Option<FunctionCallDelta>,
+        }
+
+        #[derive(Deserialize)]
+        struct FunctionCallDelta {
+            name: Option<String>,
+            arguments: Option<String>,
+        }
+
+        loop {
+            let mut request = json!({
+                "model": self.model,
+                "messages": current_messages.iter().map(openai_message).collect::<Vec<_>>(),
+                "tools": functions,
+                "tool_choice": self.openai_tool_choice(options, &output),
+                "temperature": self.temperature,
+                "max_tokens": self.max_tokens,
+                "stream": true,
+            });
+            if matches!(self.provider, LlmProvider::OpenAi) {
+                // OpenAI only reports usage on streams when asked; Groq
+                // always sends it in `x_groq`
+                request["stream_options"] = json!({ "include_usage": true });
+            }
+            self.apply_reasoning_format(&mut request, options);
+            self.apply_extra_params(&mut request);
+            self.fit_request_size(&mut request, "messages", pinned);
+
+            let response = self
+                .retry
+                .send(&label, || {
+                    self.authorize(self.stream_client.post(&url))
+                        .header("Content-Type", "application/json")
+                        .headers(self.extra_headers.clone())
+                        .json(&request)
+                })
+                .await?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let error_text = response.text().await?;
+                return Err(self.api_error(provider, &self.model, status, error_text));
+            }
+
+            let mut content = String::new();
+            // (id, name, arguments) per tool call index
+            let mut calls: Vec<(String, String, String)> = Vec::new();
+            let mut finish_reason = None;
+            read_sse(response, self.stream_idle_timeout, |data| {
+                let chunk: CompletionChunk = serde_json::from_str(data)?;
+                if let Some(usage) = chunk.usage.or(chunk.x_groq.and_then(|meta| meta.usage)) {
+                    output.usage.add(usage);
+                }
+                for choice in chunk.choices {
+                    if let Some(text) = choice.delta.content.filter(|text| !text.is_empty()) {
+                        on_chunk(&text);
+                        content.push_str(&text);
+                    }
+                    for delta in choice.delta.tool_calls.unwrap_or_default() {
+                        if calls.len() <= delta.index {
+                            calls.resize_with(delta.index + 1, Default::default);
+                        }
+                        let call = &mut calls[delta.index];
+                        if let Some(id) = delta.id {
+                            call.0 = id;
+                        }
+                        if let Some(function) = delta.function {
+                            call.1.push_str(&function.name.unwrap_or_default());
+                            call.2.push_str(&function.arguments.unwrap_or_default());
+                        }
+                    }
+                    if choice.finish_reason.is_some() {
+                        finish_reason = choice.finish_reason;
+                    }
+                }
+                Ok(())
+            })
+            .await?;
+
+            if finish_reason.as_deref() == Some("content_filter") {
+                return Err(AgentError::ContentFiltered(format!(
+                    "{} content filter blocked the response",
+                    provider
+                ))
+                .into());
+            }
+
+            if calls.is_empty() {
+                self.ensure_tool_called(options, &output)?;
+                output.content = content;
+                return Ok(output);
+            }
+
+            let tool_calls = calls
+                .into_iter()
+                .map(|(id, name, arguments)| ToolCall {
+                    id,
+                    r#type: "function".to_string(),
+                    function: FunctionCall {
+                        name,
+                        arguments: serde_json::from_str(&arguments).unwrap_or_default(),
+                    },
+                })
+                .collect();
+            self.next_tool_round(&mut rounds)?;
+            self.run_openai_tool_round(
+                mcp_client,
+                tools,
+                content,
+                tool_calls,
+                &mut current_messages,
+                &mut output,
+            )
+            .await?;
+        }
+    }
+
+    /// Streaming variant of `call_google_with_functions`, using
+    /// `streamGenerateContent`. All function calls of a round are executed.
+    async fn stream_google_with_functions(
+        &self,
+        messages: &[ChatMessage],
+        functions: &[serde_json::Value],
+        tools: &[McpTool],
+        mcp_client: &McpClient,
+        options: &GenerationOptions,
+        on_chunk: &ChunkSink,
+    ) -> Result<LlmResponse> {
+        let mut output = LlmResponse::default();
+        let mut rounds = 0;
+        let pinned = leading_system_messages(messages);
+        let mut contents = gemini_contents(messages);
+        let function_declarations: Vec<serde_json::Value> = functions
+            .iter()
+            .map(|f| {
+                let func = &f["function"];
+                json!({
+                    "name": func["name"],
+                    "description": func["description"],
+                    "parameters": func["parameters"]
+                })
+            })
+            .collect();
+
+        let url = format!(
+            "https://generativelanguage.googleapis.com/v1beta/models/{}:streamGenerateContent?alt=sse&key={}",
+            self.model, self.api_key
+        );
+
+        loop {
+            let mut request = json!({
+                "contents": contents,
+                "tools": [{
+                    "functionDeclarations": function_declarations
+                }],
+                "toolConfig": self.gemini_tool_config(options, &output),
+                "generationConfig": {
+                    "temperature": self.temperature,
+                    "maxOutputTokens": self.max_tokens,
+                }
+            });
+            self.apply_extra_params(&mut request);
+            self.fit_request_size(&mut request, "contents", pinned);
+
+            let response = self
+                .retry
+                .send("Google API", || {
+                    self.stream_client
+                        .post(&url)
+                        .headers(self.extra_headers.clone())
+                        .json(&request)
+                })
+                .await?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let error_text = response.text().await?;
+                return Err(self.api_error("Google", &self.model, status, error_text));
+            }
+
+            let mut content = String::new();
+            let mut function_calls: Vec<serde_json::Value> = Vec::new();
+            // Usage is cumulative across chunks; the last one is the total
+            let mut usage = None;
+            read_sse(response, self.stream_idle_timeout, |data| {
+                let chunk: serde_json::Value = serde_json::from_str(data)?;
+                if let Some(reason) = gemini_block_reason(&chunk) {
+                    return Err(AgentError::ContentFiltered(format!(
+                        "Gemini safety filter blocked the response: {}",
+                        reason
+                    ))
+                    .into());
+                }
+                let chunk: GeminiResponse = serde_json::from_value(chunk)?;
+                if chunk.usage_metadata.is_some() {
+                    usage = chunk.usage_metadata;
+                }
+                if let Some(candidate) = chunk.candidates.into_iter().next() {
+                    let text = gemini_text(&candidate.content.parts);
+                    if !text.is_empty() {
+                        on_chunk(&text);
+                        content.push_str(&text);
+                    }
+                    function_calls.extend(
+                        candidate
+                            .content
+                            .parts
+                            .into_iter()
+                            .filter_map(|mut part| part.get_mut("functionCall").map(|f| f.take())),
+                    );
+                }
+                Ok(())
+            })
+            .await?;
+            if let Some(usage) = usage {
+                output.usage.add(usage.into());
+            }
+
+            if function_calls.is_empty() {
+                self.ensure_tool_called(options, &output)?;
+                output.content = content;
+                return Ok(output);
+            }
+
+            self.next_tool_round(&mut rounds)?;
+            let calls: Vec<FunctionCall> =
+                function_calls.iter().map(gemini_function_call).collect();
+            let call_refs: Vec<&FunctionCall> = calls.iter().collect();
+            let tool_results = self
+                .run_tool_calls(mcp_client, tools, &call_refs, &mut output)
+                .await?;
+
+            contents.push(json!({
+                "role": "model",
+                "parts": function_calls
+                    .iter()
+                    .map(|function_call| json!({"functionCall": function_call}))
+                    .collect::<Vec<_>>()
+            }));
+            contents.push(gemini_function_responses(&calls, tool_results));
+        }
+    }
+
+    /// Condenses a conversation into a short summary, used to compact history.
+    pub async fn summarize(&self, messages: &[ChatMessage]) -> Result<String> {
+        let transcript = messages
+            .iter()
+            .map(|m| format!("{}: {}", m.role, m.content))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let prompt = [
+            ChatMessage {
+                role: "system".to_string(),
+                content: "Summarize the following conversation in a few sentences. Keep \
+                          names, dates, salons, services and any booking details; drop \
+                          small talk."
+                    .to_string(),
+                tool_calls: None,
+                tool_call_id: None,
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: transcript,
+                tool_calls: None,
+                tool_call_id: None,
+            },
+        ];
+
+        let model = self.summary_model.as_deref().unwrap_or(&self.model);
+        let (summary, _) = self
+            .complete(
+                &prompt,
+                model,
+                self.summary_max_tokens.unwrap_or(self.max_tokens),
+            )
+            .await?;
+        Ok(summary)
+    }
+
+    /// Single plain completion with no tools, with the tokens it used.
+    async fn complete(
+        &self,
+        messages: &[ChatMessage],
+        model: &str,
+        max_tokens: u32,
+    ) -> Result<(String, TokenUsage)> {
+        match self.provider {
+            LlmProvider::Groq | LlmProvider::OpenAi => {
+                let (provider, url) = self.chat_completions_endpoint();
+                let mut request = json!({
+                    "model": model,
+                    "messages": messages.iter().map(openai_message).collect::<Vec<_>>(),
+                    "temperature": self.temperature,
+                    "max_tokens": max_tokens,
+                });
+                self.apply_reasoning_format(&mut request, &GenerationOptions::default());
+                self.apply_extra_params(&mut request);
+
+                let response = self
+                    .retry
+                    .send(&format!("{} API", provider), || {
+                        self.authorize(self.client.post(&url))
+                            .headers(self.extra_headers.clone())
+                            .json(&request)
+                    })
+                    .await?;
+
+                if !response.status().is_success() {
+                    let status = response.status();
+                    let error_text = response.text().await?;
+                    return Err(self.api_error(provider, model, status, error_text));
+                }
+
+                let result: serde_json::Value = response.json().await?;
+                if result["choices"][0]["finish_reason"] == "content_filter" {
+                    return Err(AgentError::ContentFiltered(format!(
+                        "{} content filter blocked the response",
+                        provider
+                    ))
+                    .into());
+                }
+                let content = result["choices"][0]["message"]["content"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string();
+                let usage = serde_json::from_value(result["usage"].clone()).unwrap_or_default();
+                Ok((content, usage))
+            }
+            LlmProvider::Claude => {
+                let (system, history) = claude_messages(messages);
+                let mut request = json!({
+                    "model": model,
+                    "max_tokens": max_tokens,
+                    "temperature": self.temperature,
+                    "messages": history,
+                });
+                if !system.is_empty() {
+                    request["system"] = json!(system);
+                }
+                self.apply_extra_params(&mut request);
+
+                let url = format!("{}/messages", ANTHROPIC_API_URL);
+                let response = self
+                    .retry
+                    .send("Anthropic API", || {
+                        self.anthropic_request(self.client.post(&url))
+                            .headers(self.extra_headers.clone())
+                            .json(&request)
+                    })
+                    .await?;
+
+                if !response.status().is_success() {
+                    let status = response.status();
+                    let error_text = response.text().await?;
+                    return Err(self.api_error("Anthropic", model, status, error_text));
+                }
+
+                let result: serde_json::Value = response.json().await?;
+                if result["stop_reason"] == "refusal" {
+                    return Err(AgentError::ContentFiltered(
+                        "Anthropic declined to answer the request".to_string(),
+                    )
+                    .into());
+                }
+                let content = result["content"]
+                    .as_array()
+                    .map(|blocks| claude_text(blocks))
+                    .unwrap_or_default();
+                let usage = serde_json::from_value::<ClaudeUsage>(result["usage"].clone())
+                    .map(TokenUsage::from)
+                    .unwrap_or_default();
+                Ok((content, usage))
+            }
+            LlmProvider::Google => {
+                let mut request = json!({
+                    "contents": gemini_contents(messages),
+                    "generationConfig": {
+                        "temperature": self.temperature,
+                        "maxOutputTokens": max_tokens,
+                    }
+                });
+                self.apply_extra_params(&mut request);
+
+                let url = format!(
+                    "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
+                    model, self.api_key
+                );
+
+                let response = self
+                    .retry
+                    .send("Google API", || {
+                        self.client
+                            .post(&url)
+                            .headers(self.extra_headers.clone())
+                            .json(&request)
+                    })
+                    .await?;
+
+                if !response.status().is_success() {
+                    let status = response.status();
+                    let error_text = response.text().await?;
+                    return Err(self.api_error("Google", model, status, error_text));
+                }
+
+                let result: serde_json::Value = response.json().await?;
+                if let Some(reason) = gemini_block_reason(&result) {
+                    return Err(AgentError::ContentFiltered(format!(
+                        "Gemini safety filter blocked the response: {}",
+                        reason
+                    ))
+                    .into());
+                }
+                let content = result["candidates"][0]["content"]["parts"]
+                    .as_array()
+                    .map(|parts| gemini_text(parts))
+                    .unwrap_or_default();
+                let usage = serde_json::from_value::<GeminiUsage>(result["usageMetadata"].clone())
+                    .map(TokenUsage::from)
+                    .unwrap_or_default();
+                Ok((content, usage))
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub async fn generate_embedding(&self, text: &str) -> Result<Vec<f32>> {
+        match self.provider {
+            LlmProvider::Google => self.generate_google_embedding(text).await,
+            LlmProvider::Groq => Err(anyhow!(
+                "Groq does not support embeddings. Use Google AI Studio for embeddings."
+            )),
+            LlmProvider::OpenAi => Err(anyhow!(
+                "OpenAI embeddings are not supported yet. Use Google AI Studio for embeddings."
+            )),
+            LlmProvider::Claude => Err(anyhow!(
+                "Anthropic does not offer embeddings. Use Google AI Studio for embeddings."
+            )),
+        }
+    }
+
+    #[allow(dead_code)]
+    async fn generate_google_embedding(&self, text: &str) -> Result<Vec<f32>> {
+        let request = json!({
+            "model": "text-embedding-004",
+            "content": {
+                "parts": [{"text": text}]
+            }
+        });
+
+        let url = format!(
+            "https://generativelanguage.googleapis.com/v1beta/models/text-embedding-004:embedContent?key={}",
+            self.api_key
+        );
+
+        let response = self.client.post(&url).json(&request).send().await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            return Err(anyhow!("Google Embeddings API error: {}", error_text));
+        }
+
+        #[derive(Deserialize)]
+        struct EmbeddingResponse {
+            embedding: EmbeddingData,
+        }
+
+        #[derive(Deserialize)]
+        struct EmbeddingData {
+            values: Vec<f32>,
+        }
+
+        let result: EmbeddingResponse = response.json().await?;
         Ok(result.embedding.values)
     }
 }
+
+#[derive(Deserialize)]
+struct GeminiResponse {
+    #[serde(default)]
+    candidates: Vec<GeminiCandidate>,
+    #[serde(rename = "usageMetadata")]
+    usage_metadata: Option<GeminiUsage>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct GeminiUsage {
+    prompt_token_count: u64,
+    candidates_token_count: u64,
+    total_token_count: u64,
+}
+
+impl From<GeminiUsage> for TokenUsage {
+    fn from(usage: GeminiUsage) -> Self {
+        TokenUsage {
+            prompt_tokens: usage.prompt_token_count,
+            completion_tokens: usage.candidates_token_count,
+            total_tokens: usage.total_token_count,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ClaudeUsage {
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+impl From<ClaudeUsage> for TokenUsage {
+    fn from(usage: ClaudeUsage) -> Self {
+        TokenUsage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+            total_tokens: usage.input_tokens + usage.output_tokens,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GeminiCandidate {
+    #[serde(default)]
+    content: GeminiContent,
+}
+
+#[derive(Deserialize, Default)]
+struct GeminiContent {
+    #[serde(default)]
+    parts: Vec<serde_json::Value>,
+}
+
+/// Reads a `text/event-stream` body, passing each `data:` payload to
+/// `on_data` until `[DONE]` or the end of the body. Fails if the provider
+/// sends nothing for `idle_timeout`.
+async fn read_sse(
+    mut response: reqwest::Response,
+    idle_timeout: Duration,
+    mut on_data: impl FnMut(&str) -> Result<()>,
+) -> Result<()> {
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        let chunk = tokio::time::timeout(idle_timeout, response.chunk())
+            .await
+            .map_err(|elapsed| {
+                anyhow::Error::new(elapsed).context(format!(
+                    "LLM stream sent no data for {}s",
+                    idle_timeout.as_secs()
+                ))
+            })?;
+        let Some(bytes) = chunk? else {
+            break;
+        };
+        buffer.extend_from_slice(&bytes);
+        // Only complete lines are decoded, so a character split across
+        // network chunks is never cut in half
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim_start();
+            if data == "[DONE]" {
+                return Ok(());
+            }
+            on_data(data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a provider error means the requested model does not exist or has
+/// been retired. Groq and OpenAI report `model_not_found` (Groq also
+/// `model_decommissioned`); Gemini and Claude answer 404 naming the model. A
+/// 404 that doesn't mention the model is a wrong URL, not a retired model.
+fn is_model_unavailable(status: reqwest::StatusCode, error_text: &str) -> bool {
+    let text = error_text.to_lowercase();
+    (status == reqwest::StatusCode::NOT_FOUND && text.contains("model"))
+        || text.contains("model_not_found")
+        || text.contains("decommissioned")
+        || (text.contains("model") && text.contains("deprecated"))
+}
+
+/// Every `functionCall` part of a Gemini candidate, in order.
+fn gemini_function_calls(parts: &[serde_json::Value]) -> Vec<FunctionCall> {
+    parts
+        .iter()
+        .filter_map(|part| part.get("functionCall"))
+        .map(gemini_function_call)
+        .collect()
+}
+
+fn gemini_function_call(function_call: &serde_json::Value) -> FunctionCall {
+    FunctionCall {
+        name: function_call["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        arguments: function_call["args"].clone(),
+    }
+}
+
+/// The `function` turn answering `calls`: one `functionResponse` part per
+/// call, in the same order as the calls.
+fn gemini_function_responses(calls: &[FunctionCall], results: Vec<String>) -> serde_json::Value {
+    let parts: Vec<serde_json::Value> = calls
+        .iter()
+        .zip(results)
+        .map(|(call, result)| {
+            json!({
+                "functionResponse": {
+                    "name": call.name,
+                    "response": {"result": result}
+                }
+            })
+        })
+        .collect();
+    json!({
+        "role": "function",
+        "parts": parts
+    })
+}
+
+/// Joins the text parts of a Gemini candidate in order. Gemini may split one
+/// answer across several parts; non-text parts and thought summaries are
+/// skipped.
+fn gemini_text(parts: &[serde_json::Value]) -> String {
+    parts
+        .iter()
+        .filter(|part| !part["thought"].as_bool().unwrap_or(false))
+        .filter_map(|part| part["text"].as_str())
+        .collect()
+}
+
+/// OpenAI-style (Groq, OpenAI) message payload. Assistant tool calls and tool
+/// results keep their ids so replayed tool history is accepted; a tool result
+/// without an id can't be matched to its call, so it is replayed as plain
+/// context.
+fn openai_message(m: &ChatMessage) -> serde_json::Value {
+    match (m.role.as_str(), &m.tool_call_id, &m.tool_calls) {
+        ("tool", Some(id), _) => json!({
+            "role": "tool",
+            "tool_call_id": id,
+            "content": m.content
+        }),
+        ("tool", None, _) => json!({
+            "role": "system",
+            "content": format!("Tool result: {}", m.content)
+        }),
+        ("assistant", _, Some(calls)) if !calls.is_empty() => json!({
+            "role": "assistant",
+            "content": (!m.content.is_empty()).then_some(&m.content),
+            "tool_calls": calls.iter().map(|call| json!({
+                "id": call.id,
+                "type": "function",
+                "function": {
+                    "name": call.function.name,
+                    "arguments": call.function.arguments.to_string()
+                }
+            })).collect::<Vec<_>>()
+        }),
+        _ => json!({
+            "role": m.role,
+            "content": m.content
+        }),
+    }
+}
+
+/// Whether a request history entry carries tool results, which can't be
+/// kept without the call they answer. Anthropic sends them as user messages
+/// made of `tool_result` blocks.
+fn is_tool_result_entry(entry: &serde_json::Value) -> bool {
+    matches!(entry["role"].as_str(), Some("tool" | "function"))
+        || entry["content"]
+            .as_array()
+            .is_some_and(|blocks| blocks.iter().any(|block| block["type"] == "tool_result"))
+}
+
+/// Number of system messages at the start of `messages`; they map one-to-one
+/// to the first entries of a provider request's history.
+fn leading_system_messages(messages: &[ChatMessage]) -> usize {
+    messages.iter().take_while(|m| m.role == "system").count()
+}
+
+fn json_size(value: &serde_json::Value) -> usize {
+    serde_json::to_vec(value)
+        .map(|body| body.len())
+        .unwrap_or(0)
+}
+
+/// Removes `description` keywords from the JSON schemas under any
+/// `parameters` key, leaving tool names and top-level descriptions.
+fn strip_parameter_descriptions(value: &mut serde_json::Value, in_parameters: bool) {
+    match value {
+        serde_json::Value::Object(object) => {
+            if in_parameters && object.get("description").is_some_and(|d| d.is_string()) {
+                object.remove("description");
+            }
+            for (key, child) in object.iter_mut() {
+                strip_parameter_descriptions(child, in_parameters || key == "parameters");
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                strip_parameter_descriptions(item, in_parameters);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Gemini `contents` for a conversation. Assistant tool calls become
+/// `functionCall` parts and tool results `functionResponse` parts, named
+/// after the call they answer (looked up by `tool_call_id`).
+fn gemini_contents(messages: &[ChatMessage]) -> Vec<serde_json::Value> {
+    let mut call_names = std::collections::HashMap::new();
+    messages
+        .iter()
+        .map(|m| match m.role.as_str() {
+            "assistant" => {
+                let mut parts = Vec::new();
+                if !m.content.is_empty() {
+                    parts.push(json!({"text": m.content}));
+                }
+                for call in m.tool_calls.iter().flatten() {
+                    call_names.insert(call.id.clone(), call.function.name.clone());
+                    parts.push(json!({
+                        "functionCall": {
+                            "name": call.function.name,
+                            "args": call.function.arguments
+                        }
+                    }));
+                }
+                if parts.is_empty() {
+                    parts.push(json!({"text": ""}));
+                }
+                json!({"role": "model", "parts": parts})
+            }
+            "tool" => match m.tool_call_id.as_ref().and_then(|id| call_names.get(id)) {
+                Some(name) => json!({
+                    "role": "function",
+                    "parts": [{
+                        "functionResponse": {
+                            "name": name,
+                            "response": {"result": m.content}
+                        }
+                    }]
+                }),
+                None => json!({
+                    "role": "user",
+                    "parts": [{"text": format!("Tool result: {}", m.content)}]
+                }),
+            },
+            _ => json!({
+                "role": "user",
+                "parts": [{"text": m.content}]
+            }),
+        })
+        .collect()
+}
+
+/// Anthropic `system` prompt and `messages` for a conversation. System
+/// messages are joined into the top-level prompt; assistant tool calls become
+/// `tool_use` blocks and tool results `tool_result` blocks, merged into one
+/// user message per turn since roles must alternate.
+fn claude_messages(messages: &[ChatMessage]) -> (String, Vec<serde_json::Value>) {
+    let mut system = Vec::new();
+    let mut history: Vec<serde_json::Value> = Vec::new();
+    for m in messages {
+        let (role, blocks) = match m.role.as_str() {
+            "system" => {
+                system.push(m.content.as_str());
+                continue;
+            }
+            "assistant" => {
+                let mut blocks = Vec::new();
+                if !m.content.is_empty() {
+                    blocks.push(json!({"type": "text", "text": m.content}));
+                }
+                for call in m.tool_calls.iter().flatten() {
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.function.name,
+                        "input": call.function.arguments
+                    }));
+                }
+                ("assistant", blocks)
+            }
+            "tool" => match &m.tool_call_id {
+                Some(id) => (
+                    "user",
+                    vec![json!({
+                        "type": "tool_result",
+                        "tool_use_id": id,
+                        "content": m.content
+                    })],
+                ),
+                None => (
+                    "user",
+                    vec![json!({"type": "text", "text": format!("Tool result: {}", m.content)})],
+                ),
+            },
+            _ if m.content.is_empty() => continue,
+            _ => ("user", vec![json!({"type": "text", "text": m.content})]),
+        };
+        if blocks.is_empty() {
+            continue;
+        }
+        match history.last_mut() {
+            Some(last) if last["role"] == role => {
+                if let Some(content) = last["content"].as_array_mut() {
+                    content.extend(blocks);
+                }
+            }
+            _ => history.push(json!({"role": role, "content": blocks})),
+        }
+    }
+    (system.join("\n\n"), history)
+}
+
+/// Joins the text blocks of an Anthropic response in order.
+fn claude_text(blocks: &[serde_json::Value]) -> String {
+    blocks
+        .iter()
+        .filter(|block| block["type"] == "text")
+        .filter_map(|block| block["text"].as_str())
+        .collect()
+}
+
+/// Groq models that accept `reasoning_format` (DeepSeek R1 distills, Qwen3 and
+/// QwQ). `openai/gpt-oss-*` models reason too but take `include_reasoning`
+/// instead, so they are not listed.
+fn is_groq_reasoning_model(model: &str) -> bool {
+    let model = model.to_lowercase();
+    model.contains("deepseek-r1") || model.contains("qwen3") || model.contains("qwq")
+}
+
+/// Whether a provider error body reports a content-policy rejection
+/// (OpenAI-compatible `content_filter` / `content_policy_violation` codes).
+fn is_content_filter_error(error_text: &str) -> bool {
+    let text = error_text.to_lowercase();
+    text.contains("content_filter") || text.contains("content_policy")
+}
+
+/// Why Gemini withheld its answer on safety grounds, if it did: a blocked
+/// prompt (`promptFeedback.blockReason`) or a candidate stopped for safety.
+/// Includes the offending category when Gemini reports one.
+fn gemini_block_reason(result: &serde_json::Value) -> Option<String> {
+    let (reason, ratings) =
+        match result["promptFeedback"]["blockReason"].as_str() {
+            Some(reason) => (reason, &result["promptFeedback"]["safetyRatings"]),
+            None => {
+                let candidate = &result["candidates"][0];
+                match candidate["finishReason"].as_str()? {
+                    reason @ ("SAFETY" | "PROHIBITED_CONTENT" | "BLOCKLIST" | "SPII"
+                    | "RECITATION") => (reason, &candidate["safetyRatings"]),
+                    _ => return None,
+                }
+            }
+        };
+
+    let category = ratings.as_array().and_then(|ratings| {
+        ratings
+            .iter()
+            .find(|rating| rating["blocked"].as_bool().unwrap_or(false))
+            .and_then(|rating| rating["category"].as_str())
+    });
+    Some(match category {
+        Some(category) => format!("{} ({})", reason, category),
+        None => reason.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcp::HttpTransport;
+    use crate::test_support::{openai_reply, serve, FakeMcp};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// An OpenAI-compatible endpoint that asks for a `lookup` call on every
+    /// request, counting the requests.
+    async fn tool_hungry_llm() -> (String, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let app = axum::Router::new().fallback(move || {
+            let round = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                axum::Json(json!({
+                    "choices": [{
+                        "message": {
+                            "content": null,
+                            "tool_calls": [{
+                                "id": format!("call_{}", round),
+                                "type": "function",
+                                "function": {"name": "lookup", "arguments": "{}"}
+                            }]
+                        },
+                        "finish_reason": "tool_calls"
+                    }]
+                }))
+            }
+        });
+        (serve(app).await, calls)
+    }
+
+    fn user_message(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: "user".to_string(),
+            content: content.to_string(),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    fn test_client() -> LlmClient {
+        LlmClient::new(
+            LlmProvider::OpenAi,
+            "test-key".to_string(),
+            "test-model".to_string(),
+            0.0,
+            100,
+        )
+    }
+
+    #[tokio::test]
+    async fn tool_calls_of_one_round_run_concurrently_in_order() {
+        let delay = Duration::from_millis(200);
+        let mcp = McpClient::new(FakeMcp { delay });
+        let tools = mcp.cached_tools().await.unwrap();
+        let calls = [
+            FunctionCall {
+                name: "lookup".to_string(),
+                arguments: json!({"date": "monday"}),
+            },
+            FunctionCall {
+                name: "lookup".to_string(),
+                arguments: json!({"date": "tuesday"}),
+            },
+        ];
+        let call_refs: Vec<&FunctionCall> = calls.iter().collect();
+
+        // Built up front: creating HTTP clients takes a while in debug builds
+        let llm = test_client();
+        let mut output = LlmResponse::default();
+        let started = Instant::now();
+        let results = llm
+            .run_tool_calls(&mcp, &tools, &call_refs, &mut output)
+            .await
+            .unwrap();
+        let elapsed = started.elapsed();
+
+        assert!(elapsed < delay * 3 / 2, "took {:?}", elapsed);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].contains("monday"), "{}", results[0]);
+        assert!(results[1].contains("tuesday"), "{}", results[1]);
+        assert_eq!(output.tool_calls, 2);
+    }
+
+    #[tokio::test]
+    async fn tool_loop_stops_after_max_iterations() {
+        let (base_url, llm_calls) = tool_hungry_llm().await;
+        let llm = test_client()
+            .with_base_url(Some(base_url))
+            .with_max_tool_iterations(3);
+        let mcp = McpClient::new(FakeMcp {
+            delay: Duration::ZERO,
+        });
+
+        let error = llm
+            .generate_with_mcp_tools(
+                &[user_message("Keep looking")],
+                &mcp,
+                &GenerationOptions::default(),
+            )
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("after 3 rounds"), "{}", error);
+        // Three rounds ran; the fourth request for tools was refused
+        assert_eq!(llm_calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn degraded_answer_reports_its_usage() {
+        let llm = test_client()
+            .with_base_url(Some(serve(openai_reply("Tools are down")).await))
+            .with_mcp_degraded_mode(Some("Booking tools are unavailable.".to_string()));
+        // Nothing listens on port 1, so listing tools fails
+        let mcp = McpClient::new(HttpTransport::new("http://127.0.0.1:1".to_string()));
+
+        let response = llm
+            .generate_with_mcp_tools(
+                &[user_message("Book me in")],
+                &mcp,
+                &GenerationOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.content, "Tools are down");
+        assert_eq!(response.usage.total_tokens, 15);
+    }
+
+    #[test]
+    fn gemini_candidate_with_two_function_calls() {
+        let parts = vec![
+            json!({"text": "Let me check both salons. "}),
+            json!({"functionCall": {"name": "get_salon", "args": {"id": 1}}}),
+            json!({"thought": true, "text": "planning"}),
+            json!({"functionCall": {"name": "get_availability", "args": {"salon_id": 2}}}),
+            json!({"text": "One moment."}),
+        ];
+
+        let calls = gemini_function_calls(&parts);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "get_salon");
+        assert_eq!(calls[0].arguments, json!({"id": 1}));
+        assert_eq!(calls[1].name, "get_availability");
+        assert_eq!(calls[1].arguments, json!({"salon_id": 2}));
+        assert_eq!(gemini_text(&parts), "Let me check both salons. One moment.");
+
+        let responses =
+            gemini_function_responses(&calls, vec!["open".to_string(), "10:00".to_string()]);
+        assert_eq!(
+            responses,
+            json!({
+                "role": "function",
+                "parts": [
+                    {"functionResponse": {"name": "get_salon", "response": {"result": "open"}}},
+                    {"functionResponse": {
+                        "name": "get_availability",
+                        "response": {"result": "10:00"}
+                    }}
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn gemini_multi_part_answer_is_joined_in_order() {
+        let response: GeminiResponse = serde_json::from_value(json!({
+            "candidates": [{
+                "content": {
+                    "role": "model",
+                    "parts": [
+                        {"text": "Salon A is open 9-17. "},
+                        {"thought": true, "text": "the user asked about B too"},
+                        {"inlineData": {"mimeType": "image/png", "data": "AAAA"}},
+                        {"text": "Salon B is open 10-18."}
+                    ]
+                },
+                "finishReason": "STOP"
+            }]
+        }))
+        .unwrap();
+
+        let candidate = response.candidates.into_iter().next().unwrap();
+        assert_eq!(
+            gemini_text(&candidate.content.parts),
+            "Salon A is open 9-17. Salon B is open 10-18."
+        );
+    }
+
+    #[test]
+    fn gemini_candidate_without_calls_is_text_only() {
+        let parts = vec![json!({"text": "Hello"}), json!({"text": " there"})];
+        assert!(gemini_function_calls(&parts).is_empty());
+        assert_eq!(gemini_text(&parts), "Hello there");
+    }
+
+    #[test]
+    fn only_a_404_naming_the_model_means_model_unavailable() {
+        let not_found = reqwest::StatusCode::NOT_FOUND;
+        let gemini = r#"{"error": {"code": 404, "message": "models/gemini-0 is not found for API version v1beta", "status": "NOT_FOUND"}}"#;
+        assert!(is_model_unavailable(not_found, gemini));
+        let groq = r#"{"error": {"message": "The model `llama-0` does not exist", "code": "model_not_found"}}"#;
+        assert!(is_model_unavailable(not_found, groq));
+
+        // A wrong base URL or a proxy without the route
+        assert!(!is_model_unavailable(not_found, "404 page not found"));
+        assert!(!is_model_unavailable(
+            not_found,
+            "<html><body>Not Found</body></html>"
+        ));
+    }
+
+    /// A replayed tool exchange: a call, its result, and a result whose id
+    /// was lost.
+    fn tool_history() -> Vec<ChatMessage> {
+        vec![
+            user_message("Is Aura open?"),
+            ChatMessage {
+                role: "assistant".to_string(),
+                content: String::new(),
+                tool_calls: Some(vec![ToolCall {
+                    id: "call_1".to_string(),
+                    r#type: "function".to_string(),
+                    function: FunctionCall {
+                        name: "lookup".to_string(),
+                        arguments: json!({"salon": "Aura"}),
+                    },
+                }]),
+                tool_call_id: None,
+            },
+            ChatMessage {
+                role: "tool".to_string(),
+                content: "open 9-17".to_string(),
+                tool_calls: None,
+                tool_call_id: Some("call_1".to_string()),
+            },
+            ChatMessage {
+                role: "tool".to_string(),
+                content: "closed sundays".to_string(),
+                tool_calls: None,
+                tool_call_id: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn openai_payload_replays_tool_calls_and_results() {
+        let payload: Vec<_> = tool_history().iter().map(openai_message).collect();
+        assert_eq!(
+            payload[1],
+            json!({
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "lookup", "arguments": "{\"salon\":\"Aura\"}"}
+                }]
+            })
+        );
+        assert_eq!(
+            payload[2],
+            json!({"role": "tool", "tool_call_id": "call_1", "content": "open 9-17"})
+        );
+        assert_eq!(
+            payload[3],
+            json!({"role": "system", "content": "Tool result: closed sundays"})
+        );
+    }
+
+    #[test]
+    fn gemini_payload_replays_tool_calls_and_results() {
+        let contents = gemini_contents(&tool_history());
+        assert_eq!(
+            contents[1],
+            json!({
+                "role": "model",
+                "parts": [{"functionCall": {"name": "lookup", "args": {"salon": "Aura"}}}]
+            })
+        );
+        assert_eq!(
+            contents[2],
+            json!({
+                "role": "function",
+                "parts": [{
+                    "functionResponse": {"name": "lookup", "response": {"result": "open 9-17"}}
+                }]
+            })
+        );
+        assert_eq!(
+            contents[3],
+            json!({"role": "user", "parts": [{"text": "Tool result: closed sundays"}]})
+        );
+    }
+
+    #[test]
+    fn claude_payload_replays_tool_calls_and_results() {
+        let (system, history) = claude_messages(&tool_history());
+        assert!(system.is_empty());
+        assert_eq!(history.len(), 3);
+        assert_eq!(
+            history[1],
+            json!({
+                "role": "assistant",
+                "content": [{
+                    "type": "tool_use",
+                    "id": "call_1",
+                    "name": "lookup",
+                    "input": {"salon": "Aura"}
+                }]
+            })
+        );
+        // Both results share one user message, since roles must alternate
+        assert_eq!(
+            history[2],
+            json!({
+                "role": "user",
+                "content": [
+                    {"type": "tool_result", "tool_use_id": "call_1", "content": "open 9-17"},
+                    {"type": "text", "text": "Tool result: closed sundays"}
+                ]
+            })
+        );
+    }
+}
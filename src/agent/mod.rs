@@ -1,7 +1,19 @@
+pub mod context_provider;
 pub mod embeddings;
+pub mod indexer;
+pub mod json_repair;
 pub mod llm;
 pub mod orchestrator;
+pub mod outcomes;
+pub mod tool_results;
+pub mod tool_schemas;
+pub mod webhook;
 
-pub use embeddings::EmbeddingService;
+pub use context_provider::HttpContextProvider;
+pub use embeddings::{EmbeddingService, EmbeddingTask};
+pub use indexer::EmbeddingIndexer;
 pub use llm::LlmClient;
 pub use orchestrator::Orchestrator;
+pub use tool_results::ToolResultShaper;
+pub use tool_schemas::SchemaSanitizer;
+pub use webhook::WebhookNotifier;
@@ -1,22 +1,123 @@
-use crate::agent::{EmbeddingService, LlmClient};
-use crate::mcp::McpClient;
-use crate::models::{ChatMessage, ChatResponse};
+use crate::agent::context_provider::{ContextProvider, NoopContextProvider};
+use crate::agent::json_repair::repair_json;
+use crate::agent::llm::{ChunkSink, GenerationOptions, LlmResponse};
+use crate::agent::outcomes::RecentOutcomes;
+use crate::agent::{EmbeddingService, EmbeddingTask, LlmClient, WebhookNotifier};
+use crate::config::{EmptyResponseStorage, RagScope, RateLimitKey};
+use crate::error::classify;
+use crate::error::AgentError;
+use crate::mcp::openapi::tools_to_openapi;
+use crate::mcp::{
+    McpClient, McpClientError, McpPrompt, McpResource, McpResourceContents, ToolStatsSnapshot,
+};
+use crate::models::{
+    BatchChatResult, ChatMessage, ChatRequest, ChatResponse, ChatTrace, ConfirmResponse,
+    ConversationContext, DependencyHealth, EmbeddingStatus, HealthReport, ImportRequest,
+    ImportResponse, LlmStatus, McpStatus, MergeSessionsRequest, MergeSessionsResponse,
+    ModelPricing, PendingAction, SessionStats, StatusReport, TurnTimings,
+};
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryBudget;
 use crate::session::SessionManager;
 use crate::vector::VectorService;
 use anyhow::Result;
+use dashmap::DashMap;
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Mutex};
+use tokio_util::task::TaskTracker;
+use tracing::field::Empty;
+use tracing::{debug, error, info_span, warn, Instrument, Span};
+use uuid::Uuid;
+
+/// Values Groq accepts for `reasoning_format`.
+const REASONING_FORMATS: [&str; 3] = ["parsed", "hidden", "raw"];
+
+/// Receives a turn's response before it is stored, when replying first.
+type ReplySink<'a> = dyn Fn(&ChatResponse) + Send + Sync + 'a;
+
+/// How far back `GET /api/status` looks when reporting the error rate.
+const STATUS_WINDOW: Duration = Duration::from_secs(300);
+
+/// How long `GET /api/health` waits for each dependency.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
 
 pub struct Orchestrator {
     llm_client: LlmClient,
-    mcp_client: McpClient,
+    mcp_client: Arc<McpClient>,
     session_manager: SessionManager,
     vector_service: VectorService,
     embedding_service: EmbeddingService,
+    rag_scope: RagScope,
+    /// Roles whose messages are embedded for RAG.
+    embedded_roles: Vec<String>,
+    /// Roles RAG retrieves from; empty means all.
+    rag_roles: Vec<String>,
+    rag_min_similarity: f32,
+    trim_response: bool,
+    strip_code_fences: bool,
+    empty_response_storage: EmptyResponseStorage,
+    /// `(threshold, keep_recent)` when on-write compaction is enabled.
+    compaction: Option<(usize, usize)>,
+    /// Similarity above which an older exchange is dropped from the prompt
+    /// as redundant with a later one; `None` disables the pass.
+    history_compression: Option<f32>,
+    /// Roles of stored messages left out of the prompt.
+    history_excluded_roles: Vec<String>,
+    /// Content prefixes marking stored messages left out of the prompt.
+    history_excluded_prefixes: Vec<String>,
+    pending_action_ttl_minutes: u64,
+    default_response_style: Option<String>,
+    response_style_instructions: HashMap<String, String>,
+    json_repair: bool,
+    json_max_retries: u32,
+    session_locking: bool,
+    /// One lock per session with a request in flight; entries are removed
+    /// once nobody holds or waits for them.
+    session_locks: DashMap<String, Arc<Mutex<()>>>,
+    defer_embeddings: bool,
+    /// Reply used instead of a `content_filtered` error when set.
+    content_filter_reply: Option<String>,
+    max_concurrent_requests: usize,
+    /// Largest accepted `POST /api/chat/batch` array.
+    max_batch_size: usize,
+    stream_keepalive_secs: u64,
+    /// Bearer token for `GET /api/status`; the endpoint is off without one.
+    status_api_key: Option<String>,
+    /// Bearer token for admin endpoints; they are off without one.
+    admin_api_key: Option<String>,
+    /// Key required by the chat routes; they are open without one.
+    agent_api_key: Option<String>,
+    /// Per-client limit on chat requests; unlimited without one.
+    rate_limiter: Option<RateLimiter>,
+    rate_limit_key: RateLimitKey,
+    trust_forwarded_for: bool,
+    started_at: Instant,
+    recent_outcomes: RecentOutcomes,
+    /// Cap on retry time per chat turn; `None` leaves retries unbounded.
+    retry_budget: Option<Duration>,
+    respond_before_persist: bool,
+    /// Background work that must finish before shutdown (stores after an
+    /// early reply, streamed turns, webhooks).
+    tasks: TaskTracker,
+    /// Receives `chat.completed` events for sessions that opt in.
+    webhook: Option<Arc<WebhookNotifier>>,
+    /// Source of prompt context beyond RAG; a no-op unless configured.
+    context_provider: Arc<dyn ContextProvider>,
+    /// List the turn's tool calls with their arguments in `tools_used`.
+    return_tools_used: bool,
+    /// Prices of the configured model; turns are not costed without them.
+    pricing: Option<ModelPricing>,
+    total_cost_usd: std::sync::Mutex<f64>,
 }
 
 impl Orchestrator {
     pub fn new(
         llm_client: LlmClient,
-        mcp_client: McpClient,
+        mcp_client: Arc<McpClient>,
         session_manager: SessionManager,
         vector_service: VectorService,
         embedding_service: EmbeddingService,
@@ -27,39 +128,918 @@ impl Orchestrator {
             session_manager,
             vector_service,
             embedding_service,
+            rag_scope: RagScope::Isolated,
+            embedded_roles: vec!["user".to_string(), "assistant".to_string()],
+            rag_roles: Vec::new(),
+            rag_min_similarity: 0.7,
+            trim_response: true,
+            strip_code_fences: false,
+            empty_response_storage: EmptyResponseStorage::Skip,
+            compaction: None,
+            history_compression: None,
+            history_excluded_roles: Vec::new(),
+            history_excluded_prefixes: Vec::new(),
+            pending_action_ttl_minutes: 30,
+            default_response_style: None,
+            response_style_instructions: HashMap::new(),
+            json_repair: true,
+            json_max_retries: 1,
+            session_locking: true,
+            session_locks: DashMap::new(),
+            defer_embeddings: false,
+            content_filter_reply: None,
+            max_concurrent_requests: 4,
+            max_batch_size: 50,
+            stream_keepalive_secs: 15,
+            status_api_key: None,
+            admin_api_key: None,
+            agent_api_key: None,
+            rate_limiter: None,
+            rate_limit_key: RateLimitKey::Ip,
+            trust_forwarded_for: false,
+            started_at: Instant::now(),
+            recent_outcomes: RecentOutcomes::new(STATUS_WINDOW),
+            retry_budget: None,
+            respond_before_persist: false,
+            tasks: TaskTracker::new(),
+            webhook: None,
+            context_provider: Arc::new(NoopContextProvider),
+            return_tools_used: true,
+            pricing: None,
+            total_cost_usd: std::sync::Mutex::new(0.0),
+        }
+    }
+
+    /// POSTs finished turns of sessions whose request or stored metadata has
+    /// `"webhook": true` to this notifier.
+    pub fn with_webhook(mut self, webhook: Option<WebhookNotifier>) -> Self {
+        self.webhook = webhook.map(Arc::new);
+        self
+    }
+
+    /// Adds this provider's snippets to every prompt, next to RAG context.
+    /// Failures are logged and the turn proceeds without them.
+    pub fn with_context_provider(mut self, provider: impl ContextProvider + 'static) -> Self {
+        self.context_provider = Arc::new(provider);
+        self
+    }
+
+    /// Whether chat responses list the tools called and their arguments.
+    /// Arguments can hold customer details, so this may be turned off.
+    pub fn with_tools_used(mut self, enabled: bool) -> Self {
+        self.return_tools_used = enabled;
+        self
+    }
+
+    /// Costs each turn's token usage with these prices.
+    pub fn with_pricing(mut self, pricing: Option<ModelPricing>) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    /// Lets `POST /api/chat` reply before the turn is stored; see
+    /// `process_message_reply_first`.
+    pub fn with_respond_before_persist(mut self, enabled: bool) -> Self {
+        self.respond_before_persist = enabled;
+        self
+    }
+
+    pub fn respond_before_persist(&self) -> bool {
+        self.respond_before_persist
+    }
+
+    /// Spawns work that shutdown waits for, unlike a bare `tokio::spawn`.
+    pub fn spawn_tracked<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.spawn(task);
+    }
+
+    /// Tracker of `spawn_tracked` tasks, for draining them on shutdown.
+    pub fn task_tracker(&self) -> TaskTracker {
+        self.tasks.clone()
+    }
+
+    /// Bounds the time a single chat turn spends on retries, across the LLM,
+    /// MCP and embedding clients. 0 disables the budget.
+    pub fn with_retry_budget(mut self, budget_ms: u64) -> Self {
+        self.retry_budget = (budget_ms > 0).then(|| Duration::from_millis(budget_ms));
+        self
+    }
+
+    async fn with_retry_budget_scope<T>(&self, operation: impl Future<Output = T>) -> T {
+        match self.retry_budget {
+            Some(budget) => RetryBudget::new(budget).scope(operation).await,
+            None => operation.await,
+        }
+    }
+
+    pub fn with_status_api_key(mut self, key: Option<String>) -> Self {
+        self.status_api_key = key;
+        self
+    }
+
+    pub fn status_api_key(&self) -> Option<&str> {
+        self.status_api_key.as_deref()
+    }
+
+    pub fn with_agent_api_key(mut self, key: Option<String>) -> Self {
+        self.agent_api_key = key;
+        self
+    }
+
+    pub fn agent_api_key(&self) -> Option<&str> {
+        self.agent_api_key.as_deref()
+    }
+
+    /// Allows each client `per_minute` chat requests a minute, identified by
+    /// `key`; 0 disables the limit.
+    pub fn with_rate_limit(mut self, per_minute: u32, key: RateLimitKey) -> Self {
+        self.rate_limiter = (per_minute > 0).then(|| RateLimiter::new(per_minute));
+        self.rate_limit_key = key;
+        self
+    }
+
+    pub fn rate_limit(&self) -> Option<(&RateLimiter, RateLimitKey)> {
+        self.rate_limiter
+            .as_ref()
+            .map(|limiter| (limiter, self.rate_limit_key))
+    }
+
+    /// Identify clients by the first `X-Forwarded-For` address rather than
+    /// the connecting peer.
+    pub fn with_trusted_forwarded_for(mut self, trusted: bool) -> Self {
+        self.trust_forwarded_for = trusted;
+        self
+    }
+
+    pub fn trusts_forwarded_for(&self) -> bool {
+        self.trust_forwarded_for
+    }
+
+    pub fn with_admin_api_key(mut self, key: Option<String>) -> Self {
+        self.admin_api_key = key;
+        self
+    }
+
+    pub fn admin_api_key(&self) -> Option<&str> {
+        self.admin_api_key.as_deref()
+    }
+
+    /// Operator-facing summary of the agent's internal state.
+    pub fn status(&self) -> StatusReport {
+        let (hits, misses) = self.mcp_client.tools_cache_stats();
+        let mcp = McpStatus {
+            healthy: self.mcp_client.is_healthy(),
+            protocol_version: self.mcp_client.protocol_version(),
+            degraded_mode: self.llm_client.mcp_degraded_mode(),
+            tools_cache_hits: hits,
+            tools_cache_misses: misses,
+            tools_cache_hit_rate: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+        };
+        let embeddings = EmbeddingStatus {
+            deferred: self.defer_embeddings,
+            reindex_needed: self.vector_service.reindex_needed(),
+        };
+        let status = if mcp.healthy && !embeddings.reindex_needed {
+            "ok"
+        } else {
+            "degraded"
+        };
+
+        StatusReport {
+            status: status.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            database: self.session_manager.pool_status(),
+            mcp,
+            llm: LlmStatus {
+                provider: self.llm_client.provider_name().to_string(),
+                model: self.llm_client.model().to_string(),
+                total_cost_usd: *self.total_cost_usd.lock().unwrap(),
+            },
+            embeddings,
+            requests: self.recent_outcomes.summary(),
+        }
+    }
+
+    fn record_outcome<T>(&self, result: &Result<T>) {
+        self.recent_outcomes
+            .record(result.as_ref().err().map(|e| classify(e).1));
+    }
+
+    /// Interval for keepalive events on streamed chats; 0 disables them.
+    pub fn with_stream_keepalive(mut self, secs: u64) -> Self {
+        self.stream_keepalive_secs = secs;
+        self
+    }
+
+    pub fn stream_keepalive_secs(&self) -> u64 {
+        self.stream_keepalive_secs
+    }
+
+    /// Caps how many items of a batch request are processed at once.
+    pub fn with_max_concurrent_requests(mut self, max: usize) -> Self {
+        self.max_concurrent_requests = max.max(1);
+        self
+    }
+
+    /// Caps how many items one batch request may carry.
+    pub fn with_max_batch_size(mut self, max: usize) -> Self {
+        self.max_batch_size = max;
+        self
+    }
+
+    pub fn max_batch_size(&self) -> usize {
+        self.max_batch_size
+    }
+
+    pub fn with_content_filter_reply(mut self, reply: Option<String>) -> Self {
+        self.content_filter_reply = reply;
+        self
+    }
+
+    /// Queue message embeddings for the background indexer instead of
+    /// embedding them during the request.
+    pub fn with_deferred_embeddings(mut self, enabled: bool) -> Self {
+        self.defer_embeddings = enabled;
+        self
+    }
+
+    pub fn with_session_locking(mut self, enabled: bool) -> Self {
+        self.session_locking = enabled;
+        self
+    }
+
+    pub fn with_json_output(mut self, repair: bool, max_retries: u32) -> Self {
+        self.json_repair = repair;
+        self.json_max_retries = max_retries;
+        self
+    }
+
+    pub fn with_response_styles(
+        mut self,
+        default_style: Option<String>,
+        instructions: HashMap<String, String>,
+    ) -> Self {
+        self.default_response_style = default_style;
+        self.response_style_instructions = instructions;
+        self
+    }
+
+    pub fn with_pending_action_ttl(mut self, ttl_minutes: u64) -> Self {
+        self.pending_action_ttl_minutes = ttl_minutes;
+        self
+    }
+
+    pub fn with_compaction(mut self, threshold: usize, keep_recent: usize) -> Self {
+        self.compaction = Some((threshold, keep_recent));
+        self
+    }
+
+    /// Before each LLM call, drops older exchanges whose embedding is at
+    /// least `threshold` similar to a later one. Stored history is untouched.
+    pub fn with_history_compression(mut self, threshold: f32) -> Self {
+        self.history_compression = Some(threshold);
+        self
+    }
+
+    /// Leaves stored messages with these roles, or starting with one of these
+    /// prefixes, out of the history sent to the model. They stay stored.
+    pub fn with_history_filter(mut self, roles: Vec<String>, prefixes: Vec<String>) -> Self {
+        self.history_excluded_roles = roles;
+        self.history_excluded_prefixes = prefixes;
+        self
+    }
+
+    pub fn with_empty_response_storage(mut self, storage: EmptyResponseStorage) -> Self {
+        self.empty_response_storage = storage;
+        self
+    }
+
+    pub fn with_response_cleanup(mut self, trim_response: bool, strip_code_fences: bool) -> Self {
+        self.trim_response = trim_response;
+        self.strip_code_fences = strip_code_fences;
+        self
+    }
+
+    pub fn with_rag_scope(mut self, rag_scope: RagScope) -> Self {
+        self.rag_scope = rag_scope;
+        self
+    }
+
+    /// Which messages of a turn (`user`, `assistant`, `tool`) are embedded.
+    pub fn with_embedded_roles(mut self, roles: Vec<String>) -> Self {
+        self.embedded_roles = roles;
+        self
+    }
+
+    /// Limits RAG to snippets from messages with these roles.
+    pub fn with_rag_roles(mut self, roles: Vec<String>) -> Self {
+        self.rag_roles = roles;
+        self
+    }
+
+    /// Drops RAG snippets whose cosine similarity to the message is lower.
+    pub fn with_rag_min_similarity(mut self, min_similarity: f32) -> Self {
+        self.rag_min_similarity = min_similarity;
+        self
+    }
+
+    /// Probes the database and the MCP server concurrently. The database is
+    /// always critical; MCP only when degraded mode is off.
+    pub async fn health(&self) -> HealthReport {
+        let (database, mcp) = tokio::join!(
+            probe_dependency(self.session_manager.ping_database()),
+            probe_dependency(self.mcp_client.ping())
+        );
+        let status = if database.error.is_some() {
+            "unavailable"
+        } else if mcp.error.is_some() {
+            if self.mcp_degraded_mode() {
+                "degraded"
+            } else {
+                "unavailable"
+            }
+        } else {
+            "ok"
+        };
+        HealthReport {
+            status: status.to_string(),
+            database,
+            mcp,
         }
     }
 
+    pub fn mcp_healthy(&self) -> bool {
+        self.mcp_client.is_healthy()
+    }
+
+    /// Whether chats keep working (without tools) while MCP is down.
+    pub fn mcp_degraded_mode(&self) -> bool {
+        self.llm_client.mcp_degraded_mode()
+    }
+
+    /// The resources the MCP server offers.
+    pub async fn list_resources(&self) -> Result<Vec<McpResource>> {
+        self.mcp_client.list_resources().await
+    }
+
+    /// Reads one MCP resource by URI.
+    pub async fn read_resource(&self, uri: &str) -> Result<Vec<McpResourceContents>> {
+        self.mcp_client.read_resource(uri).await
+    }
+
+    /// The prompt templates the MCP server offers.
+    pub async fn list_prompts(&self) -> Result<Vec<McpPrompt>> {
+        self.mcp_client.list_prompts().await
+    }
+
+    /// Renders an MCP prompt into chat messages, e.g. to seed a session
+    /// through `import_session`.
+    pub async fn render_prompt(
+        &self,
+        name: &str,
+        arguments: &HashMap<String, String>,
+    ) -> Result<Vec<ChatMessage>> {
+        let messages = self.mcp_client.get_prompt(name, arguments).await?;
+        Ok(messages
+            .into_iter()
+            .map(|message| ChatMessage {
+                role: message.role,
+                content: message.content.text(),
+                tool_calls: None,
+                tool_call_id: None,
+            })
+            .collect())
+    }
+
+    /// The MCP tools as an OpenAPI document, for gateways and codegen.
+    pub async fn tools_openapi(&self) -> Result<serde_json::Value> {
+        let tools = self.mcp_client.cached_tools().await?;
+        Ok(tools_to_openapi(&tools))
+    }
+
+    /// Refetches the MCP tool list, bypassing the cache. Returns the names of
+    /// the tools now offered to the model.
+    pub async fn refresh_tools(&self) -> Result<Vec<String>> {
+        let tools = self.mcp_client.refresh_tools().await?;
+        Ok(tools.into_iter().map(|tool| tool.name).collect())
+    }
+
+    pub fn tool_stats(&self) -> Vec<ToolStatsSnapshot> {
+        self.mcp_client.tool_stats()
+    }
+
+    pub async fn session_stats(&self, session_id: &str) -> Result<Option<SessionStats>> {
+        self.session_manager.get_session_stats(session_id).await
+    }
+
+    /// Permanently deletes a session and its embeddings, waiting for any
+    /// in-flight turn of it to finish first. Returns `false` if it didn't
+    /// exist.
+    pub async fn delete_session(&self, session_id: &str) -> Result<bool> {
+        self.with_session_lock(session_id, self.session_manager.delete_session(session_id))
+            .await
+    }
+
+    /// Recovers a conversation split across several session ids by folding
+    /// the source sessions into the target (see
+    /// `SessionManager::merge_sessions`). Only the target session is locked,
+    /// so run it while the source sessions are idle.
+    pub async fn merge_sessions(
+        &self,
+        request: MergeSessionsRequest,
+    ) -> Result<MergeSessionsResponse> {
+        let MergeSessionsRequest { target, sources } = request;
+        if sources.is_empty() {
+            return Err(AgentError::BadRequest("sources must not be empty".to_string()).into());
+        }
+        for id in std::iter::once(&target).chain(&sources) {
+            if Uuid::parse_str(id).is_err() {
+                return Err(AgentError::BadRequest(format!("'{}' is not a valid UUID", id)).into());
+            }
+        }
+        let mut seen = std::collections::HashSet::new();
+        if let Some(duplicate) = std::iter::once(&target)
+            .chain(&sources)
+            .find(|id| !seen.insert(id.to_lowercase()))
+        {
+            return Err(AgentError::BadRequest(format!(
+                "Session {} is listed more than once",
+                duplicate
+            ))
+            .into());
+        }
+
+        let context = self
+            .with_session_lock(
+                &target,
+                self.session_manager.merge_sessions(&target, &sources),
+            )
+            .await?;
+        Ok(MergeSessionsResponse {
+            session_id: target,
+            merged: sources.len(),
+            messages: context.messages.len(),
+        })
+    }
+
+    /// Stored messages of a session; `None` if it doesn't exist.
+    pub async fn session_history(&self, session_id: &str) -> Result<Option<Vec<ChatMessage>>> {
+        Ok(self
+            .session_manager
+            .get_session(session_id)
+            .await?
+            .map(|context| context.messages))
+    }
+
+    /// Executes a tool call the user confirmed. Returns `None` when there is no
+    /// live pending action for the token in this session.
+    pub async fn confirm_action(
+        &self,
+        session_id: &str,
+        token: &str,
+    ) -> Result<Option<ConfirmResponse>> {
+        self.with_session_lock(session_id, self.confirm_action_unlocked(session_id, token))
+            .await
+    }
+
+    async fn confirm_action_unlocked(
+        &self,
+        session_id: &str,
+        token: &str,
+    ) -> Result<Option<ConfirmResponse>> {
+        let Some(call) = self
+            .session_manager
+            .claim_pending_action(session_id, token, self.pending_action_ttl_minutes)
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let result = match self
+            .mcp_client
+            .call_tool_text(&call.name, &call.arguments)
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                log_mcp_error(session_id, &e);
+                self.session_manager
+                    .resolve_pending_action(token, "failed", &e.to_string())
+                    .await?;
+                return Err(e);
+            }
+        };
+        self.session_manager
+            .resolve_pending_action(token, "resolved", &result)
+            .await?;
+
+        // Let the model see the outcome on the next turn
+        self.session_manager
+            .append_message(
+                session_id,
+                ChatMessage {
+                    role: "assistant".to_string(),
+                    content: format!("Confirmed and executed {}: {}", call.name, result),
+                    tool_calls: None,
+                    tool_call_id: None,
+                },
+            )
+            .await?;
+
+        Ok(Some(ConfirmResponse {
+            session_id: session_id.to_string(),
+            tool: call.name,
+            result,
+        }))
+    }
+
+    /// Stores a conversation migrated from another system, optionally
+    /// queueing its messages in `RAG_EMBED_ROLES` for embedding. Waits for
+    /// any in-flight turn of the session, so the turn can't overwrite the
+    /// import afterwards.
+    pub async fn import_session(&self, request: ImportRequest) -> Result<ImportResponse> {
+        validate_imported_messages(&request.messages)?;
+
+        // Embedding happens in the background indexer, so a slow or failing
+        // embedding API can't fail an import that is already stored
+        let embed_roles: Vec<&str> = if request.embed {
+            self.embedded_roles.iter().map(String::as_str).collect()
+        } else {
+            Vec::new()
+        };
+        let (context, embedded) = self
+            .with_session_lock(
+                &request.session_id,
+                self.session_manager.import_session(
+                    &request.session_id,
+                    request.messages,
+                    request.metadata.as_ref(),
+                    &embed_roles,
+                ),
+            )
+            .await?;
+
+        Ok(ImportResponse {
+            session_id: request.session_id,
+            imported: context.messages.len(),
+            embedded,
+        })
+    }
+
     pub async fn process_message(
         &self,
-        message: String,
+        request: ChatRequest,
         session_id: String,
     ) -> Result<ChatResponse> {
+        let lock_key = session_id.clone();
+        let result = self
+            .with_session_lock(
+                &lock_key,
+                self.with_retry_budget_scope(
+                    self.process_message_unlocked(request, session_id, None, None),
+                ),
+            )
+            .await;
+        self.record_outcome(&result);
+        result
+    }
+
+    /// Like `process_message`, but passes the answer text to `on_chunk` as
+    /// the model streams it. The returned response (cleaned, as stored) is
+    /// only available once the turn has been persisted.
+    pub async fn process_message_stream(
+        &self,
+        request: ChatRequest,
+        session_id: String,
+        on_chunk: &ChunkSink,
+    ) -> Result<ChatResponse> {
+        let lock_key = session_id.clone();
+        let result = self
+            .with_session_lock(
+                &lock_key,
+                self.with_retry_budget_scope(self.process_message_unlocked(
+                    request,
+                    session_id,
+                    Some(on_chunk),
+                    None,
+                )),
+            )
+            .await;
+        self.record_outcome(&result);
+        result
+    }
+
+    /// Like `process_message`, but sends the response to `reply` as soon as
+    /// it is ready and stores the turn afterwards, still holding the session
+    /// lock. Errors before that point are sent instead; storage failures
+    /// after it are only logged, so a reply the client received can be
+    /// missing from history (at-most-once persistence).
+    pub async fn process_message_reply_first(
+        &self,
+        request: ChatRequest,
+        session_id: String,
+        reply: oneshot::Sender<Result<ChatResponse>>,
+    ) {
+        let reply = std::sync::Mutex::new(Some(reply));
+        let send_reply = |response: &ChatResponse| {
+            if let Some(reply) = reply.lock().unwrap().take() {
+                let _ = reply.send(Ok(response.clone()));
+            }
+        };
+        let lock_key = session_id.clone();
+        let result = self
+            .with_session_lock(
+                &lock_key,
+                self.with_retry_budget_scope(self.process_message_unlocked(
+                    request,
+                    session_id,
+                    None,
+                    Some(&send_reply),
+                )),
+            )
+            .await;
+        self.record_outcome(&result);
+        let unsent = reply.lock().unwrap().take();
+        if let Some(reply) = unsent {
+            let _ = reply.send(result);
+        }
+    }
+
+    /// Processes independent chat requests concurrently (bounded by
+    /// `max_concurrent_requests`), returning one result per request in order.
+    pub async fn process_batch(&self, requests: Vec<ChatRequest>) -> Vec<BatchChatResult> {
+        let items: Vec<_> = requests
+            .into_iter()
+            .map(|request| self.process_batch_item(request))
+            .collect();
+        stream::iter(items)
+            .buffered(self.max_concurrent_requests)
+            .collect()
+            .await
+    }
+
+    async fn process_batch_item(&self, request: ChatRequest) -> BatchChatResult {
+        let session_id = request
+            .session_id
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        // Each item gets its own span, so request logs tell items apart
+        let span = info_span!("batch_item", session_id = %session_id, tool_calls = Empty);
+        match self
+            .process_message(request, session_id.clone())
+            .instrument(span)
+            .await
+        {
+            Ok(response) => BatchChatResult::Ok(response),
+            Err(e) => {
+                warn!("Batch item for session {} failed: {}", session_id, e);
+                let (_, code) = classify(&e);
+                BatchChatResult::Error {
+                    session_id,
+                    code: code.to_string(),
+                    message: e.to_string(),
+                }
+            }
+        }
+    }
+
+    /// Runs `operation` while holding the session's lock (when locking is
+    /// enabled), so turns within one session never interleave.
+    async fn with_session_lock<T>(
+        &self,
+        session_id: &str,
+        operation: impl Future<Output = T>,
+    ) -> T {
+        if !self.session_locking {
+            return operation.await;
+        }
+
+        with_lock_entry(
+            &self.session_locks,
+            &session_lock_key(session_id),
+            operation,
+        )
+        .await
+    }
+
+    async fn process_message_unlocked(
+        &self,
+        request: ChatRequest,
+        session_id: String,
+        on_chunk: Option<&ChunkSink>,
+        on_reply: Option<&ReplySink<'_>>,
+    ) -> Result<ChatResponse> {
+        let started = Instant::now();
+        let ChatRequest {
+            message,
+            metadata,
+            include_context,
+            include_trace,
+            response_style,
+            persist,
+            response_format,
+            reasoning_format,
+            tool_choice,
+            ..
+        } = request;
+        let persist = persist.unwrap_or(true);
+
+        let json_output = match response_format.as_deref().map(str::to_lowercase).as_deref() {
+            None | Some("text") => false,
+            // Streamed text can't be repaired or retried after the fact
+            Some("json") if on_chunk.is_some() => {
+                return Err(AgentError::BadRequest(
+                    "response_format json is not supported for streamed chats".to_string(),
+                )
+                .into());
+            }
+            Some("json") => true,
+            Some(other) => {
+                return Err(AgentError::BadRequest(format!(
+                    "Unknown response_format '{}' (expected text or json)",
+                    other
+                ))
+                .into());
+            }
+        };
+
+        if let Some(format) = &reasoning_format {
+            if !REASONING_FORMATS.contains(&format.as_str()) {
+                return Err(AgentError::BadRequest(format!(
+                    "Unknown reasoning_format '{}' (expected parsed, hidden or raw)",
+                    format
+                ))
+                .into());
+            }
+        }
+        let require_tool = match tool_choice.as_deref().map(str::to_lowercase).as_deref() {
+            None => None,
+            Some("auto") => Some(false),
+            Some("required") => Some(true),
+            Some(other) => {
+                return Err(AgentError::BadRequest(format!(
+                    "Unknown tool_choice '{}' (expected auto or required)",
+                    other
+                ))
+                .into());
+            }
+        };
+        let options = GenerationOptions {
+            reasoning_format,
+            require_tool,
+        };
+
+        if message.trim().is_empty() {
+            return Err(AgentError::BadRequest("message must not be empty".to_string()).into());
+        }
+
+        let style_instruction = match response_style.or_else(|| self.default_response_style.clone())
+        {
+            Some(style) => Some(
+                self.response_style_instructions
+                    .get(&style.to_lowercase())
+                    .cloned()
+                    .ok_or_else(|| {
+                        AgentError::BadRequest(format!("Unknown response_style '{}'", style))
+                    })?,
+            ),
+            None => None,
+        };
+
         // 1. Load conversation context
         let context = self
             .session_manager
             .get_or_create_session(&session_id)
             .await?;
+        let notify_webhook = self.webhook.is_some()
+            && (webhook_opt_in(metadata.as_ref()) || webhook_opt_in(Some(&context.metadata)));
 
-        // 2. Optional: RAG for context enhancement
-        let embedding = self.embedding_service.generate_embedding(&message).await?;
-        let similar_context = self
-            .vector_service
-            .retrieve_context_for_rag(&embedding, 5)
-            .await?;
+        // 2. Optional: RAG for context enhancement, alongside external context
+        let mut timings = TurnTimings::default();
+        // Stored metadata, overridden by the request's, for the context provider
+        let mut session_metadata = ConversationContext::new(session_id.clone());
+        session_metadata.merge_metadata(&context.metadata);
+        if let Some(metadata) = &metadata {
+            session_metadata.merge_metadata(metadata);
+        }
+        session_metadata.merge_metadata(&serde_json::json!({ "session_id": session_id }));
+        let session_metadata = session_metadata.metadata;
+        let retrieve_rag = async {
+            Ok::<_, anyhow::Error>(match self.rag_scope {
+                RagScope::None => Vec::new(),
+                scope => {
+                    let phase_started = Instant::now();
+                    let query_embedding = self
+                        .embedding_service
+                        .generate_embedding(&message, EmbeddingTask::RetrievalQuery)
+                        .await?;
+                    timings.embedding_ms = phase_started.elapsed().as_millis() as u64;
+
+                    let phase_started = Instant::now();
+                    let session_filter =
+                        (scope == RagScope::Isolated).then_some(session_id.as_str());
+                    let hits = self
+                        .vector_service
+                        .retrieve_context_for_rag(
+                            &query_embedding,
+                            5,
+                            session_filter,
+                            &self.rag_roles,
+                            self.rag_min_similarity,
+                        )
+                        .await?;
+                    timings.rag_ms = phase_started.elapsed().as_millis() as u64;
+                    hits
+                }
+            })
+        };
+        let (similar_context, external_context) = tokio::join!(
+            retrieve_rag,
+            self.context_provider.fetch_context(&session_metadata)
+        );
+        let similar_context = similar_context?;
+        // External context is an enrichment; the turn goes on without it
+        let external_context = external_context.unwrap_or_else(|e| {
+            warn!("Context provider failed for {}: {}", session_id, e);
+            Vec::new()
+        });
+        if !similar_context.is_empty() {
+            debug!(
+                "RAG retrieved {} snippets for {} (scores: {})",
+                similar_context.len(),
+                session_id,
+                similar_context
+                    .iter()
+                    .map(|hit| format!("{:.3}", hit.score))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
 
         // 3. Build messages with context
-        let mut messages = context.messages.clone();
+        let history: Vec<ChatMessage> = context
+            .messages
+            .iter()
+            .filter(|message| self.sends_to_llm(message))
+            .cloned()
+            .collect();
+        let mut messages = match self.history_compression {
+            Some(threshold) => self.compress_history(&session_id, history, threshold).await,
+            None => history,
+        };
+        let mut context_sections = Vec::new();
         if !similar_context.is_empty() {
+            context_sections.push(format!(
+                "Relevant context from past conversations:\n{}",
+                similar_context
+                    .iter()
+                    .map(|hit| hit.text.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
+        if !external_context.is_empty() {
+            context_sections.push(format!(
+                "Context from external sources:\n{}",
+                external_context.join("\n")
+            ));
+        }
+        if !context_sections.is_empty() {
             messages.insert(
                 0,
                 ChatMessage {
                     role: "system".to_string(),
-                    content: format!(
-                        "Relevant context from past conversations:\n{}",
-                        similar_context.join("\n")
-                    ),
+                    content: context_sections.join("\n\n"),
                     tool_calls: None,
+                    tool_call_id: None,
+                },
+            );
+        }
+        if let Some(instruction) = style_instruction {
+            messages.insert(
+                0,
+                ChatMessage {
+                    role: "system".to_string(),
+                    content: instruction,
+                    tool_calls: None,
+                    tool_call_id: None,
+                },
+            );
+        }
+        if json_output {
+            messages.insert(
+                0,
+                ChatMessage {
+                    role: "system".to_string(),
+                    content: "Respond with only a valid JSON object or array, with no \
+                              surrounding text or markdown."
+                        .to_string(),
+                    tool_calls: None,
+                    tool_call_id: None,
                 },
             );
         }
@@ -67,27 +1047,790 @@ impl Orchestrator {
             role: "user".to_string(),
             content: message.clone(),
             tool_calls: None,
+            tool_call_id: None,
         });
 
         // 4. LLM handles everything via MCP tools - no manual routing!
-        let response = self
-            .llm_client
-            .generate_with_mcp_tools(&messages, &self.mcp_client)
+        let llm_started = Instant::now();
+        let generated = match on_chunk {
+            Some(on_chunk) => {
+                self.llm_client
+                    .generate_with_mcp_tools_stream(&messages, &self.mcp_client, &options, on_chunk)
+                    .await
+            }
+            None => {
+                self.llm_client
+                    .generate_with_mcp_tools(&messages, &self.mcp_client, &options)
+                    .await
+            }
+        };
+        let mut llm_response = match generated {
+            Ok(llm_response) => llm_response,
+            Err(e) => match (e.downcast_ref::<AgentError>(), &self.content_filter_reply) {
+                (Some(AgentError::ContentFiltered(reason)), Some(reply)) => {
+                    warn!("Replying with CONTENT_FILTER_REPLY: {}", reason);
+                    if let Some(on_chunk) = on_chunk {
+                        on_chunk(reply);
+                    }
+                    LlmResponse {
+                        content: reply.clone(),
+                        ..LlmResponse::default()
+                    }
+                }
+                _ => {
+                    log_mcp_error(&session_id, &e);
+                    return Err(e);
+                }
+            },
+        };
+        let mut response = self.clean_response(std::mem::take(&mut llm_response.content));
+        if json_output {
+            response = self
+                .ensure_json(&mut messages, response, &mut llm_response, &options)
+                .await?;
+        }
+        timings.tool_ms = llm_response.tool_time.as_millis() as u64;
+        timings.llm_ms = llm_started
+            .elapsed()
+            .saturating_sub(llm_response.tool_time)
+            .as_millis() as u64;
+
+        // 5. Persist tool calls awaiting user confirmation
+        let mut pending_actions: Vec<PendingAction> = Vec::new();
+        for call in &llm_response.deferred_calls {
+            pending_actions.push(
+                self.session_manager
+                    .create_pending_action(&session_id, call)
+                    .await?,
+            );
+        }
+
+        let cost_usd = self
+            .pricing
+            .map(|pricing| pricing.cost(&llm_response.usage));
+        if let Some(cost) = cost_usd {
+            *self.total_cost_usd.lock().unwrap() += cost;
+        }
+
+        timings.total_ms = started.elapsed().as_millis() as u64;
+        Span::current().record("tool_calls", llm_response.tool_calls);
+        let mut chat_response = ChatResponse {
+            response,
+            session_id,
+            context: include_context.then_some(similar_context),
+            pending_actions,
+            trace: include_trace.then_some(ChatTrace {
+                available_tools: llm_response.available_tools,
+                called_tools: llm_response
+                    .called_tools
+                    .iter()
+                    .map(|call| call.name.clone())
+                    .collect(),
+            }),
+            timings: include_trace.then_some(timings),
+            // Providers that don't report usage leave it all zero
+            usage: (llm_response.usage.total_tokens > 0).then_some(llm_response.usage),
+            cost_usd: cost_usd.filter(|_| include_trace),
+            tools_used: if self.return_tools_used {
+                llm_response.called_tools.clone()
+            } else {
+                Vec::new()
+            },
+        };
+        let session_id = chat_response.session_id.clone();
+        if let Some(on_reply) = on_reply {
+            on_reply(&chat_response);
+        }
+
+        // 6-7. Store conversation and embedding, unless the caller opted out
+        if persist {
+            let persist_started = Instant::now();
+            if let Err(e) = self
+                .persist_turn(
+                    &session_id,
+                    &message,
+                    &chat_response.response,
+                    &llm_response.tool_results,
+                    metadata.as_ref(),
+                )
+                .await
+            {
+                if on_reply.is_none() {
+                    return Err(e);
+                }
+                error!(
+                    "Failed to store turn for {} after replying: {}",
+                    session_id, e
+                );
+            }
+            if let (Some(timings), None) = (&mut chat_response.timings, on_reply) {
+                timings.persistence_ms = Some(persist_started.elapsed().as_millis() as u64);
+                timings.total_ms = started.elapsed().as_millis() as u64;
+            }
+        }
+
+        // 8-9. Stats and the webhook describe stored turns, so an ephemeral one
+        // skips them too
+        if persist {
+            // 8. Record turn stats (best-effort, never fails the chat)
+            if let Err(e) = self
+                .session_manager
+                .record_turn_stats(
+                    &session_id,
+                    started.elapsed().as_millis() as u64,
+                    &llm_response.usage,
+                    llm_response.tool_calls,
+                    cost_usd,
+                )
+                .await
+            {
+                warn!("Failed to record turn stats for {}: {}", session_id, e);
+            }
+
+            // 9. Notify the webhook in the background, if the session opted in
+            if let (Some(webhook), true) = (&self.webhook, notify_webhook) {
+                let webhook = webhook.clone();
+                let payload = serde_json::json!({
+                    "event": "chat.completed",
+                    "session_id": session_id,
+                    "message": message,
+                    "response": chat_response.response,
+                    "called_tools": llm_response
+                        .called_tools
+                        .iter()
+                        .map(|call| call.name.as_str())
+                        .collect::<Vec<_>>(),
+                    "pending_actions": chat_response.pending_actions,
+                    "completed_at": chrono::Utc::now().to_rfc3339(),
+                });
+                self.spawn_tracked(async move { webhook.notify(&payload).await });
+            }
+        }
+
+        Ok(chat_response)
+    }
+
+    /// Stores a finished turn and the embeddings of its messages. Any
+    /// response path, including a streaming one, must complete this before
+    /// telling the client the turn is done, so a reply the user saw is never
+    /// missing from history. The only exception is the opt-in
+    /// `process_message_reply_first`.
+    async fn persist_turn(
+        &self,
+        session_id: &str,
+        message: &str,
+        response: &str,
+        tool_results: &[String],
+        metadata: Option<&serde_json::Value>,
+    ) -> Result<()> {
+        let assistant_message = self.assistant_message_to_store(response);
+        let stored = self
+            .session_manager
+            .add_message(session_id, message, assistant_message, metadata)
             .await?;
 
-        // 5. Store conversation
+        if let Some((threshold, keep_recent)) = self.compaction {
+            if stored.messages.len() > threshold {
+                if let Err(e) = self
+                    .compact_history(session_id, stored.messages, keep_recent)
+                    .await
+                {
+                    warn!("Failed to compact history for {}: {}", session_id, e);
+                }
+            }
+        }
+
+        let documents: Vec<(&str, &str)> = std::iter::once(("user", message))
+            .chain(assistant_message.map(|reply| ("assistant", reply)))
+            .chain(tool_results.iter().map(|result| ("tool", result.as_str())))
+            .filter(|(role, text)| {
+                !text.trim().is_empty() && self.embedded_roles.iter().any(|r| r == role)
+            })
+            .collect();
+        if documents.is_empty() {
+            return Ok(());
+        }
+
+        if self.defer_embeddings {
+            for (role, text) in documents {
+                self.vector_service
+                    .queue_conversation_embedding(session_id, role, text)
+                    .await?;
+            }
+        } else {
+            let texts: Vec<String> = documents.iter().map(|(_, text)| text.to_string()).collect();
+            let embeddings = self
+                .embedding_service
+                .generate_embeddings_batch(&texts, EmbeddingTask::RetrievalDocument)
+                .await?;
+            for ((role, text), embedding) in documents.iter().zip(&embeddings) {
+                self.vector_service
+                    .store_conversation_embedding(session_id, role, text, embedding)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `response` as compact JSON, repairing it if enabled and
+    /// otherwise asking the model again up to `json_max_retries` times.
+    /// Usage and tool calls of the extra rounds are added to `llm_response`.
+    async fn ensure_json(
+        &self,
+        messages: &mut Vec<ChatMessage>,
+        mut response: String,
+        llm_response: &mut LlmResponse,
+        options: &GenerationOptions,
+    ) -> Result<String> {
+        let mut attempt = 0;
+        loop {
+            let parsed = if self.json_repair {
+                repair_json(&response)
+            } else {
+                serde_json::from_str::<serde_json::Value>(response.trim()).ok()
+            };
+            if let Some(value) = parsed {
+                return Ok(value.to_string());
+            }
+            if attempt >= self.json_max_retries {
+                return Err(
+                    AgentError::Upstream("Model did not return valid JSON".to_string()).into(),
+                );
+            }
+            attempt += 1;
+            warn!(
+                "Model reply was not valid JSON, asking again ({}/{})",
+                attempt, self.json_max_retries
+            );
+
+            messages.push(ChatMessage {
+                role: "assistant".to_string(),
+                content: response,
+                tool_calls: None,
+                tool_call_id: None,
+            });
+            messages.push(ChatMessage {
+                role: "user".to_string(),
+                content: "Your previous reply was not valid JSON. Reply again with only the \
+                          JSON, no other text."
+                    .to_string(),
+                tool_calls: None,
+                tool_call_id: None,
+            });
+
+            let retry = self
+                .llm_client
+                .generate_with_mcp_tools(messages, &self.mcp_client, options)
+                .await?;
+            llm_response.usage.add(retry.usage);
+            llm_response.tool_calls += retry.tool_calls;
+            llm_response.called_tools.extend(retry.called_tools);
+            llm_response.tool_time += retry.tool_time;
+            llm_response.deferred_calls.extend(retry.deferred_calls);
+            response = self.clean_response(retry.content);
+        }
+    }
+
+    /// Replaces all but the last `keep_recent` stored messages with a single
+    /// summary message, permanently shrinking the stored history.
+    async fn compact_history(
+        &self,
+        session_id: &str,
+        messages: Vec<ChatMessage>,
+        keep_recent: usize,
+    ) -> Result<()> {
+        let split = messages.len().saturating_sub(keep_recent);
+        let (older, recent) = messages.split_at(split);
+        if older.is_empty() {
+            return Ok(());
+        }
+
+        let summary = self.llm_client.summarize(older).await?;
+        let mut compacted = vec![ChatMessage {
+            role: "system".to_string(),
+            content: format!("Summary of the earlier conversation:\n{}", summary),
+            tool_calls: None,
+            tool_call_id: None,
+        }];
+        compacted.extend_from_slice(recent);
+
         self.session_manager
-            .add_message(&session_id, &message, &response)
+            .replace_messages(session_id, compacted)
             .await?;
+        Ok(())
+    }
 
-        // 6. Store embedding
-        self.vector_service
-            .store_conversation_embedding(&session_id, &message, &embedding)
-            .await?;
+    /// Whether a stored message passes the configured history filter.
+    fn sends_to_llm(&self, message: &ChatMessage) -> bool {
+        !self.history_excluded_roles.contains(&message.role)
+            && !self
+                .history_excluded_prefixes
+                .iter()
+                .any(|prefix| message.content.starts_with(prefix.as_str()))
+    }
 
-        Ok(ChatResponse {
-            response,
+    /// Prunes semantically redundant exchanges (a user message and the replies
+    /// up to the next user message) from history sent to the model. Walking
+    /// from newest to oldest, an exchange is dropped when it is at least
+    /// `threshold` similar to one already kept, so the latest version of a
+    /// repeated question survives. Messages before the first user message
+    /// are always kept. On embedding errors the history is returned as is.
+    async fn compress_history(
+        &self,
+        session_id: &str,
+        messages: Vec<ChatMessage>,
+        threshold: f32,
+    ) -> Vec<ChatMessage> {
+        let starts: Vec<usize> = messages
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| message.role == "user")
+            .map(|(index, _)| index)
+            .collect();
+        if starts.len() < 2 {
+            return messages;
+        }
+        let exchanges: Vec<(usize, usize)> = starts
+            .iter()
+            .zip(
+                starts
+                    .iter()
+                    .skip(1)
+                    .chain(std::iter::once(&messages.len())),
+            )
+            .map(|(&start, &end)| (start, end))
+            .collect();
+        let texts: Vec<String> = exchanges
+            .iter()
+            .map(|&(start, end)| {
+                messages[start..end]
+                    .iter()
+                    .map(|message| message.content.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+            .collect();
+        let embedded: Vec<String> = texts
+            .iter()
+            .filter(|text| !text.trim().is_empty())
+            .cloned()
+            .collect();
+        let embeddings = match self
+            .embedding_service
+            .generate_embeddings_batch(&embedded, EmbeddingTask::RetrievalDocument)
+            .await
+        {
+            Ok(embeddings) => embeddings,
+            Err(e) => {
+                warn!("Skipping history compression for {}: {}", session_id, e);
+                return messages;
+            }
+        };
+
+        let mut embeddings = embeddings.into_iter();
+        let exchange_embeddings: Vec<Option<Vec<f32>>> = texts
+            .iter()
+            .map(|text| {
+                if text.trim().is_empty() {
+                    None
+                } else {
+                    embeddings.next()
+                }
+            })
+            .collect();
+
+        let mut kept: Vec<&Vec<f32>> = Vec::new();
+        let mut dropped = vec![false; messages.len()];
+        let mut dropped_exchanges = 0;
+        for (&(start, end), embedding) in exchanges.iter().zip(&exchange_embeddings).rev() {
+            let Some(embedding) = embedding else {
+                continue;
+            };
+            if kept
+                .iter()
+                .any(|later| cosine_similarity(embedding, later) >= threshold)
+            {
+                dropped[start..end].fill(true);
+                dropped_exchanges += 1;
+            } else {
+                kept.push(embedding);
+            }
+        }
+        if dropped_exchanges > 0 {
+            debug!(
+                "Compressed history for {}: dropped {} redundant exchanges",
+                session_id, dropped_exchanges
+            );
+        }
+
+        messages
+            .into_iter()
+            .zip(dropped)
+            .filter(|(_, dropped)| !dropped)
+            .map(|(message, _)| message)
+            .collect()
+    }
+
+    /// Decides what assistant turn to persist, keeping blank answers out of
+    /// history unless configured otherwise.
+    fn assistant_message_to_store<'a>(&'a self, response: &'a str) -> Option<&'a str> {
+        if !response.trim().is_empty() {
+            return Some(response);
+        }
+        match &self.empty_response_storage {
+            EmptyResponseStorage::Skip => None,
+            EmptyResponseStorage::Placeholder(text) => Some(text),
+            EmptyResponseStorage::Keep => Some(response),
+        }
+    }
+
+    /// Applies the configured cleanup to the model's final text before it is
+    /// returned and persisted.
+    fn clean_response(&self, response: String) -> String {
+        let mut response = if self.trim_response {
+            response.trim().to_string()
+        } else {
+            response
+        };
+
+        if self.strip_code_fences {
+            if let Some(inner) = strip_wrapping_fence(&response) {
+                response = inner.to_string();
+            }
+        }
+
+        response
+    }
+}
+
+/// The body of a reply that is one fenced code block and nothing else,
+/// without the opening fence line (which may carry a language tag) and the
+/// closing fence. `None` for anything else, e.g. a single-line "```foo```" or
+/// a reply with several code blocks, which are left as they are.
+fn strip_wrapping_fence(response: &str) -> Option<&str> {
+    let trimmed = response.trim();
+    let rest = trimmed.strip_prefix("```")?;
+    let (opening, rest) = rest.split_once('\n')?;
+    if opening.contains('`') {
+        return None;
+    }
+    let body = rest.strip_suffix("```")?;
+    // Another fence line inside means the reply holds more than one block
+    if body
+        .lines()
+        .any(|line| line.trim_start().starts_with("```"))
+    {
+        return None;
+    }
+    Some(body.trim_end())
+}
+
+/// Checks that imported messages look like a conversation this agent could
+/// have produced: known roles, no blank content, system messages only before
+/// the first user turn, and a user turn before any assistant reply.
+fn validate_imported_messages(messages: &[ChatMessage]) -> Result<(), AgentError> {
+    if messages.is_empty() {
+        return Err(AgentError::BadRequest(
+            "messages must not be empty".to_string(),
+        ));
+    }
+
+    let mut seen_user = false;
+    for (index, message) in messages.iter().enumerate() {
+        if message.content.trim().is_empty() {
+            return Err(AgentError::BadRequest(format!(
+                "messages[{}] has empty content",
+                index
+            )));
+        }
+        match message.role.as_str() {
+            "user" => seen_user = true,
+            "assistant" if !seen_user => {
+                return Err(AgentError::BadRequest(format!(
+                    "messages[{}] is an assistant reply before any user message",
+                    index
+                )));
+            }
+            "assistant" => {}
+            "system" if seen_user => {
+                return Err(AgentError::BadRequest(format!(
+                    "messages[{}] is a system message after the conversation started",
+                    index
+                )));
+            }
+            "system" => {}
+            other => {
+                return Err(AgentError::BadRequest(format!(
+                    "messages[{}] has unknown role '{}' (expected user, assistant or system)",
+                    index, other
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a health probe under `HEALTH_CHECK_TIMEOUT`.
+async fn probe_dependency(probe: impl Future<Output = Result<()>>) -> DependencyHealth {
+    let started = Instant::now();
+    let result = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, probe).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!(
+            "no answer within {}s",
+            HEALTH_CHECK_TIMEOUT.as_secs()
+        )),
+    };
+    DependencyHealth {
+        status: if result.is_ok() { "ok" } else { "unreachable" }.to_string(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        error: result.err().map(|e| e.to_string()),
+    }
+}
+
+/// Whether chat metadata asks for webhook notifications.
+fn webhook_opt_in(metadata: Option<&serde_json::Value>) -> bool {
+    metadata
+        .and_then(|metadata| metadata.get("webhook"))
+        .and_then(|value| value.as_bool())
+        .unwrap_or(false)
+}
+
+/// Logs the JSON-RPC code and data of an MCP server error, which the
+/// client-facing message leaves out.
+fn log_mcp_error(session_id: &str, err: &anyhow::Error) {
+    if let Some(e) = err.downcast_ref::<McpClientError>() {
+        warn!(
+            "MCP {} failed for session {}: code {}, message {:?}, data {}",
+            e.method,
             session_id,
-        })
+            e.code,
+            e.message,
+            e.data
+                .as_ref()
+                .map_or_else(|| "none".to_string(), |data| data.to_string())
+        );
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// Sessions are stored by UUID, so ids differing only in case (or other
+/// spellings `Uuid::parse_str` accepts) share one lock. Ids that don't parse
+/// are rejected by the session manager, so their key doesn't matter.
+fn session_lock_key(session_id: &str) -> String {
+    Uuid::parse_str(session_id)
+        .map(|uuid| uuid.to_string())
+        .unwrap_or_else(|_| session_id.to_string())
+}
+
+/// Runs `operation` holding the lock for `key` in `locks`, creating it on
+/// demand and removing it afterwards unless someone else holds or waits for
+/// it.
+async fn with_lock_entry<T>(
+    locks: &DashMap<String, Arc<Mutex<()>>>,
+    key: &str,
+    operation: impl Future<Output = T>,
+) -> T {
+    // Declared first so it runs last, after the lock below is released,
+    // including when the caller drops this future mid-turn
+    let _cleanup = LockEntryCleanup { locks, key };
+    let lock = locks.entry(key.to_string()).or_default().clone();
+    let _guard = lock.lock_owned().await;
+    operation.await
+}
+
+/// Removes a lock entry on drop once only the map's own reference is left,
+/// i.e. no one is holding or waiting on it.
+struct LockEntryCleanup<'a> {
+    locks: &'a DashMap<String, Arc<Mutex<()>>>,
+    key: &'a str,
+}
+
+impl Drop for LockEntryCleanup<'_> {
+    fn drop(&mut self) {
+        self.locks
+            .remove_if(self.key, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::embeddings::EmbeddingProvider;
+    use crate::agent::llm::LlmProvider;
+    use crate::test_support::{google_embeddings, openai_reply, serve, FakeMcp};
+    use sqlx::PgPool;
+
+    /// An orchestrator over `pool` whose model always answers `reply` and
+    /// whose embeddings are all the same 768-dimension vector.
+    async fn test_orchestrator(pool: &PgPool, reply: &'static str) -> Orchestrator {
+        let llm = LlmClient::new(
+            LlmProvider::OpenAi,
+            "test-key".to_string(),
+            "test-model".to_string(),
+            0.0,
+            100,
+        )
+        .with_base_url(Some(serve(openai_reply(reply)).await));
+        let embedding_service = EmbeddingService::new(
+            EmbeddingProvider::Google,
+            "test-key".to_string(),
+            "text-embedding-004".to_string(),
+        )
+        .with_base_url(serve(google_embeddings(768)).await);
+        Orchestrator::new(
+            llm,
+            Arc::new(McpClient::new(FakeMcp {
+                delay: Duration::ZERO,
+            })),
+            SessionManager::new(pool.clone()),
+            VectorService::new(pool.clone()),
+            embedding_service,
+        )
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres with pgvector at DATABASE_URL"]
+    async fn streamed_turn_is_stored_before_it_completes(pool: PgPool) -> Result<()> {
+        let orchestrator = test_orchestrator(&pool, "Hello there").await;
+
+        let session_id = Uuid::new_v4().to_string();
+        let request: ChatRequest = serde_json::from_value(serde_json::json!({ "message": "Hi" }))?;
+        let chunks = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = chunks.clone();
+        let on_chunk = move |text: &str| sink.lock().unwrap().push(text.to_string());
+        let response = orchestrator
+            .process_message_stream(request, session_id.clone(), &on_chunk)
+            .await?;
+        assert_eq!(response.response, "Hello there");
+        assert_eq!(*chunks.lock().unwrap(), vec!["Hello there".to_string()]);
+
+        // The handler sends `done` once this returns, so the turn must
+        // already be stored by now
+        let stored = orchestrator
+            .session_manager
+            .get_or_create_session(&session_id)
+            .await?;
+        let turns: Vec<(&str, &str)> = stored
+            .messages
+            .iter()
+            .map(|m| (m.role.as_str(), m.content.as_str()))
+            .collect();
+        assert_eq!(turns, vec![("user", "Hi"), ("assistant", "Hello there")]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres with pgvector at DATABASE_URL"]
+    async fn user_and_assistant_messages_are_retrievable(pool: PgPool) -> Result<()> {
+        let orchestrator = test_orchestrator(&pool, "Salon A opens at 9").await;
+        let session_id = Uuid::new_v4().to_string();
+        let request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "message": "When does Salon A open?",
+            "session_id": session_id
+        }))?;
+        orchestrator
+            .process_message(request, session_id.clone())
+            .await?;
+
+        let query = vec![0.1_f32; 768];
+        let mut hits: Vec<(String, String)> = orchestrator
+            .vector_service
+            .retrieve_context_for_rag(&query, 10, Some(&session_id), &[], 0.0)
+            .await?
+            .into_iter()
+            .map(|hit| (hit.role, hit.text))
+            .collect();
+        hits.sort();
+        assert_eq!(
+            hits,
+            vec![
+                ("assistant".to_string(), "Salon A opens at 9".to_string()),
+                ("user".to_string(), "When does Salon A open?".to_string()),
+            ]
+        );
+
+        let assistant_only = orchestrator
+            .vector_service
+            .retrieve_context_for_rag(
+                &query,
+                10,
+                Some(&session_id),
+                &["assistant".to_string()],
+                0.0,
+            )
+            .await?;
+        assert_eq!(assistant_only.len(), 1);
+        assert_eq!(assistant_only[0].text, "Salon A opens at 9");
+        Ok(())
+    }
+
+    #[test]
+    fn strips_a_fence_wrapping_the_whole_reply() {
+        assert_eq!(
+            strip_wrapping_fence("```json\n{\"ok\": true}\n```\n"),
+            Some("{\"ok\": true}")
+        );
+        assert_eq!(strip_wrapping_fence("```\nplain\n```"), Some("plain"));
+    }
+
+    #[test]
+    fn keeps_single_line_fences() {
+        assert_eq!(strip_wrapping_fence("```foo```"), None);
+    }
+
+    #[test]
+    fn keeps_replies_with_several_code_blocks() {
+        let reply = "```\nfirst\n```\nThen:\n```\nsecond\n```";
+        assert_eq!(strip_wrapping_fence(reply), None);
+    }
+
+    #[test]
+    fn keeps_replies_not_wrapped_in_a_fence() {
+        assert_eq!(strip_wrapping_fence("Here:\n```\ncode\n```"), None);
+    }
+
+    #[tokio::test]
+    async fn lock_entry_is_removed_when_turns_are_cancelled() {
+        let locks = DashMap::new();
+        with_lock_entry(&locks, "s", async {}).await;
+        assert!(locks.is_empty());
+
+        // Cancelled while holding the lock
+        let holder = with_lock_entry(&locks, "s", std::future::pending::<()>());
+        assert!(tokio::time::timeout(Duration::from_millis(10), holder)
+            .await
+            .is_err());
+        assert!(locks.is_empty());
+
+        // Cancelled while waiting for the lock: the holder keeps the entry
+        // until it finishes
+        let (release, released) = oneshot::channel::<()>();
+        let holder = with_lock_entry(&locks, "s", released);
+        tokio::pin!(holder);
+        assert!(futures::poll!(holder.as_mut()).is_pending());
+        let waiter = with_lock_entry(&locks, "s", async {});
+        assert!(tokio::time::timeout(Duration::from_millis(10), waiter)
+            .await
+            .is_err());
+        assert_eq!(locks.len(), 1);
+        release.send(()).unwrap();
+        holder.await.unwrap();
+        assert!(locks.is_empty());
+    }
+
+    #[test]
+    fn session_ids_differing_in_case_share_a_lock() {
+        let id = "A1B2C3D4-E5F6-4789-8ABC-DEF012345678";
+        assert_eq!(session_lock_key(id), session_lock_key(&id.to_lowercase()));
+        assert_eq!(session_lock_key("not-a-uuid"), "not-a-uuid");
     }
 }
@@ -0,0 +1,64 @@
+use crate::models::OutcomeSummary;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Outcomes of recent chat turns, kept for a sliding window.
+#[derive(Debug)]
+pub struct RecentOutcomes {
+    window: Duration,
+    /// `(finished_at, error code)`, oldest first; `None` for a success.
+    outcomes: Mutex<VecDeque<(Instant, Option<&'static str>)>>,
+}
+
+impl RecentOutcomes {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            outcomes: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, error_code: Option<&'static str>) {
+        let now = Instant::now();
+        let mut outcomes = self.outcomes.lock().unwrap();
+        outcomes.push_back((now, error_code));
+        Self::prune(&mut outcomes, now, self.window);
+    }
+
+    pub fn summary(&self) -> OutcomeSummary {
+        let mut outcomes = self.outcomes.lock().unwrap();
+        Self::prune(&mut outcomes, Instant::now(), self.window);
+
+        let mut errors_by_code = BTreeMap::new();
+        for code in outcomes.iter().filter_map(|(_, code)| *code) {
+            *errors_by_code.entry(code).or_default() += 1;
+        }
+        let total = outcomes.len();
+        let errors = errors_by_code.values().sum();
+        OutcomeSummary {
+            window_secs: self.window.as_secs(),
+            total,
+            errors,
+            error_rate: if total == 0 {
+                0.0
+            } else {
+                errors as f64 / total as f64
+            },
+            errors_by_code,
+        }
+    }
+
+    fn prune(
+        outcomes: &mut VecDeque<(Instant, Option<&'static str>)>,
+        now: Instant,
+        window: Duration,
+    ) {
+        while let Some((finished_at, _)) = outcomes.front() {
+            if now.duration_since(*finished_at) <= window {
+                break;
+            }
+            outcomes.pop_front();
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Post-processes raw MCP tool output before it is fed back to the model.
+#[derive(Debug, Clone, Default)]
+pub struct ToolResultShaper {
+    default_max_depth: Option<usize>,
+    max_depth_per_tool: HashMap<String, usize>,
+    /// Per-tool text templates with `{{path}}` placeholders.
+    templates: HashMap<String, String>,
+}
+
+impl ToolResultShaper {
+    pub fn new(
+        default_max_depth: Option<usize>,
+        max_depth_per_tool: HashMap<String, usize>,
+    ) -> Self {
+        Self {
+            default_max_depth,
+            max_depth_per_tool,
+            templates: HashMap::new(),
+        }
+    }
+
+    /// Renders the JSON results of the given tools as text, e.g.
+    /// `"Available slots: {{slots}}"`. A placeholder is a dotted path into the
+    /// result (array indexes allowed, `{{.}}` for the whole result); arrays
+    /// are joined with commas.
+    pub fn with_templates(mut self, templates: HashMap<String, String>) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    /// Applies the tool's template if it has one and the result fits it;
+    /// otherwise flattens structured (JSON) results deeper than the tool's max
+    /// depth and re-serializes them compactly. Non-JSON results pass through
+    /// untouched.
+    pub fn shape(&self, tool_name: &str, raw: String) -> String {
+        if let Some(template) = self.templates.get(tool_name) {
+            let rendered = serde_json::from_str::<Value>(&raw)
+                .ok()
+                .and_then(|value| render_template(template, &value));
+            if let Some(rendered) = rendered {
+                return rendered;
+            }
+        }
+
+        let max_depth = match self
+            .max_depth_per_tool
+            .get(tool_name)
+            .copied()
+            .or(self.default_max_depth)
+        {
+            Some(depth) => depth,
+            None => return raw,
+        };
+
+        match serde_json::from_str::<Value>(&raw) {
+            Ok(value) if value.is_object() || value.is_array() => {
+                truncate_depth(&value, max_depth).to_string()
+            }
+            _ => raw,
+        }
+    }
+}
+
+/// Replaces containers nested deeper than `remaining` levels with a short
+/// placeholder describing what was elided.
+fn truncate_depth(value: &Value, remaining: usize) -> Value {
+    match value {
+        Value::Object(map) if remaining == 0 => Value::String(format!("{{…{} keys}}", map.len())),
+        Value::Array(items) if remaining == 0 => Value::String(format!("[…{} items]", items.len())),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), truncate_depth(v, remaining - 1)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|v| truncate_depth(v, remaining - 1))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Fills `{{path}}` placeholders from `value`. Returns `None` if a path is
+/// missing, so the caller can fall back to the raw result.
+fn render_template(template: &str, value: &Value) -> Option<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let end = start + rest[start..].find("}}")?;
+        let path = rest[start + 2..end].trim();
+        out.push_str(&render_value(lookup(value, path)?));
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    if path == "." {
+        return Some(value);
+    }
+    path.split('.')
+        .try_fold(value, |current, key| match current {
+            Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => current.get(key),
+        })
+}
+
+fn render_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        Value::Array(items) => items
+            .iter()
+            .map(render_value)
+            .collect::<Vec<_>>()
+            .join(", "),
+        other => other.to_string(),
+    }
+}
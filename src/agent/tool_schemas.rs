@@ -0,0 +1,263 @@
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+
+/// Nesting depth at which sanitization stops, so self-referencing `$ref`s
+/// can't expand forever.
+const MAX_SCHEMA_DEPTH: usize = 32;
+
+/// Simplifies MCP tool `inputSchema`s for models that reject some JSON
+/// Schema keywords. `$ref` is inlined from `$defs`/`definitions`, `oneOf` and
+/// `anyOf` are replaced by their first non-null variant, `allOf` variants are
+/// merged, and any other listed keyword is removed.
+#[derive(Debug, Clone, Default)]
+pub struct SchemaSanitizer {
+    stripped: HashSet<String>,
+}
+
+impl SchemaSanitizer {
+    pub fn new(stripped: HashSet<String>) -> Self {
+        Self { stripped }
+    }
+
+    pub fn sanitize(&self, schema: &Value) -> Value {
+        let mut sanitized = schema.clone();
+        if self.stripped.is_empty() {
+            return sanitized;
+        }
+        let definitions = collect_definitions(schema);
+        self.sanitize_node(&mut sanitized, &definitions, 0);
+        sanitized
+    }
+
+    fn strips(&self, keyword: &str) -> bool {
+        self.stripped.contains(keyword)
+    }
+
+    fn sanitize_node(&self, node: &mut Value, definitions: &Map<String, Value>, depth: usize) {
+        let Value::Object(object) = node else {
+            return;
+        };
+        if depth >= MAX_SCHEMA_DEPTH {
+            object.clear();
+            return;
+        }
+
+        // Flattening can pull in variants that carry these keywords
+        // themselves, so repeat until none is left (bounded, for `$ref` cycles)
+        for _ in 0..MAX_SCHEMA_DEPTH {
+            let mut changed = false;
+            if self.strips("$ref") {
+                if let Some(reference) = object.remove("$ref") {
+                    changed = true;
+                    if let Some(Value::Object(target)) = reference
+                        .as_str()
+                        .and_then(|reference| definitions.get(reference))
+                    {
+                        merge_schema(object, target.clone());
+                    }
+                }
+            }
+            for combinator in ["oneOf", "anyOf"] {
+                if !self.strips(combinator) {
+                    continue;
+                }
+                if let Some(Value::Array(variants)) = object.remove(combinator) {
+                    changed = true;
+                    if let Some(Value::Object(variant)) = variants
+                        .into_iter()
+                        .find(|variant| variant.get("type") != Some(&Value::from("null")))
+                    {
+                        merge_schema(object, variant);
+                    }
+                }
+            }
+            if self.strips("allOf") {
+                if let Some(Value::Array(variants)) = object.remove("allOf") {
+                    changed = true;
+                    for variant in variants {
+                        if let Value::Object(variant) = variant {
+                            merge_schema(object, variant);
+                        }
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        if self.strips("$ref") {
+            object.remove("$defs");
+            object.remove("definitions");
+        }
+        object.retain(|keyword, _| {
+            !self.strips(keyword) || matches!(keyword.as_str(), "oneOf" | "anyOf" | "allOf")
+        });
+
+        for (keyword, child) in object.iter_mut() {
+            match keyword.as_str() {
+                "properties" | "patternProperties" | "$defs" | "definitions" => {
+                    if let Value::Object(schemas) = child {
+                        for schema in schemas.values_mut() {
+                            self.sanitize_node(schema, definitions, depth + 1);
+                        }
+                    }
+                }
+                "items" | "prefixItems" | "oneOf" | "anyOf" | "allOf" => match child {
+                    Value::Array(schemas) => {
+                        for schema in schemas {
+                            self.sanitize_node(schema, definitions, depth + 1);
+                        }
+                    }
+                    schema => self.sanitize_node(schema, definitions, depth + 1),
+                },
+                "additionalProperties" | "not" | "if" | "then" | "else" | "contains" => {
+                    self.sanitize_node(child, definitions, depth + 1)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// `$defs` and `definitions` of the root schema, by reference path.
+fn collect_definitions(schema: &Value) -> Map<String, Value> {
+    let mut definitions = Map::new();
+    for keyword in ["$defs", "definitions"] {
+        if let Some(Value::Object(defs)) = schema.get(keyword) {
+            for (name, definition) in defs {
+                definitions.insert(format!("#/{}/{}", keyword, name), definition.clone());
+            }
+        }
+    }
+    definitions
+}
+
+/// Adds `source` to `target`: properties are united, `required` lists
+/// concatenated, and other keywords already in `target` win.
+fn merge_schema(target: &mut Map<String, Value>, source: Map<String, Value>) {
+    for (keyword, value) in source {
+        let Some(existing) = target.get_mut(&keyword) else {
+            target.insert(keyword, value);
+            continue;
+        };
+        match (keyword.as_str(), existing, value) {
+            ("properties", Value::Object(existing), Value::Object(properties)) => {
+                for (name, schema) in properties {
+                    existing.entry(name).or_insert(schema);
+                }
+            }
+            ("required", Value::Array(existing), Value::Array(required)) => {
+                for name in required {
+                    if !existing.contains(&name) {
+                        existing.push(name);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sanitizer(keywords: &[&str]) -> SchemaSanitizer {
+        SchemaSanitizer::new(keywords.iter().map(|k| k.to_string()).collect())
+    }
+
+    fn contains_keyword(schema: &Value, keyword: &str) -> bool {
+        match schema {
+            Value::Object(object) => {
+                object.contains_key(keyword)
+                    || object
+                        .values()
+                        .any(|child| contains_keyword(child, keyword))
+            }
+            Value::Array(items) => items.iter().any(|child| contains_keyword(child, keyword)),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn self_referencing_ref_is_inlined_to_a_bounded_depth() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"root": {"$ref": "#/$defs/node"}},
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {"child": {"$ref": "#/$defs/node"}}
+                }
+            }
+        });
+
+        let sanitized = sanitizer(&["$ref"]).sanitize(&schema);
+        assert!(!contains_keyword(&sanitized, "$ref"));
+        assert!(!contains_keyword(&sanitized, "$defs"));
+        let root = &sanitized["properties"]["root"];
+        assert_eq!(root["type"], "object");
+        assert_eq!(root["properties"]["child"]["type"], "object");
+    }
+
+    #[test]
+    fn nullable_any_of_becomes_its_non_null_variant() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "note": {
+                    "description": "Optional note",
+                    "anyOf": [{"type": "null"}, {"type": "string", "maxLength": 200}]
+                }
+            }
+        });
+
+        let sanitized = sanitizer(&["anyOf"]).sanitize(&schema);
+        assert_eq!(
+            sanitized["properties"]["note"],
+            json!({"description": "Optional note", "type": "string", "maxLength": 200})
+        );
+    }
+
+    #[test]
+    fn all_of_variants_are_merged() {
+        let schema = json!({
+            "allOf": [
+                {
+                    "type": "object",
+                    "properties": {"salon_id": {"type": "string"}},
+                    "required": ["salon_id"]
+                },
+                {
+                    "properties": {"date": {"type": "string", "format": "date"}},
+                    "required": ["date", "salon_id"]
+                }
+            ]
+        });
+
+        let sanitized = sanitizer(&["allOf"]).sanitize(&schema);
+        assert_eq!(
+            sanitized,
+            json!({
+                "type": "object",
+                "properties": {
+                    "salon_id": {"type": "string"},
+                    "date": {"type": "string", "format": "date"}
+                },
+                "required": ["salon_id", "date"]
+            })
+        );
+    }
+
+    #[test]
+    fn empty_strip_set_leaves_the_schema_untouched() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"root": {"$ref": "#/$defs/node"}},
+            "$defs": {"node": {"anyOf": [{"type": "string"}, {"type": "null"}]}}
+        });
+        assert_eq!(SchemaSanitizer::default().sanitize(&schema), schema);
+    }
+}
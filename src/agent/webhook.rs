@@ -0,0 +1,89 @@
+use crate::retry::RetryPolicy;
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use sha2::Sha256;
+use tracing::warn;
+
+/// Header carrying `sha256=<hex HMAC-SHA256 of the body>` when a secret is
+/// configured.
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// Posts chat events to an external URL. Delivery is best-effort: failures
+/// are retried per the retry policy, then logged.
+pub struct WebhookNotifier {
+    client: Client,
+    url: String,
+    secret: Option<String>,
+    retry: RetryPolicy,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            secret: None,
+            retry: RetryPolicy::new(0),
+        }
+    }
+
+    /// Signs every body with HMAC-SHA256 under this secret.
+    pub fn with_secret(mut self, secret: Option<String>) -> Self {
+        self.secret = secret;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Uses a shared, preconfigured HTTP client (timeouts, connection pool).
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub async fn notify(&self, payload: &serde_json::Value) {
+        if let Err(e) = self.deliver(payload).await {
+            warn!("Webhook delivery to {} failed: {}", self.url, e);
+        }
+    }
+
+    async fn deliver(&self, payload: &serde_json::Value) -> Result<()> {
+        let body = serde_json::to_vec(payload)?;
+        let signature = match &self.secret {
+            Some(secret) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                    .map_err(|e| anyhow!("Invalid webhook secret: {}", e))?;
+                mac.update(&body);
+                Some(format!(
+                    "sha256={}",
+                    hex::encode(mac.finalize().into_bytes())
+                ))
+            }
+            None => None,
+        };
+
+        let response = self
+            .retry
+            .send("Webhook", || {
+                let request = self
+                    .client
+                    .post(&self.url)
+                    .header("Content-Type", "application/json")
+                    .body(body.clone());
+                match &signature {
+                    Some(signature) => request.header(SIGNATURE_HEADER, signature),
+                    None => request,
+                }
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("endpoint returned {}", response.status()));
+        }
+        Ok(())
+    }
+}
@@ -1,36 +1,866 @@
 use crate::agent::Orchestrator;
-use crate::models::{ChatRequest, ChatResponse};
-use axum::{extract::State, http::StatusCode, Json};
+use crate::config::RateLimitKey;
+use crate::error::{classify, AgentError};
+use crate::mcp::{McpPrompt, McpResource, McpResourceContents, ToolStatsSnapshot};
+use crate::models::{
+    BatchChatResult, ChatMessage, ChatRequest, ChatResponse, ConfirmRequest, ConfirmResponse,
+    HealthReport, ImportRequest, ImportResponse, MergeSessionsRequest, MergeSessionsResponse,
+    ReadResourceQuery, RenderPromptRequest, SessionStats, StatusReport, VersionInfo,
+};
+use axum::{
+    body::Body,
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::sse::{Event, KeepAlive, Sse},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::stream::{self, Stream};
+use futures::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tracing::error;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, Instrument, Span};
 use uuid::Uuid;
 
-pub async fn handle_health() -> StatusCode {
+type ApiError = (StatusCode, Json<serde_json::Value>);
+
+/// Largest chat body buffered to find its `session_id` (axum's `Json` limit).
+const MAX_CHAT_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// WebSocket requests held while a turn runs; more are answered with an
+/// error frame and dropped.
+const MAX_QUEUED_WS_REQUESTS: usize = 8;
+
+/// Error body shared by all handlers. `code` is stable and machine-readable;
+/// `message` is for humans and may change.
+fn api_error(status: StatusCode, code: &str, error: &str, message: String) -> ApiError {
+    (
+        status,
+        Json(serde_json::json!({
+            "error": error,
+            "code": code,
+            "message": message
+        })),
+    )
+}
+
+fn agent_api_error(error: &str, e: AgentError) -> ApiError {
+    api_error(e.status(), e.code(), error, e.to_string())
+}
+
+fn api_error_from(error: &str, e: &anyhow::Error) -> ApiError {
+    let (status, code) = classify(e);
+    api_error(status, code, error, e.to_string())
+}
+
+/// Liveness probe; never touches dependencies.
+pub async fn handle_live() -> StatusCode {
     StatusCode::OK
 }
 
+/// Dependency check: 503 unless the database and (outside degraded mode)
+/// the MCP server answer.
+pub async fn handle_health(
+    State(orchestrator): State<Arc<Orchestrator>>,
+) -> (StatusCode, Json<HealthReport>) {
+    let report = orchestrator.health().await;
+    let status = if report.status == "unavailable" {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (status, Json(report))
+}
+
+pub async fn handle_version() -> Json<VersionInfo> {
+    let built_at = env!("BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: env!("GIT_COMMIT_HASH").to_string(),
+        built_at,
+    })
+}
+
+/// Operator health summary, gated by `STATUS_API_KEY` (404 when unset).
+pub async fn handle_status(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    headers: HeaderMap,
+) -> Result<Json<StatusReport>, ApiError> {
+    require_bearer(
+        &headers,
+        orchestrator.status_api_key(),
+        "Status endpoint disabled",
+        "Set STATUS_API_KEY to enable /api/status",
+    )?;
+
+    Ok(Json(orchestrator.status()))
+}
+
+/// Checks the `Authorization: Bearer` token of an operator endpoint. Without
+/// a configured key the endpoint is reported as not found.
+fn require_bearer(
+    headers: &HeaderMap,
+    key: Option<&str>,
+    disabled_error: &str,
+    disabled_message: &str,
+) -> Result<(), ApiError> {
+    let Some(key) = key else {
+        return Err(agent_api_error(
+            disabled_error,
+            AgentError::NotFound(disabled_message.to_string()),
+        ));
+    };
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !key_matches(token, key) {
+        return Err(agent_api_error(
+            "Unauthorized",
+            AgentError::Unauthorized("A valid bearer token is required".to_string()),
+        ));
+    }
+    Ok(())
+}
+
+/// Middleware for the chat, session and tool routes: with `AGENT_API_KEY`
+/// set, requests need it as `Authorization: Bearer <key>` or
+/// `X-API-Key: <key>`. Without a key everything passes, for local
+/// development.
+pub async fn require_api_key(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if let Some(key) = orchestrator.agent_api_key() {
+        if !key_matches(presented_api_key(request.headers()), key) {
+            return Err(agent_api_error(
+                "Unauthorized",
+                AgentError::Unauthorized("A valid API key is required".to_string()),
+            ));
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+/// Compares a presented key with the configured one in constant time, so
+/// response timing doesn't reveal how much of a guess was right.
+fn key_matches(presented: Option<&str>, key: &str) -> bool {
+    presented.is_some_and(|presented| presented.as_bytes().ct_eq(key.as_bytes()).into())
+}
+
+/// The client's API key: the bearer token, else the `X-API-Key` header.
+fn presented_api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            headers
+                .get("x-api-key")
+                .and_then(|value| value.to_str().ok())
+        })
+}
+
+/// Middleware for `/api/chat*`: answers 429 with `Retry-After` once the
+/// client has used up `RATE_LIMIT_PER_MINUTE`. A batch runs one turn per
+/// item, so it costs one token per item.
+pub async fn rate_limit(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some((limiter, key)) = orchestrator.rate_limit() else {
+        return next.run(request).await;
+    };
+
+    // The session id and the batch size are in the body, which is buffered
+    // and put back
+    let is_batch = request.uri().path() == "/api/chat/batch";
+    let mut body_json = None;
+    let request = if key == RateLimitKey::Session || is_batch {
+        let (parts, body) = request.into_parts();
+        let bytes = match axum::body::to_bytes(body, MAX_CHAT_BODY_BYTES).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return agent_api_error(
+                    "Invalid request body",
+                    AgentError::BadRequest(e.to_string()),
+                )
+                .into_response()
+            }
+        };
+        body_json = serde_json::from_slice::<serde_json::Value>(&bytes).ok();
+        Request::from_parts(parts, Body::from(bytes))
+    } else {
+        request
+    };
+    let cost = match body_json.as_ref().and_then(serde_json::Value::as_array) {
+        Some(items) if is_batch => u32::try_from(items.len()).unwrap_or(u32::MAX),
+        _ => 1,
+    };
+
+    let client = match key {
+        RateLimitKey::Ip => None,
+        RateLimitKey::ApiKey => {
+            presented_api_key(request.headers()).map(|api_key| format!("key:{}", api_key))
+        }
+        RateLimitKey::Session => body_json
+            .as_ref()
+            .and_then(|body| body.get("session_id")?.as_str())
+            .map(|id| format!("session:{}", id)),
+    };
+    let client = client.unwrap_or_else(|| {
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        format!(
+            "ip:{}",
+            client_ip(request.headers(), peer, orchestrator.trusts_forwarded_for())
+        )
+    });
+
+    if let Err(wait) = limiter.check(&client, cost) {
+        let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+        let (status, body) = agent_api_error(
+            "Rate limit exceeded",
+            AgentError::RateLimited(format!("Too many requests; retry in {}s", retry_after)),
+        );
+        return (
+            status,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            body,
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// The connecting peer's address, or the first `X-Forwarded-For` entry when
+/// the proxy in front is trusted to set it.
+fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>, trust_forwarded_for: bool) -> String {
+    if trust_forwarded_for {
+        if let Some(forwarded) = headers
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+        {
+            return forwarded.to_string();
+        }
+    }
+    peer.map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Readiness probe: 503 while the MCP server is unreachable, unless degraded
+/// mode lets chats continue without tools.
+pub async fn handle_ready(
+    State(orchestrator): State<Arc<Orchestrator>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if orchestrator.mcp_healthy() {
+        (
+            StatusCode::OK,
+            Json(serde_json::json!({ "status": "ready", "mcp": "ok" })),
+        )
+    } else if orchestrator.mcp_degraded_mode() {
+        (
+            StatusCode::OK,
+            Json(serde_json::json!({ "status": "degraded", "mcp": "unreachable" })),
+        )
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "status": "not_ready", "mcp": "unreachable" })),
+        )
+    }
+}
+
+pub async fn handle_tool_stats(
+    State(orchestrator): State<Arc<Orchestrator>>,
+) -> Json<Vec<ToolStatsSnapshot>> {
+    Json(orchestrator.tool_stats())
+}
+
+pub async fn handle_refresh_tools(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    require_bearer(
+        &headers,
+        orchestrator.admin_api_key(),
+        "Admin endpoints disabled",
+        "Set ADMIN_API_KEY to enable /api/tools/refresh",
+    )?;
+
+    match orchestrator.refresh_tools().await {
+        Ok(tools) => Ok(Json(serde_json::json!({ "tools": tools }))),
+        Err(e) => {
+            error!("Error refreshing MCP tools: {}", e);
+            Err(api_error_from("Failed to refresh tools", &e))
+        }
+    }
+}
+
+pub async fn handle_tools_openapi(
+    State(orchestrator): State<Arc<Orchestrator>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    match orchestrator.tools_openapi().await {
+        Ok(document) => Ok(Json(document)),
+        Err(e) => {
+            error!("Error listing MCP tools: {}", e);
+            Err(api_error_from("Failed to list tools", &e))
+        }
+    }
+}
+
+pub async fn handle_list_resources(
+    State(orchestrator): State<Arc<Orchestrator>>,
+) -> Result<Json<Vec<McpResource>>, ApiError> {
+    match orchestrator.list_resources().await {
+        Ok(resources) => Ok(Json(resources)),
+        Err(e) => {
+            error!("Error listing MCP resources: {}", e);
+            Err(api_error_from("Failed to list resources", &e))
+        }
+    }
+}
+
+pub async fn handle_read_resource(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    Query(query): Query<ReadResourceQuery>,
+) -> Result<Json<Vec<McpResourceContents>>, ApiError> {
+    match orchestrator.read_resource(&query.uri).await {
+        Ok(contents) => Ok(Json(contents)),
+        Err(e) => {
+            error!("Error reading MCP resource {}: {}", query.uri, e);
+            Err(api_error_from("Failed to read resource", &e))
+        }
+    }
+}
+
+pub async fn handle_list_prompts(
+    State(orchestrator): State<Arc<Orchestrator>>,
+) -> Result<Json<Vec<McpPrompt>>, ApiError> {
+    match orchestrator.list_prompts().await {
+        Ok(prompts) => Ok(Json(prompts)),
+        Err(e) => {
+            error!("Error listing MCP prompts: {}", e);
+            Err(api_error_from("Failed to list prompts", &e))
+        }
+    }
+}
+
+pub async fn handle_render_prompt(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    Path(name): Path<String>,
+    Json(request): Json<RenderPromptRequest>,
+) -> Result<Json<Vec<ChatMessage>>, ApiError> {
+    match orchestrator.render_prompt(&name, &request.arguments).await {
+        Ok(messages) => Ok(Json(messages)),
+        Err(e) => {
+            error!("Error rendering MCP prompt {}: {}", name, e);
+            Err(api_error_from("Failed to render prompt", &e))
+        }
+    }
+}
+
+pub async fn handle_session_stats(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    Path(session_id): Path<String>,
+) -> Result<Json<SessionStats>, ApiError> {
+    if Uuid::parse_str(&session_id).is_err() {
+        return Err(agent_api_error(
+            "Invalid session id",
+            AgentError::BadRequest(format!("'{}' is not a valid UUID", session_id)),
+        ));
+    }
+
+    match orchestrator.session_stats(&session_id).await {
+        Ok(Some(stats)) => Ok(Json(stats)),
+        Ok(None) => Err(agent_api_error(
+            "Session not found",
+            AgentError::NotFound(format!("No stats recorded for session {}", session_id)),
+        )),
+        Err(e) => {
+            error!("Error loading session stats: {}", e);
+            Err(api_error_from("Failed to load session stats", &e))
+        }
+    }
+}
+
+pub async fn handle_get_history(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    Path(session_id): Path<String>,
+) -> Result<Json<Vec<ChatMessage>>, ApiError> {
+    if Uuid::parse_str(&session_id).is_err() {
+        return Err(agent_api_error(
+            "Invalid session id",
+            AgentError::BadRequest(format!("'{}' is not a valid UUID", session_id)),
+        ));
+    }
+
+    match orchestrator.session_history(&session_id).await {
+        Ok(Some(messages)) => Ok(Json(messages)),
+        Ok(None) => Err(agent_api_error(
+            "Session not found",
+            AgentError::NotFound(format!("No session {}", session_id)),
+        )),
+        Err(e) => {
+            error!("Error loading session history: {}", e);
+            Err(api_error_from("Failed to load session history", &e))
+        }
+    }
+}
+
+pub async fn handle_delete_session(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    Path(session_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if Uuid::parse_str(&session_id).is_err() {
+        return Err(agent_api_error(
+            "Invalid session id",
+            AgentError::BadRequest(format!("'{}' is not a valid UUID", session_id)),
+        ));
+    }
+
+    match orchestrator.delete_session(&session_id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(agent_api_error(
+            "Session not found",
+            AgentError::NotFound(format!("No session {}", session_id)),
+        )),
+        Err(e) => {
+            error!("Error deleting session: {}", e);
+            Err(api_error_from("Failed to delete session", &e))
+        }
+    }
+}
+
+pub async fn handle_merge_sessions(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    headers: HeaderMap,
+    Json(request): Json<MergeSessionsRequest>,
+) -> Result<Json<MergeSessionsResponse>, ApiError> {
+    require_bearer(
+        &headers,
+        orchestrator.admin_api_key(),
+        "Admin endpoints disabled",
+        "Set ADMIN_API_KEY to enable /api/sessions/merge",
+    )?;
+
+    match orchestrator.merge_sessions(request).await {
+        Ok(response) => Ok(Json(response)),
+        Err(e) => {
+            error!("Error merging sessions: {}", e);
+            Err(api_error_from("Failed to merge sessions", &e))
+        }
+    }
+}
+
 pub async fn handle_chat(
     State(orchestrator): State<Arc<Orchestrator>>,
     Json(request): Json<ChatRequest>,
-) -> Result<Json<ChatResponse>, (StatusCode, Json<serde_json::Value>)> {
+) -> Result<Json<ChatResponse>, ApiError> {
     let session_id = request
         .session_id
+        .clone()
         .unwrap_or_else(|| Uuid::new_v4().to_string());
+    Span::current().record("session_id", session_id.as_str());
+
+    let result = if orchestrator.respond_before_persist() {
+        // Storage continues in the spawned task after the reply is sent
+        let (reply, response) = oneshot::channel();
+        let worker = orchestrator.clone();
+        orchestrator.spawn_tracked(
+            async move {
+                worker
+                    .process_message_reply_first(request, session_id, reply)
+                    .await;
+            }
+            .instrument(Span::current()),
+        );
+        response
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Chat turn ended without a response")))
+    } else {
+        orchestrator.process_message(request, session_id).await
+    };
+
+    match result {
+        Ok(response) => Ok(Json(response)),
+        Err(e) => {
+            error!("Error processing chat message: {}", e);
+            Err(api_error_from("Failed to process message", &e))
+        }
+    }
+}
+
+/// Streams a chat turn as Server-Sent Events: `chunk` events with answer
+/// text as it is generated, then one `done` event with the full
+/// `ChatResponse` (sent after the turn is stored) or an `error` event. The
+/// turn runs to completion even if the client disconnects.
+pub async fn handle_chat_stream(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    Json(request): Json<ChatRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let session_id = request
+        .session_id
+        .clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    // Rejected before the stream opens, so the status matches /api/chat
+    if Uuid::parse_str(&session_id).is_err() {
+        return Err(agent_api_error(
+            "Invalid session id",
+            AgentError::BadRequest(format!("'{}' is not a valid UUID", session_id)),
+        ));
+    }
+    let keepalive_secs = orchestrator.stream_keepalive_secs();
+    Span::current().record("session_id", session_id.as_str());
+
+    let (tx, rx) = mpsc::unbounded_channel::<Event>();
+    let worker = orchestrator.clone();
+    let turn = async move {
+        let chunk_tx = tx.clone();
+        let on_chunk = move |text: &str| {
+            let _ = chunk_tx.send(
+                Event::default()
+                    .event("chunk")
+                    .data(serde_json::json!({ "text": text }).to_string()),
+            );
+        };
+
+        let event = match worker
+            .process_message_stream(request, session_id, &on_chunk)
+            .await
+        {
+            Ok(response) => Event::default()
+                .event("done")
+                .data(serde_json::json!(response).to_string()),
+            Err(e) => {
+                error!("Error processing streamed chat message: {}", e);
+                let (_, Json(body)) = api_error_from("Failed to process message", &e);
+                Event::default().event("error").data(body.to_string())
+            }
+        };
+        let _ = tx.send(event);
+    };
+    orchestrator.spawn_tracked(turn.instrument(Span::current()));
+
+    let events = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (Ok(event), rx))
+    });
+    let sse = Sse::new(events);
+    Ok(if keepalive_secs > 0 {
+        sse.keep_alive(KeepAlive::new().interval(Duration::from_secs(keepalive_secs)))
+    } else {
+        sse
+    })
+}
 
+/// Upgrades to a WebSocket chat: each text frame from the client is a chat
+/// request (`ChatRequest` JSON), answered with `chunk` frames and one `done`
+/// or `error` frame. The connection keeps one session for its lifetime.
+pub async fn handle_chat_ws(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let api_key = match orchestrator.rate_limit() {
+        Some((_, RateLimitKey::ApiKey)) => presented_api_key(&headers),
+        _ => None,
+    };
+    let client = match api_key {
+        Some(api_key) => format!("key:{}", api_key),
+        None => format!(
+            "ip:{}",
+            client_ip(&headers, Some(peer), orchestrator.trusts_forwarded_for())
+        ),
+    };
+    ws.on_upgrade(move |socket| chat_socket(socket, orchestrator, client))
+}
+
+/// Runs one WebSocket connection. Requests arriving while a turn is running
+/// are queued, up to `MAX_QUEUED_WS_REQUESTS`; a disconnect mid-turn drops
+/// the turn, cancelling the LLM call.
+async fn chat_socket(socket: WebSocket, orchestrator: Arc<Orchestrator>, client: String) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut session_id: Option<String> = None;
+    let mut queued: VecDeque<String> = VecDeque::new();
+    let keepalive_secs = orchestrator.stream_keepalive_secs();
+    let mut keepalive = tokio::time::interval(Duration::from_secs(keepalive_secs.max(1)));
+    keepalive.reset();
+
+    loop {
+        let text = match queued.pop_front() {
+            Some(text) => text,
+            None => tokio::select! {
+                frame = receiver.next() => match frame {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                },
+                _ = keepalive.tick(), if keepalive_secs > 0 => {
+                    if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            },
+        };
+
+        let mut request: ChatRequest = match serde_json::from_str(&text) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = ws_error(
+                    "Invalid chat request",
+                    AgentError::BadRequest(e.to_string()),
+                );
+                if sender.send(error).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        if let Some(requested) = &request.session_id {
+            if Uuid::parse_str(requested).is_err() {
+                let error = ws_error(
+                    "Invalid session id",
+                    AgentError::BadRequest(format!("'{}' is not a valid UUID", requested)),
+                );
+                if sender.send(error).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        }
+        let current_session = session_id
+            .get_or_insert_with(|| {
+                request
+                    .session_id
+                    .clone()
+                    .unwrap_or_else(|| Uuid::new_v4().to_string())
+            })
+            .clone();
+        if request
+            .session_id
+            .as_ref()
+            .is_some_and(|requested| *requested != current_session)
+        {
+            let error = ws_error(
+                "Invalid chat request",
+                AgentError::BadRequest(format!(
+                    "This connection is bound to session {}",
+                    current_session
+                )),
+            );
+            if sender.send(error).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        request.session_id = Some(current_session.clone());
+
+        if let Some((limiter, key)) = orchestrator.rate_limit() {
+            let client = match key {
+                RateLimitKey::Session => format!("session:{}", current_session),
+                _ => client.clone(),
+            };
+            if let Err(wait) = limiter.check(&client, 1) {
+                let error = ws_error(
+                    "Rate limit exceeded",
+                    AgentError::RateLimited(format!(
+                        "Too many requests; retry in {}s",
+                        wait.as_secs_f64().ceil().max(1.0) as u64
+                    )),
+                );
+                if sender.send(error).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        }
+
+        let (chunk_tx, mut chunk_rx) = mpsc::unbounded_channel::<String>();
+        let on_chunk = move |text: &str| {
+            let _ = chunk_tx.send(text.to_string());
+        };
+        let turn = orchestrator.process_message_stream(request, current_session, &on_chunk);
+        tokio::pin!(turn);
+        let result = loop {
+            tokio::select! {
+                result = &mut turn => break result,
+                Some(chunk) = chunk_rx.recv() => {
+                    let frame = ws_frame("chunk", serde_json::json!({ "text": chunk }));
+                    if sender.send(frame).await.is_err() {
+                        return;
+                    }
+                }
+                frame = receiver.next() => match frame {
+                    Some(Ok(Message::Text(text))) => {
+                        if queued.len() < MAX_QUEUED_WS_REQUESTS {
+                            queued.push_back(text);
+                        } else {
+                            let error = ws_error(
+                                "Too many queued requests",
+                                AgentError::RateLimited(format!(
+                                    "At most {} requests may wait for a running turn",
+                                    MAX_QUEUED_WS_REQUESTS
+                                )),
+                            );
+                            if sender.send(error).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        debug!("WebSocket client disconnected mid-turn; cancelling it");
+                        return;
+                    }
+                    Some(Ok(_)) => {}
+                },
+                _ = keepalive.tick(), if keepalive_secs > 0 => {
+                    if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        };
+        while let Ok(chunk) = chunk_rx.try_recv() {
+            let frame = ws_frame("chunk", serde_json::json!({ "text": chunk }));
+            if sender.send(frame).await.is_err() {
+                return;
+            }
+        }
+
+        let frame = match result {
+            Ok(response) => ws_frame("done", serde_json::json!(response)),
+            Err(e) => {
+                error!("Error processing WebSocket chat message: {}", e);
+                let (_, Json(body)) = api_error_from("Failed to process message", &e);
+                ws_frame("error", body)
+            }
+        };
+        if sender.send(frame).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// An `error` frame with the usual error body.
+fn ws_error(error: &str, e: AgentError) -> Message {
+    let (_, Json(body)) = agent_api_error(error, e);
+    ws_frame("error", body)
+}
+
+/// A `{"type": ..., "data": ...}` text frame, mirroring the SSE events.
+fn ws_frame(kind: &str, data: serde_json::Value) -> Message {
+    Message::Text(serde_json::json!({ "type": kind, "data": data }).to_string())
+}
+
+pub async fn handle_import(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    Json(request): Json<ImportRequest>,
+) -> Result<Json<ImportResponse>, ApiError> {
+    if Uuid::parse_str(&request.session_id).is_err() {
+        return Err(agent_api_error(
+            "Invalid session id",
+            AgentError::BadRequest(format!("'{}' is not a valid UUID", request.session_id)),
+        ));
+    }
+
+    match orchestrator.import_session(request).await {
+        Ok(response) => Ok(Json(response)),
+        Err(e) => {
+            error!("Error importing session: {}", e);
+            Err(api_error_from("Failed to import session", &e))
+        }
+    }
+}
+
+pub async fn handle_chat_batch(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    Json(requests): Json<Vec<ChatRequest>>,
+) -> Result<Json<Vec<BatchChatResult>>, ApiError> {
+    if requests.len() > orchestrator.max_batch_size() {
+        return Err(agent_api_error(
+            "Batch too large",
+            AgentError::BadRequest(format!(
+                "batch has {} items; at most {} are allowed",
+                requests.len(),
+                orchestrator.max_batch_size()
+            )),
+        ));
+    }
+
+    Ok(Json(orchestrator.process_batch(requests).await))
+}
+
+pub async fn handle_confirm(
+    State(orchestrator): State<Arc<Orchestrator>>,
+    Json(request): Json<ConfirmRequest>,
+) -> Result<Json<ConfirmResponse>, ApiError> {
+    if Uuid::parse_str(&request.session_id).is_err() || Uuid::parse_str(&request.token).is_err() {
+        return Err(agent_api_error(
+            "Invalid confirmation",
+            AgentError::BadRequest("session_id and token must be valid UUIDs".to_string()),
+        ));
+    }
+
+    Span::current().record("session_id", request.session_id.as_str());
     match orchestrator
-        .process_message(request.message, session_id.clone())
+        .confirm_action(&request.session_id, &request.token)
         .await
     {
-        Ok(response) => Ok(Json(response)),
+        Ok(Some(response)) => Ok(Json(response)),
+        Ok(None) => Err(agent_api_error(
+            "Pending action not found",
+            AgentError::NotFound(
+                "No pending action for this token; it may have expired or already run".to_string(),
+            ),
+        )),
         Err(e) => {
-            error!("Error processing chat message: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({
-                    "error": "Failed to process message",
-                    "message": e.to_string()
-                })),
-            ))
+            error!("Error executing confirmed action: {}", e);
+            Err(api_error_from("Failed to execute confirmed action", &e))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_keys_must_match_exactly() {
+        assert!(key_matches(Some("secret"), "secret"));
+        assert!(!key_matches(Some("secreT"), "secret"));
+        assert!(!key_matches(Some("secret2"), "secret"));
+        assert!(!key_matches(Some(""), "secret"));
+        assert!(!key_matches(None, "secret"));
+    }
+
+    #[test]
+    fn api_key_is_read_from_bearer_then_x_api_key() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "from-header".parse().unwrap());
+        assert_eq!(presented_api_key(&headers), Some("from-header"));
+        headers.insert(header::AUTHORIZATION, "Bearer from-bearer".parse().unwrap());
+        assert_eq!(presented_api_key(&headers), Some("from-bearer"));
+    }
+}
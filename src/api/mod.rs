@@ -4,6 +4,6 @@ pub mod routes;
 use crate::agent::Orchestrator;
 use axum::Router;
 
-pub fn create_router(orchestrator: Orchestrator) -> Router {
-    routes::create_routes(orchestrator)
+pub fn create_router(orchestrator: Orchestrator, allowed_origins: &[String]) -> Router {
+    routes::create_routes(orchestrator, allowed_origins)
 }
@@ -1,14 +1,138 @@
 use crate::agent::Orchestrator;
-use axum::{routing::post, Router};
+use axum::{
+    extract::Request,
+    http::{header, HeaderName, HeaderValue, Method},
+    middleware,
+    response::Response,
+    routing::post,
+    Router,
+};
 use std::sync::Arc;
+use std::time::Duration;
 use tower_http::cors::CorsLayer;
+use tower_http::trace::TraceLayer;
+use tracing::field::Empty;
+use tracing::{info, info_span, warn, Span};
 
 use super::handlers;
 
-pub fn create_routes(orchestrator: Orchestrator) -> Router {
-    Router::new()
+pub fn create_routes(orchestrator: Orchestrator, allowed_origins: &[String]) -> Router {
+    let orchestrator = Arc::new(orchestrator);
+    let chat_routes = Router::new()
         .route("/api/chat", post(handlers::handle_chat))
+        .route("/api/chat/batch", post(handlers::handle_chat_batch))
+        .route("/api/chat/stream", post(handlers::handle_chat_stream))
+        .route_layer(middleware::from_fn_with_state(
+            orchestrator.clone(),
+            handlers::rate_limit,
+        ));
+    // Everything that reaches the model, the tools or stored sessions
+    // requires AGENT_API_KEY, if set. Authentication runs first, so rejected
+    // requests don't use up the limit.
+    let protected_routes = chat_routes
+        // WebSocket chats are rate limited per message instead
+        .route("/api/chat/ws", axum::routing::get(handlers::handle_chat_ws))
+        .route("/api/confirm", post(handlers::handle_confirm))
+        .route("/api/session/import", post(handlers::handle_import))
+        .route(
+            "/api/session/:id/stats",
+            axum::routing::get(handlers::handle_session_stats),
+        )
+        .route(
+            "/api/sessions/:id",
+            axum::routing::delete(handlers::handle_delete_session),
+        )
+        .route(
+            "/api/sessions/:id/history",
+            axum::routing::get(handlers::handle_get_history),
+        )
+        .route(
+            "/api/tools/stats",
+            axum::routing::get(handlers::handle_tool_stats),
+        )
+        .route(
+            "/api/tools/openapi",
+            axum::routing::get(handlers::handle_tools_openapi),
+        )
+        .route(
+            "/api/prompts",
+            axum::routing::get(handlers::handle_list_prompts),
+        )
+        .route("/api/prompts/:name", post(handlers::handle_render_prompt))
+        .route(
+            "/api/resources",
+            axum::routing::get(handlers::handle_list_resources),
+        )
+        .route(
+            "/api/resources/read",
+            axum::routing::get(handlers::handle_read_resource),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            orchestrator.clone(),
+            handlers::require_api_key,
+        ));
+
+    // Operator endpoints (status, tool refresh, merge) check their own keys
+    Router::new()
+        .merge(protected_routes)
         .route("/api/health", axum::routing::get(handlers::handle_health))
-        .layer(CorsLayer::permissive())
-        .with_state(Arc::new(orchestrator))
+        .route(
+            "/api/health/live",
+            axum::routing::get(handlers::handle_live),
+        )
+        .route("/api/ready", axum::routing::get(handlers::handle_ready))
+        .route("/api/version", axum::routing::get(handlers::handle_version))
+        .route("/api/status", axum::routing::get(handlers::handle_status))
+        .route("/api/sessions/merge", post(handlers::handle_merge_sessions))
+        .route("/api/tools/refresh", post(handlers::handle_refresh_tools))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request| {
+                    // Handlers fill in session_id and tool_calls for chat turns
+                    info_span!(
+                        "request",
+                        method = %request.method(),
+                        path = %request.uri().path(),
+                        session_id = Empty,
+                        tool_calls = Empty,
+                    )
+                })
+                .on_response(|response: &Response, latency: Duration, _: &Span| {
+                    info!(
+                        status = response.status().as_u16(),
+                        latency_ms = latency.as_millis() as u64,
+                        "request finished"
+                    )
+                }),
+        )
+        .layer(cors_layer(allowed_origins))
+        .with_state(orchestrator)
+}
+
+/// Restricts browsers to `allowed_origins`, or allows any origin when the
+/// list is empty. Origins that aren't valid header values are skipped.
+fn cors_layer(allowed_origins: &[String]) -> CorsLayer {
+    if allowed_origins.is_empty() {
+        warn!("ALLOWED_ORIGINS is empty; accepting cross-origin requests from any origin");
+        return CorsLayer::permissive();
+    }
+
+    let origins: Vec<HeaderValue> = allowed_origins
+        .iter()
+        .filter_map(|origin| match HeaderValue::from_str(origin) {
+            Ok(origin) => Some(origin),
+            Err(_) => {
+                warn!("Ignoring invalid CORS origin '{}'", origin);
+                None
+            }
+        })
+        .collect();
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::AUTHORIZATION,
+            HeaderName::from_static("x-api-key"),
+        ])
 }
@@ -1,3 +1,6 @@
 pub mod settings;
 
-pub use settings::{EmbeddingProvider, LlmProvider, Settings};
+pub use settings::{
+    EmbeddingProvider, EmptyResponseStorage, LlmProvider, McpTransport, RagScope, RateLimitKey,
+    Settings,
+};
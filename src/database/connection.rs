@@ -1,10 +1,59 @@
+use crate::config::Settings;
+use crate::retry::RetryPolicy;
 use anyhow::Result;
 use sqlx::postgres::{PgPool, PgPoolOptions};
+use std::time::Duration;
+use tracing::info;
 
-pub async fn get_pool(database_url: &str) -> Result<PgPool> {
-    let pool = PgPoolOptions::new()
-        .max_connections(10)
-        .connect(database_url)
+/// Connection pool sizing and timeouts.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout: Duration,
+    /// `None` never closes idle connections.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl PoolConfig {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_connections: settings.db_max_connections,
+            min_connections: settings.db_min_connections,
+            acquire_timeout: Duration::from_secs(settings.db_acquire_timeout_secs),
+            idle_timeout: (settings.db_idle_timeout_secs > 0)
+                .then(|| Duration::from_secs(settings.db_idle_timeout_secs)),
+        }
+    }
+}
+
+/// Connects a pool, retrying with backoff so the agent can start before
+/// Postgres accepts connections (e.g. in docker-compose). Only this initial
+/// connect is retried; the pool handles reconnects at runtime itself.
+pub async fn get_pool(
+    database_url: &str,
+    config: &PoolConfig,
+    retry: RetryPolicy,
+) -> Result<PgPool> {
+    let mut attempt = 0;
+    let pool = retry
+        .run("Database connection", || {
+            attempt += 1;
+            info!(
+                "Connecting to database (attempt {}/{})",
+                attempt,
+                retry.max_retries + 1
+            );
+            async {
+                Ok(PgPoolOptions::new()
+                    .max_connections(config.max_connections)
+                    .min_connections(config.min_connections)
+                    .acquire_timeout(config.acquire_timeout)
+                    .idle_timeout(config.idle_timeout)
+                    .connect(database_url)
+                    .await?)
+            }
+        })
         .await?;
 
     Ok(pool)
@@ -1,3 +1,3 @@
 pub mod connection;
 
-pub use connection::get_pool;
+pub use connection::{get_pool, PoolConfig};
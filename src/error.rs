@@ -0,0 +1,83 @@
+use crate::mcp::McpClientError;
+use axum::http::StatusCode;
+use thiserror::Error;
+
+/// Errors that map to a specific client-facing error code. Anything else
+/// travelling through `anyhow` is reported as `internal_error`, except
+/// transport failures talking to providers and JSON-RPC errors from the MCP
+/// server, which count as `upstream_error` (`upstream_timeout` when the HTTP
+/// timeout or another upstream deadline expired).
+#[derive(Debug, Error)]
+pub enum AgentError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    RateLimited(String),
+    #[error("{0}")]
+    Upstream(String),
+    /// The configured model was rejected by the provider as unknown,
+    /// decommissioned or deprecated.
+    #[error("{0}")]
+    ModelUnavailable(String),
+    /// The provider's safety/content filter withheld the prompt or answer.
+    #[error("{0}")]
+    ContentFiltered(String),
+}
+
+impl AgentError {
+    /// Builds the error for a non-success provider HTTP status.
+    pub fn from_upstream_status(status: reqwest::StatusCode, message: String) -> Self {
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            AgentError::RateLimited(message)
+        } else {
+            AgentError::Upstream(message)
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AgentError::BadRequest(_) => "bad_request",
+            AgentError::Unauthorized(_) => "unauthorized",
+            AgentError::NotFound(_) => "not_found",
+            AgentError::RateLimited(_) => "rate_limited",
+            AgentError::Upstream(_) => "upstream_error",
+            AgentError::ModelUnavailable(_) => "model_unavailable",
+            AgentError::ContentFiltered(_) => "content_filtered",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AgentError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AgentError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AgentError::NotFound(_) => StatusCode::NOT_FOUND,
+            AgentError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AgentError::Upstream(_) | AgentError::ModelUnavailable(_) => StatusCode::BAD_GATEWAY,
+            AgentError::ContentFiltered(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+/// Returns the HTTP status and stable error code for any error.
+pub fn classify(err: &anyhow::Error) -> (StatusCode, &'static str) {
+    if let Some(agent_error) = err.downcast_ref::<AgentError>() {
+        return (agent_error.status(), agent_error.code());
+    }
+    if err.downcast_ref::<McpClientError>().is_some() {
+        return (StatusCode::BAD_GATEWAY, "upstream_error");
+    }
+    if err.downcast_ref::<tokio::time::error::Elapsed>().is_some() {
+        return (StatusCode::GATEWAY_TIMEOUT, "upstream_timeout");
+    }
+    if let Some(e) = err.downcast_ref::<reqwest::Error>() {
+        if e.is_timeout() {
+            return (StatusCode::GATEWAY_TIMEOUT, "upstream_timeout");
+        }
+        return (StatusCode::BAD_GATEWAY, "upstream_error");
+    }
+    (StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+}
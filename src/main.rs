@@ -2,55 +2,139 @@ mod agent;
 mod api;
 mod config;
 mod database;
+mod error;
 mod mcp;
 mod models;
+mod rate_limit;
+mod retry;
 mod session;
+#[cfg(test)]
+mod test_support;
 mod vector;
 
 use anyhow::Result;
-use tracing::info;
+use std::future::IntoFuture;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
 
-use config::{EmbeddingProvider, LlmProvider, Settings};
-use database::get_pool;
+use config::{EmbeddingProvider, LlmProvider, McpTransport, Settings};
+use database::{get_pool, PoolConfig};
+use error::AgentError;
+use retry::RetryPolicy;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Load environment variables
     dotenv::dotenv().ok();
 
-    // Initialize logging
+    // Load configuration
+    let settings = Settings::from_env()?;
+
+    // Initialize logging: RUST_LOG, if set, overrides LOG_LEVEL
     tracing_subscriber::fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(&settings.log_level)),
         )
         .init();
 
     info!("Starting BeautiBuk Agent...");
-
-    // Load configuration
-    let settings = Settings::from_env()?;
     info!("Configuration loaded");
 
     // Initialize database
-    let db_pool = get_pool(&settings.database_url).await?;
+    let pool_config = PoolConfig::from_settings(&settings);
+    let db_connect_retry = RetryPolicy::new(settings.db_connect_max_retries)
+        .with_base_delay(Duration::from_millis(settings.db_connect_retry_delay_ms));
+    let db_pool = get_pool(&settings.database_url, &pool_config, db_connect_retry).await?;
     info!("Database connection established");
 
     // Run migrations
     sqlx::migrate!("./migrations").run(&db_pool).await?;
     info!("Database migrations completed");
 
+    // Reads that tolerate replication lag go to the replica, if configured
+    let read_pool = match &settings.database_url_replica {
+        Some(url) => {
+            let pool = get_pool(url, &pool_config, db_connect_retry).await?;
+            info!("Read replica connection established");
+            pool
+        }
+        None => db_pool.clone(),
+    };
+
+    // HTTP clients for every upstream. Streamed answers and MCP tool calls
+    // can legitimately outlast HTTP_TIMEOUT_SECS, so they get a client
+    // without a total deadline; tool calls set MCP_TOOL_TIMEOUT_SECS per
+    // request instead
+    let http_timeout = Duration::from_secs(settings.http_timeout_secs);
+    let http_client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(settings.http_connect_timeout_secs))
+        .timeout(http_timeout)
+        .build()?;
+    let long_http_client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(settings.http_connect_timeout_secs))
+        .tcp_keepalive(Duration::from_secs(30))
+        .build()?;
+
     // Initialize services
-    let mcp_client = mcp::McpClient::new(settings.mcp_server_url.clone());
+    let tool_timeout = Duration::from_secs(settings.mcp_tool_timeout_secs);
+    let mcp_client = match settings.mcp_transport {
+        McpTransport::Http => mcp::McpClient::new(
+            mcp::HttpTransport::new(settings.mcp_server_url.clone())
+                .with_http_client(long_http_client.clone())
+                .with_request_timeout(http_timeout)
+                .with_tool_timeout(tool_timeout)
+                .with_retry_policy(RetryPolicy::new(settings.mcp_max_retries)),
+        ),
+        McpTransport::Stdio => {
+            info!(
+                "Starting stdio MCP server: {}",
+                settings.mcp_command.join(" ")
+            );
+            mcp::McpClient::new(
+                mcp::StdioTransport::spawn(&settings.mcp_command[0], &settings.mcp_command[1..])?
+                    .with_request_timeout(Duration::from_secs(settings.mcp_stdio_timeout_secs))
+                    .with_tool_timeout(tool_timeout),
+            )
+        }
+    };
+    let mcp_client = Arc::new(
+        mcp_client
+            .with_tools_cache_ttl(Duration::from_secs(settings.mcp_tools_cache_secs))
+            .with_protocol_version(settings.mcp_protocol_version.clone()),
+    );
 
     // Initialize MCP connection
-    mcp_client.initialize().await?;
-    info!("MCP client initialized");
+    match mcp_client.initialize().await {
+        Ok(()) => info!("MCP client initialized"),
+        Err(e) if settings.mcp_degraded_mode => {
+            warn!("MCP server unavailable, starting without tools: {}", e)
+        }
+        Err(e) => return Err(e),
+    }
+
+    // Keep the MCP connection warm and detect server death early
+    if settings.mcp_ping_interval_secs > 0 {
+        let pinger = mcp_client.clone();
+        let mut ping_interval =
+            tokio::time::interval(Duration::from_secs(settings.mcp_ping_interval_secs));
+        tokio::spawn(async move {
+            loop {
+                ping_interval.tick().await;
+                if let Err(e) = pinger.ping().await {
+                    warn!("MCP ping failed: {}", e);
+                }
+            }
+        });
+    }
 
     // Initialize LLM client
     let llm_provider = match settings.llm_provider {
         LlmProvider::Groq => agent::llm::LlmProvider::Groq,
         LlmProvider::Google => agent::llm::LlmProvider::Google,
+        LlmProvider::OpenAi => agent::llm::LlmProvider::OpenAi,
+        LlmProvider::Claude => agent::llm::LlmProvider::Claude,
     };
 
     let llm_client = agent::llm::LlmClient::new(
@@ -59,24 +143,221 @@ async fn main() -> Result<()> {
         settings.llm_model.clone(),
         settings.llm_temperature,
         settings.llm_max_tokens,
+    )
+    .with_base_url(settings.llm_base_url.clone())
+    .with_http_client(http_client.clone())
+    .with_streaming_http_client(long_http_client, http_timeout)
+    .with_retry_policy(RetryPolicy::new(settings.llm_max_retries))
+    .with_extra_headers(settings.llm_extra_headers.clone())
+    .with_extra_params(settings.llm_extra_params.clone())
+    .with_reasoning_format(settings.llm_reasoning_format.clone())
+    .with_schema_sanitizer(agent::SchemaSanitizer::new(
+        settings.llm_schema_strip.clone(),
+    ))
+    .with_tool_required(settings.llm_tool_required)
+    .with_summary_settings(settings.summary_model.clone(), settings.summary_max_tokens)
+    .with_mcp_degraded_mode(
+        settings
+            .mcp_degraded_mode
+            .then(|| settings.mcp_degraded_note.clone()),
+    )
+    .with_out_of_scope_instruction(settings.out_of_scope_instruction.clone())
+    .with_unknown_tool_recovery(settings.recover_unknown_tools)
+    .with_confirmation_required(settings.confirm_tools.clone())
+    .with_max_concurrent_tool_calls(settings.mcp_max_concurrent_calls)
+    .with_max_tool_iterations(settings.llm_max_tool_iterations)
+    .with_max_request_bytes(settings.llm_max_request_bytes)
+    .with_tool_result_shaper(
+        agent::ToolResultShaper::new(
+            settings.tool_result_max_depth,
+            settings.tool_result_max_depth_per_tool.clone(),
+        )
+        .with_templates(settings.tool_result_templates.clone()),
     );
 
+    // Surface a retired or misspelled model now rather than on every request
+    match llm_client.preflight().await {
+        Ok(()) => info!("LLM model {} is available", settings.llm_model),
+        Err(e) => match e.downcast_ref::<AgentError>() {
+            Some(AgentError::ModelUnavailable(_)) => {}
+            _ => warn!("LLM preflight check failed: {}", e),
+        },
+    }
+
+    if !settings.model_pricing.is_empty()
+        && !settings.model_pricing.contains_key(&settings.llm_model)
+    {
+        warn!(
+            "No pricing configured for model {}; turn costs won't be tracked",
+            settings.llm_model
+        );
+    }
+
     // Initialize embedding service
     let embedding_provider = match settings.embedding_provider {
         EmbeddingProvider::Google => agent::embeddings::EmbeddingProvider::Google,
     };
 
-    let embedding_service = agent::embeddings::EmbeddingService::new(
-        embedding_provider,
-        settings.embedding_api_key.clone(),
-        settings.embedding_model.clone(),
-    );
+    let embedding_batch_size = settings
+        .embedding_batch_size
+        .unwrap_or_else(|| embedding_provider.default_batch_size());
+    let embedding_cache_ttl = Duration::from_secs(settings.embedding_cache_ttl_hours * 3600);
+    let build_embedding_service = || {
+        let service = agent::embeddings::EmbeddingService::new(
+            embedding_provider.clone(),
+            settings.embedding_api_key.clone(),
+            settings.embedding_model.clone(),
+        )
+        .with_http_client(http_client.clone())
+        .with_retry_policy(RetryPolicy::new(settings.embedding_max_retries))
+        .with_extra_headers(settings.embedding_extra_headers.clone())
+        .with_empty_as_zero(settings.embed_empty_as_zero)
+        .with_single_flight(settings.embedding_single_flight)
+        .with_memory_cache(settings.embedding_cache_size)
+        .with_batch_size(embedding_batch_size);
+        if settings.persistent_embedding_cache {
+            service.with_persistent_cache(db_pool.clone(), embedding_cache_ttl)
+        } else {
+            service
+        }
+    };
+    let embedding_service = build_embedding_service();
+
+    // Housekeeping runs on the session sweep interval, or every 5 minutes
+    // when the sweep is off
+    let maintenance_interval = Duration::from_secs(match settings.session_sweep_interval_secs {
+        0 => 300,
+        secs => secs,
+    });
+
+    // Evict persistently cached embeddings past their TTL
+    if settings.persistent_embedding_cache {
+        let evictor = build_embedding_service();
+        let mut evict_interval = tokio::time::interval(maintenance_interval);
+        tokio::spawn(async move {
+            loop {
+                evict_interval.tick().await;
+                match evictor.evict_expired_cache_entries().await {
+                    Ok(0) => {}
+                    Ok(evicted) => info!("Evicted {} expired cached embeddings", evicted),
+                    Err(e) => error!("Embedding cache eviction failed: {}", e),
+                }
+            }
+        });
+    }
+
+    // Embed queued messages (deferred embedding, session imports, vectors
+    // skipped on a dimension mismatch) in the background
+    let indexer = agent::EmbeddingIndexer::new(
+        vector::VectorService::new(db_pool.clone())
+            .with_dimension_mismatch_skip(settings.skip_embedding_dimension_mismatch)
+            .with_expected_dimensions(embedding_provider.dimensions()),
+        build_embedding_service(),
+        embedding_batch_size,
+    )
+    .with_max_attempts(settings.embedding_index_max_attempts);
+    let mut index_interval = tokio::time::interval(Duration::from_secs(
+        settings.embedding_index_interval_secs.max(1),
+    ));
+    tokio::spawn(async move {
+        loop {
+            index_interval.tick().await;
+            loop {
+                match indexer.run_once().await {
+                    Ok(0) => break,
+                    Ok(indexed) => info!("Indexed {} queued embeddings", indexed),
+                    Err(e) => {
+                        error!("Queued embedding indexing failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
 
     // Initialize vector service
-    let vector_service = vector::VectorService::new(db_pool.clone());
+    let mut vector_service = vector::VectorService::new(db_pool.clone())
+        .with_read_pool(read_pool.clone())
+        .with_dimension_mismatch_skip(settings.skip_embedding_dimension_mismatch)
+        .with_expected_dimensions(embedding_provider.dimensions());
+    vector_service.check_dimensions().await?;
+    if settings.rag_recency_weighting {
+        vector_service =
+            vector_service.with_recency_weighting(settings.rag_recency_half_life_hours);
+    }
+
+    // Archive for conversations past their retention period
+    let archive = match &settings.archive_bucket {
+        Some(bucket) => {
+            info!("Archiving expired conversations to bucket {}", bucket);
+            Some(Arc::new(
+                session::ConversationArchive::new(
+                    bucket.clone(),
+                    settings.archive_prefix.clone(),
+                    settings.archive_endpoint.clone(),
+                )
+                .await,
+            ))
+        }
+        None => None,
+    };
 
     // Initialize session manager
-    let session_manager = session::SessionManager::new(db_pool.clone());
+    let mut session_manager = session::SessionManager::new(db_pool.clone())
+        .with_read_pool(read_pool.clone())
+        .with_history_dedup(settings.dedup_history);
+    if settings.session_expire_on_load {
+        session_manager = session_manager.with_expiry(
+            settings.session_timeout_minutes,
+            settings.retention_rules.clone(),
+        );
+    }
+    if let Some(archive) = &archive {
+        session_manager = session_manager.with_archive(archive.clone());
+    }
+
+    // Clean up histories duplicated by the old insert-per-turn storage
+    if settings.dedup_stored_history_on_startup {
+        let deduped = session_manager.dedup_stored_histories().await?;
+        info!("Deduplicated {} stored conversation histories", deduped);
+    }
+
+    // Periodically delete (or archive, then delete) conversations past their
+    // retention period, if enabled; expired pending actions are always cleaned
+    // up
+    let sweep_sessions = settings.session_sweep_interval_secs > 0;
+    if !sweep_sessions {
+        info!("Session sweep disabled (SESSION_SWEEP_INTERVAL_SECS=0)");
+    }
+    let mut sweeper = session::SessionManager::new(db_pool.clone());
+    if let Some(archive) = archive {
+        sweeper = sweeper.with_archive(archive);
+    }
+    let default_retention = settings.session_timeout_minutes;
+    let retention_rules = settings.retention_rules.clone();
+    let pending_action_ttl = settings.pending_action_ttl_minutes;
+    let mut sweep_interval = tokio::time::interval(maintenance_interval);
+    tokio::spawn(async move {
+        loop {
+            sweep_interval.tick().await;
+            if sweep_sessions {
+                match sweeper
+                    .delete_expired_sessions(default_retention, &retention_rules)
+                    .await
+                {
+                    Ok(0) => {}
+                    Ok(deleted) => info!("Deleted {} expired conversation rows", deleted),
+                    Err(e) => error!("Session expiry sweep failed: {}", e),
+                }
+            }
+            if let Err(e) = sweeper
+                .delete_expired_pending_actions(pending_action_ttl)
+                .await
+            {
+                error!("Pending action cleanup failed: {}", e);
+            }
+        }
+    });
 
     // Initialize orchestrator
     let orchestrator = agent::orchestrator::Orchestrator::new(
@@ -85,17 +366,161 @@ async fn main() -> Result<()> {
         session_manager,
         vector_service,
         embedding_service,
-    );
+    )
+    .with_rag_scope(settings.rag_scope)
+    .with_embedded_roles(settings.rag_embed_roles.clone())
+    .with_rag_roles(settings.rag_roles.clone())
+    .with_rag_min_similarity(settings.rag_min_similarity)
+    .with_history_filter(
+        settings.history_exclude_roles.clone(),
+        settings.history_exclude_prefixes.clone(),
+    )
+    .with_response_cleanup(settings.trim_response, settings.strip_code_fences)
+    .with_empty_response_storage(settings.empty_response_storage.clone())
+    .with_pending_action_ttl(settings.pending_action_ttl_minutes)
+    .with_response_styles(
+        settings.default_response_style.clone(),
+        settings.response_style_instructions.clone(),
+    )
+    .with_json_output(settings.json_repair, settings.json_max_retries)
+    .with_session_locking(settings.session_locking)
+    .with_deferred_embeddings(settings.defer_embeddings)
+    .with_content_filter_reply(settings.content_filter_reply.clone())
+    .with_max_concurrent_requests(settings.max_concurrent_requests)
+    .with_max_batch_size(settings.max_batch_size)
+    .with_stream_keepalive(settings.stream_keepalive_secs)
+    .with_agent_api_key(settings.agent_api_key.clone())
+    .with_rate_limit(settings.rate_limit_per_minute, settings.rate_limit_key)
+    .with_trusted_forwarded_for(settings.rate_limit_trust_forwarded_for)
+    .with_status_api_key(settings.status_api_key.clone())
+    .with_admin_api_key(settings.admin_api_key.clone())
+    .with_retry_budget(settings.request_retry_budget_ms)
+    .with_respond_before_persist(settings.respond_before_persist)
+    .with_pricing(settings.model_pricing.get(&settings.llm_model).copied())
+    .with_tools_used(settings.return_tools_used)
+    .with_webhook(settings.webhook_url.clone().map(|url| {
+        agent::WebhookNotifier::new(url)
+            .with_http_client(http_client.clone())
+            .with_retry_policy(RetryPolicy::new(settings.webhook_max_retries))
+            .with_secret(settings.webhook_secret.clone())
+    }));
+    let orchestrator = match settings.context_provider_url.clone() {
+        Some(url) => orchestrator.with_context_provider(
+            agent::HttpContextProvider::new(url)
+                .with_http_client(http_client.clone())
+                .with_retry_policy(RetryPolicy::new(settings.context_provider_max_retries)),
+        ),
+        None => orchestrator,
+    };
+    let orchestrator = if settings.compact_on_write {
+        orchestrator.with_compaction(
+            settings.compact_on_write_threshold,
+            settings.compact_keep_recent,
+        )
+    } else {
+        orchestrator
+    };
+    let orchestrator = if settings.history_compression {
+        orchestrator.with_history_compression(settings.history_compression_threshold)
+    } else {
+        orchestrator
+    };
 
     // Build application
-    let app = api::create_router(orchestrator);
+    let background_tasks = orchestrator.task_tracker();
+    let app = api::create_router(orchestrator, &settings.allowed_origins);
 
     // Start server
     let listener =
         tokio::net::TcpListener::bind(format!("0.0.0.0:{}", settings.agent_port)).await?;
     info!("Server listening on port {}", settings.agent_port);
 
-    axum::serve(listener, app).await?;
+    // On SIGTERM/Ctrl-C stop accepting connections and let in-flight chat
+    // turns finish before closing the database pools, up to the drain
+    // deadline; long-lived SSE/WebSocket connections could otherwise hold
+    // shutdown open forever
+    let drain_timeout = Duration::from_secs(settings.shutdown_drain_timeout_secs);
+    let (deadline_tx, mut deadline_rx) = tokio::sync::watch::channel(None);
+    let mut server_deadline = deadline_rx.clone();
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        let _ = deadline_tx.send(Some(tokio::time::Instant::now() + drain_timeout));
+    });
+    tokio::select! {
+        result = server.into_future() => result?,
+        deadline = wait_for_deadline(&mut server_deadline) => {
+            tokio::time::sleep_until(deadline).await;
+            warn!("Connections still open after the drain timeout, closing them");
+        }
+    }
+
+    // Work that outlives its request, e.g. storing a turn after an early reply
+    background_tasks.close();
+    let deadline = wait_for_deadline(&mut deadline_rx).await;
+    if tokio::time::timeout_at(deadline, background_tasks.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "{} background tasks still running after the drain timeout",
+            background_tasks.len()
+        );
+    }
+
+    info!("In-flight requests drained, closing database connections");
+    read_pool.close().await;
+    db_pool.close().await;
+    info!("Shutdown complete");
 
     Ok(())
 }
+
+/// The shutdown drain deadline, once a shutdown signal has set it.
+async fn wait_for_deadline(
+    deadline: &mut tokio::sync::watch::Receiver<Option<tokio::time::Instant>>,
+) -> tokio::time::Instant {
+    loop {
+        if let Some(deadline) = *deadline.borrow_and_update() {
+            return deadline;
+        }
+        if deadline.changed().await.is_err() {
+            // The server stopped without a signal; drain without a deadline
+            return tokio::time::Instant::now() + Duration::from_secs(u32::MAX as u64);
+        }
+    }
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    info!("Shutdown signal received, draining in-flight requests");
+}
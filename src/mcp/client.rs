@@ -1,24 +1,76 @@
+use crate::mcp::error::McpClientError;
 use crate::mcp::models::*;
+use crate::mcp::stats::{ToolStats, ToolStatsSnapshot};
+use crate::mcp::transport::McpTransport;
 use anyhow::{anyhow, Result};
-use reqwest::Client;
 use serde_json::json;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Protocol version requested unless configured otherwise.
+pub const DEFAULT_PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// Versions this client can talk. A server may answer `initialize` with any
+/// of these (or the configured one) instead of the requested version. Later
+/// versions need transport changes (`Mcp-Session-Id`, the
+/// `MCP-Protocol-Version` header, SSE responses) the agent doesn't make.
+const SUPPORTED_PROTOCOL_VERSIONS: [&str; 1] = ["2024-11-05"];
+
+/// Bound on pages followed in a paginated list, against cursor loops.
+const MAX_LIST_PAGES: usize = 100;
 
 pub struct McpClient {
-    client: Client,
-    base_url: String,
+    transport: Box<dyn McpTransport>,
+    /// Version sent in `initialize`.
+    protocol_version: String,
+    /// Version the server agreed to, once initialized.
+    negotiated_version: RwLock<Option<String>>,
     request_id: AtomicU64,
+    tool_stats: ToolStats,
+    healthy: AtomicBool,
+    tools_cache_ttl: Duration,
+    tools_cache: RwLock<Option<(Instant, Vec<McpTool>)>>,
+    tools_cache_hits: AtomicU64,
+    tools_cache_misses: AtomicU64,
 }
 
 impl McpClient {
-    pub fn new(base_url: String) -> Self {
+    pub fn new(transport: impl McpTransport + 'static) -> Self {
         Self {
-            client: Client::new(),
-            base_url,
+            transport: Box::new(transport),
+            protocol_version: DEFAULT_PROTOCOL_VERSION.to_string(),
+            negotiated_version: RwLock::new(None),
             request_id: AtomicU64::new(1),
+            tool_stats: ToolStats::new(),
+            healthy: AtomicBool::new(false),
+            tools_cache_ttl: Duration::ZERO,
+            tools_cache: RwLock::new(None),
+            tools_cache_hits: AtomicU64::new(0),
+            tools_cache_misses: AtomicU64::new(0),
         }
     }
 
+    /// How long `cached_tools` reuses a `tools/list` result. Zero disables
+    /// caching.
+    pub fn with_tools_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.tools_cache_ttl = ttl;
+        self
+    }
+
+    /// Requests this MCP protocol version in `initialize`.
+    pub fn with_protocol_version(mut self, version: String) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    /// The protocol version the server chose, `None` before `initialize`.
+    pub fn protocol_version(&self) -> Option<String> {
+        self.negotiated_version.read().unwrap().clone()
+    }
+
     fn next_id(&self) -> u64 {
         self.request_id.fetch_add(1, Ordering::SeqCst)
     }
@@ -28,7 +80,7 @@ impl McpClient {
             .send_request(
                 "initialize",
                 json!({
-                    "protocolVersion": "2024-11-05",
+                    "protocolVersion": self.protocol_version,
                     "capabilities": {},
                     "clientInfo": {
                         "name": "beautibuk-agent",
@@ -38,18 +90,75 @@ impl McpClient {
             )
             .await?;
 
-        if response.error.is_some() {
-            return Err(anyhow!("MCP initialization failed: {:?}", response.error));
+        if let Some(error) = response.error {
+            return Err(McpClientError::new("initialize", error).into());
+        }
+
+        // Servers that omit the version are taken to accept the requested one
+        let negotiated = response
+            .result
+            .and_then(|result| result.protocol_version)
+            .unwrap_or_else(|| self.protocol_version.clone());
+        if negotiated != self.protocol_version
+            && !SUPPORTED_PROTOCOL_VERSIONS.contains(&negotiated.as_str())
+        {
+            return Err(anyhow!(
+                "MCP server uses protocol version {}, which this agent doesn't support \
+                 (requested {}; supported: {}). Set MCP_PROTOCOL_VERSION to a version \
+                 both sides support",
+                negotiated,
+                self.protocol_version,
+                SUPPORTED_PROTOCOL_VERSIONS.join(", ")
+            ));
+        }
+        if negotiated != self.protocol_version {
+            info!(
+                "MCP server chose protocol version {} (requested {})",
+                negotiated, self.protocol_version
+            );
         }
+        *self.negotiated_version.write().unwrap() = Some(negotiated);
+
+        // The spec has clients confirm before any other request; strict
+        // servers refuse tools/list until they do
+        self.send_notification("notifications/initialized", json!({}))
+            .await?;
 
+        self.healthy.store(true, Ordering::Relaxed);
         Ok(())
     }
 
+    /// Sends an MCP `ping` and records the outcome in the health flag.
+    pub async fn ping(&self) -> Result<()> {
+        let result = match self.send_request("ping", json!({})).await {
+            Ok(response) => match response.error {
+                Some(error) => Err(McpClientError::new("ping", error).into()),
+                None => Ok(()),
+            },
+            Err(e) => Err(e),
+        };
+        self.healthy.store(result.is_ok(), Ordering::Relaxed);
+        result
+    }
+
+    /// Whether the server answered the last `initialize`/`ping`.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// `(hits, misses)` of the tool list cache since startup.
+    pub fn tools_cache_stats(&self) -> (u64, u64) {
+        (
+            self.tools_cache_hits.load(Ordering::Relaxed),
+            self.tools_cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
     pub async fn list_tools(&self) -> Result<Vec<McpTool>> {
         let response = self.send_request("tools/list", json!({})).await?;
 
         if let Some(error) = response.error {
-            return Err(anyhow!("MCP error: {}", error.message));
+            return Err(McpClientError::new("tools/list", error).into());
         }
 
         if let Some(result) = response.result {
@@ -61,7 +170,67 @@ impl McpClient {
         Err(anyhow!("No tools in MCP response"))
     }
 
-    pub async fn call_tool(&self, name: &str, arguments: &serde_json::Value) -> Result<String> {
+    /// Like `list_tools`, but reuses the last result until the cache TTL
+    /// expires.
+    pub async fn cached_tools(&self) -> Result<Vec<McpTool>> {
+        if let Some((fetched_at, tools)) = self.tools_cache.read().unwrap().as_ref() {
+            if fetched_at.elapsed() < self.tools_cache_ttl {
+                self.tools_cache_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(tools.clone());
+            }
+        }
+        self.tools_cache_misses.fetch_add(1, Ordering::Relaxed);
+        self.refresh_tools().await
+    }
+
+    /// Fetches the tool list now, replacing the cached one even if it has
+    /// not expired (e.g. after the MCP server was redeployed).
+    pub async fn refresh_tools(&self) -> Result<Vec<McpTool>> {
+        let tools = match self.list_tools().await {
+            Ok(tools) => tools,
+            Err(e) => {
+                self.healthy.store(false, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+        self.healthy.store(true, Ordering::Relaxed);
+        if !self.tools_cache_ttl.is_zero() {
+            *self.tools_cache.write().unwrap() = Some((Instant::now(), tools.clone()));
+        }
+        Ok(tools)
+    }
+
+    pub fn tool_stats(&self) -> Vec<ToolStatsSnapshot> {
+        self.tool_stats.snapshot()
+    }
+
+    pub async fn call_tool(
+        &self,
+        name: &str,
+        arguments: &serde_json::Value,
+    ) -> Result<McpToolOutput> {
+        let started = Instant::now();
+        let result = self.execute_tool(name, arguments).await;
+        self.tool_stats
+            .record(name, started.elapsed(), result.is_ok());
+        result
+    }
+
+    /// `call_tool` for the common case of text results; see
+    /// `McpToolOutput::text`.
+    pub async fn call_tool_text(
+        &self,
+        name: &str,
+        arguments: &serde_json::Value,
+    ) -> Result<String> {
+        Ok(self.call_tool(name, arguments).await?.text())
+    }
+
+    async fn execute_tool(
+        &self,
+        name: &str,
+        arguments: &serde_json::Value,
+    ) -> Result<McpToolOutput> {
         let response = self
             .send_request(
                 "tools/call",
@@ -73,20 +242,106 @@ impl McpClient {
             .await?;
 
         if let Some(error) = response.error {
-            return Err(anyhow!("MCP tool call error: {}", error.message));
+            return Err(McpClientError::new("tools/call", error).into());
         }
 
-        if let Some(result) = response.result {
-            if let Some(content) = result.content {
-                if let Some(first_content) = content.first() {
-                    return Ok(first_content.text.clone());
-                }
+        if let Some(content) = response.result.and_then(|result| result.content) {
+            if !content.is_empty() {
+                return Ok(McpToolOutput { content });
             }
         }
 
         Err(anyhow!("No content in MCP tool response"))
     }
 
+    /// Every resource the server offers, following `nextCursor` pages.
+    pub async fn list_resources(&self) -> Result<Vec<McpResource>> {
+        self.list_all("resources/list", |result| result.resources)
+            .await
+    }
+
+    /// Every prompt template the server offers, following `nextCursor` pages.
+    pub async fn list_prompts(&self) -> Result<Vec<McpPrompt>> {
+        self.list_all("prompts/list", |result| result.prompts).await
+    }
+
+    /// Renders the prompt `name` with `arguments` into its messages.
+    pub async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: &HashMap<String, String>,
+    ) -> Result<Vec<McpPromptMessage>> {
+        let response = self
+            .send_request(
+                "prompts/get",
+                json!({
+                    "name": name,
+                    "arguments": arguments
+                }),
+            )
+            .await?;
+
+        if let Some(error) = response.error {
+            return Err(McpClientError::new("prompts/get", error).into());
+        }
+
+        response
+            .result
+            .and_then(|result| result.messages)
+            .ok_or_else(|| anyhow!("No messages in MCP prompts/get response"))
+    }
+
+    /// The contents of the resource at `uri` (several for e.g. a directory).
+    pub async fn read_resource(&self, uri: &str) -> Result<Vec<McpResourceContents>> {
+        let response = self
+            .send_request("resources/read", json!({ "uri": uri }))
+            .await?;
+
+        if let Some(error) = response.error {
+            return Err(McpClientError::new("resources/read", error).into());
+        }
+
+        response
+            .result
+            .and_then(|result| result.contents)
+            .ok_or_else(|| anyhow!("No contents in MCP resources/read response"))
+    }
+
+    /// Runs a paginated `*/list` method, following `nextCursor` until the
+    /// last page.
+    async fn list_all<T>(
+        &self,
+        method: &str,
+        items: impl Fn(McpResult) -> Option<Vec<T>>,
+    ) -> Result<Vec<T>> {
+        let mut all = Vec::new();
+        let mut cursor: Option<String> = None;
+        for _ in 0..MAX_LIST_PAGES {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let response = self.send_request(method, params).await?;
+            if let Some(error) = response.error {
+                return Err(McpClientError::new(method, error).into());
+            }
+
+            let result = response
+                .result
+                .ok_or_else(|| anyhow!("No result in MCP {} response", method))?;
+            cursor = result.next_cursor.clone();
+            all.extend(items(result).unwrap_or_default());
+            if cursor.is_none() {
+                return Ok(all);
+            }
+        }
+        Err(anyhow!(
+            "MCP {} returned more than {} pages",
+            method,
+            MAX_LIST_PAGES
+        ))
+    }
+
     async fn send_request(&self, method: &str, params: serde_json::Value) -> Result<McpResponse> {
         let request = McpRequest {
             jsonrpc: "2.0".to_string(),
@@ -95,19 +350,16 @@ impl McpClient {
             params,
         };
 
-        let response = self
-            .client
-            .post(format!("{}/mcp", self.base_url))
-            .json(&request)
-            .send()
-            .await?;
+        self.transport.send_request(&request).await
+    }
 
-        if !response.status().is_success() {
-            let error_text = response.text().await?;
-            return Err(anyhow!("MCP HTTP error: {}", error_text));
-        }
+    async fn send_notification(&self, method: &str, params: serde_json::Value) -> Result<()> {
+        let notification = McpNotification {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params,
+        };
 
-        let mcp_response: McpResponse = response.json().await?;
-        Ok(mcp_response)
+        self.transport.send_notification(&notification).await
     }
 }
@@ -0,0 +1,24 @@
+use crate::mcp::models::McpError;
+use thiserror::Error;
+
+/// A JSON-RPC error the MCP server returned for a request, with its numeric
+/// code (e.g. -32601 method not found) and optional `data` payload.
+#[derive(Debug, Error)]
+#[error("MCP {method} error {code}: {message}")]
+pub struct McpClientError {
+    pub method: String,
+    pub code: i32,
+    pub message: String,
+    pub data: Option<serde_json::Value>,
+}
+
+impl McpClientError {
+    pub fn new(method: &str, error: McpError) -> Self {
+        Self {
+            method: method.to_string(),
+            code: error.code,
+            message: error.message,
+            data: error.data,
+        }
+    }
+}
@@ -1,5 +1,12 @@
 pub mod client;
+pub mod error;
 pub mod models;
+pub mod openapi;
+pub mod stats;
+pub mod transport;
 
 pub use client::McpClient;
+pub use error::McpClientError;
 pub use models::*;
+pub use stats::ToolStatsSnapshot;
+pub use transport::{HttpTransport, StdioTransport};
@@ -8,11 +8,19 @@ pub struct McpRequest {
     pub params: serde_json::Value,
 }
 
+/// A JSON-RPC notification: like a request, but without an `id`, so the
+/// server sends no response.
+#[derive(Debug, Serialize)]
+pub struct McpNotification {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: serde_json::Value,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct McpResponse {
     #[allow(dead_code)]
     pub jsonrpc: String,
-    #[allow(dead_code)]
     pub id: u64,
     pub result: Option<McpResult>,
     pub error: Option<McpError>,
@@ -20,8 +28,22 @@ pub struct McpResponse {
 
 #[derive(Debug, Deserialize)]
 pub struct McpResult {
+    /// Version the server chose, in an `initialize` result.
+    #[serde(rename = "protocolVersion")]
+    pub protocol_version: Option<String>,
     pub tools: Option<Vec<McpTool>>,
     pub content: Option<Vec<McpContent>>,
+    /// From `resources/list`.
+    pub resources: Option<Vec<McpResource>>,
+    /// From `resources/read`.
+    pub contents: Option<Vec<McpResourceContents>>,
+    /// From `prompts/list`.
+    pub prompts: Option<Vec<McpPrompt>>,
+    /// From `prompts/get`: the rendered prompt.
+    pub messages: Option<Vec<McpPromptMessage>>,
+    /// Set when a list result continues on another page.
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -32,19 +54,104 @@ pub struct McpTool {
     pub input_schema: serde_json::Value,
 }
 
-#[derive(Debug, Deserialize)]
+/// A piece of server data (a service menu, staff bios, ...) readable by URI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpResource {
+    pub uri: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(rename = "mimeType", default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+}
+
+/// What `resources/read` returned for one URI: `text`, or base64 `blob`
+/// for binary data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpResourceContents {
+    pub uri: String,
+    #[serde(rename = "mimeType", default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+}
+
+/// One content block of a tool result: `text`, or e.g. `image`/`audio`
+/// with base64 `data`.
+#[derive(Debug, Clone, Deserialize)]
 pub struct McpContent {
     #[serde(rename = "type")]
-    #[allow(dead_code)]
     pub content_type: String,
-    pub text: String,
+    pub text: Option<String>,
+    #[serde(rename = "mimeType")]
+    pub mime_type: Option<String>,
+}
+
+/// Everything a `tools/call` returned, in block order.
+#[derive(Debug, Clone)]
+pub struct McpToolOutput {
+    pub content: Vec<McpContent>,
+}
+
+impl McpToolOutput {
+    /// Text blocks joined by newlines. Non-text blocks can't be passed to the
+    /// model, so each is replaced by a short note that it was omitted.
+    pub fn text(&self) -> String {
+        self.content
+            .iter()
+            .map(McpContent::text)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl McpContent {
+    /// The block's text, or a note that a non-text block was omitted.
+    pub fn text(&self) -> String {
+        match &self.text {
+            Some(text) if self.content_type == "text" => text.clone(),
+            _ => match &self.mime_type {
+                Some(mime_type) => {
+                    format!("[{} content ({}) omitted]", self.content_type, mime_type)
+                }
+                None => format!("[{} content omitted]", self.content_type),
+            },
+        }
+    }
+}
+
+/// A prompt template offered by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpPrompt {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub arguments: Vec<McpPromptArgument>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpPromptArgument {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// One message of a rendered prompt.
+#[derive(Debug, Clone, Deserialize)]
+pub struct McpPromptMessage {
+    /// `user` or `assistant`.
+    pub role: String,
+    pub content: McpContent,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct McpError {
-    #[allow(dead_code)]
     pub code: i32,
     pub message: String,
-    #[allow(dead_code)]
     pub data: Option<serde_json::Value>,
 }
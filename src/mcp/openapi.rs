@@ -0,0 +1,51 @@
+use crate::mcp::McpTool;
+use serde_json::{json, Map, Value};
+
+/// Describes MCP tools as an OpenAPI 3 document: one `POST /tools/{name}`
+/// operation per tool, with the tool's `inputSchema` as the request body
+/// schema under `components.schemas`.
+pub fn tools_to_openapi(tools: &[McpTool]) -> Value {
+    let mut paths = Map::new();
+    let mut schemas = Map::new();
+
+    for tool in tools {
+        let schema_name = format!("{}Input", tool.name);
+        schemas.insert(schema_name.clone(), tool.input_schema.clone());
+        paths.insert(
+            format!("/tools/{}", tool.name),
+            json!({
+                "post": {
+                    "operationId": tool.name,
+                    "summary": tool.description,
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": format!("#/components/schemas/{}", schema_name) }
+                            }
+                        }
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Tool output as returned by the MCP server",
+                            "content": {
+                                "text/plain": { "schema": { "type": "string" } }
+                            }
+                        }
+                    }
+                }
+            }),
+        );
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "beautibuk-agent tools",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "MCP tools available to the agent"
+        },
+        "paths": paths,
+        "components": { "schemas": schemas }
+    })
+}
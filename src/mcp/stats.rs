@@ -0,0 +1,95 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds (in milliseconds) of the latency histogram buckets. Calls slower
+/// than the last bound land in the overflow bucket.
+const LATENCY_BUCKETS_MS: [u64; 8] = [50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Debug, Clone, Default)]
+struct ToolCounters {
+    calls: u64,
+    successes: u64,
+    failures: u64,
+    total_latency_ms: u64,
+    buckets: [u64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+#[derive(Debug, Serialize)]
+pub struct LatencyBucket {
+    /// Upper bound in milliseconds, `None` for the overflow bucket.
+    pub le_ms: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ToolStatsSnapshot {
+    pub tool: String,
+    pub calls: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub avg_latency_ms: f64,
+    pub latency_histogram: Vec<LatencyBucket>,
+}
+
+/// Per-tool invocation counters, shared by every request through the MCP client.
+#[derive(Debug, Default)]
+pub struct ToolStats {
+    tools: Mutex<HashMap<String, ToolCounters>>,
+}
+
+impl ToolStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, tool: &str, latency: Duration, success: bool) {
+        let latency_ms = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| latency_ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+
+        let mut tools = self.tools.lock().unwrap();
+        let counters = tools.entry(tool.to_string()).or_default();
+        counters.calls += 1;
+        if success {
+            counters.successes += 1;
+        } else {
+            counters.failures += 1;
+        }
+        counters.total_latency_ms += latency_ms;
+        counters.buckets[bucket] += 1;
+    }
+
+    /// Returns stats for every tool seen so far, most-called first.
+    pub fn snapshot(&self) -> Vec<ToolStatsSnapshot> {
+        let tools = self.tools.lock().unwrap();
+        let mut snapshot: Vec<ToolStatsSnapshot> = tools
+            .iter()
+            .map(|(name, counters)| ToolStatsSnapshot {
+                tool: name.clone(),
+                calls: counters.calls,
+                successes: counters.successes,
+                failures: counters.failures,
+                avg_latency_ms: if counters.calls == 0 {
+                    0.0
+                } else {
+                    counters.total_latency_ms as f64 / counters.calls as f64
+                },
+                latency_histogram: counters
+                    .buckets
+                    .iter()
+                    .enumerate()
+                    .map(|(i, count)| LatencyBucket {
+                        le_ms: LATENCY_BUCKETS_MS.get(i).copied(),
+                        count: *count,
+                    })
+                    .collect(),
+            })
+            .collect();
+        snapshot.sort_by(|a, b| b.calls.cmp(&a.calls).then_with(|| a.tool.cmp(&b.tool)));
+        snapshot
+    }
+}
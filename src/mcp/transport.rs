@@ -0,0 +1,482 @@
+use crate::error::AgentError;
+use crate::mcp::models::{McpNotification, McpRequest, McpResponse};
+use crate::retry::RetryPolicy;
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+use reqwest::Client;
+use serde::Serialize;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::{oneshot, Mutex};
+use tracing::{debug, warn};
+
+/// Carries JSON-RPC requests to an MCP server and returns its responses.
+pub trait McpTransport: Send + Sync {
+    fn send_request<'a>(&'a self, request: &'a McpRequest) -> BoxFuture<'a, Result<McpResponse>>;
+
+    /// Delivers a notification; there is no response to wait for.
+    fn send_notification<'a>(
+        &'a self,
+        notification: &'a McpNotification,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Streamable HTTP: each request is POSTed to `{base_url}/mcp`.
+pub struct HttpTransport {
+    client: Client,
+    base_url: String,
+    retry: RetryPolicy,
+    /// Deadline for idempotent methods.
+    request_timeout: Option<Duration>,
+    /// Deadline for `tools/call` (and other non-idempotent methods), which
+    /// may legitimately outlast `request_timeout`.
+    tool_timeout: Duration,
+}
+
+impl HttpTransport {
+    pub fn new(base_url: String) -> Self {
+        Self {
+            client: Client::new(),
+            base_url,
+            retry: RetryPolicy::new(0),
+            request_timeout: None,
+            tool_timeout: DEFAULT_TOOL_TIMEOUT,
+        }
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_tool_timeout(mut self, timeout: Duration) -> Self {
+        self.tool_timeout = timeout;
+        self
+    }
+
+    /// Uses a shared, preconfigured HTTP client (timeouts, connection pool).
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Applies to idempotent methods only (see `is_idempotent`); a retried
+    /// `tools/call` could book the same appointment twice.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    async fn post(
+        &self,
+        message: &impl Serialize,
+        retry: RetryPolicy,
+        timeout: Option<Duration>,
+    ) -> Result<reqwest::Response> {
+        let url = format!("{}/mcp", self.base_url);
+        let response = retry
+            .send("MCP server", || {
+                let request = self.client.post(&url).json(message);
+                match timeout {
+                    Some(timeout) => request.timeout(timeout),
+                    None => request,
+                }
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await?;
+            return Err(AgentError::from_upstream_status(
+                status,
+                format!("MCP HTTP error: {}", error_text),
+            )
+            .into());
+        }
+        Ok(response)
+    }
+
+    async fn request(&self, request: &McpRequest) -> Result<McpResponse> {
+        let (retry, timeout) = if is_idempotent(&request.method) {
+            (self.retry, self.request_timeout)
+        } else {
+            (RetryPolicy::new(0), Some(self.tool_timeout))
+        };
+        let mcp_response: McpResponse = self.post(request, retry, timeout).await?.json().await?;
+        Ok(mcp_response)
+    }
+
+    /// The server acknowledges notifications with an empty 202.
+    async fn notify(&self, notification: &McpNotification) -> Result<()> {
+        self.post(notification, RetryPolicy::new(0), self.request_timeout)
+            .await?;
+        Ok(())
+    }
+}
+
+impl McpTransport for HttpTransport {
+    fn send_request<'a>(&'a self, request: &'a McpRequest) -> BoxFuture<'a, Result<McpResponse>> {
+        Box::pin(self.request(request))
+    }
+
+    fn send_notification<'a>(
+        &'a self,
+        notification: &'a McpNotification,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.notify(notification))
+    }
+}
+
+/// Methods that are safe to send again after a failure whose outcome is
+/// unknown; anything else (notably `tools/call`) is sent once.
+fn is_idempotent(method: &str) -> bool {
+    matches!(method, "initialize" | "ping")
+        || method.ends_with("/list")
+        || method.ends_with("/read")
+}
+
+/// How long a `tools/call` may run unless configured otherwise.
+const DEFAULT_TOOL_TIMEOUT: Duration = Duration::from_secs(120);
+
+type PendingResponses = DashMap<u64, oneshot::Sender<McpResponse>>;
+
+/// stdio: the server runs as a child process and exchanges newline-delimited
+/// JSON-RPC messages over its stdin/stdout. Its stderr is passed through. A
+/// server that exits is restarted on the next message, replaying the
+/// `initialize` handshake.
+pub struct StdioTransport {
+    program: String,
+    args: Vec<String>,
+    process: Mutex<StdioProcess>,
+    pending: Arc<PendingResponses>,
+    /// How long a request waits for its response.
+    request_timeout: Duration,
+    /// Same for `tools/call` (and other non-idempotent methods).
+    tool_timeout: Duration,
+    /// `initialize` (with its id) and `notifications/initialized` as sent,
+    /// replayed to a restarted server.
+    handshake: std::sync::Mutex<Vec<(Option<u64>, Vec<u8>)>>,
+}
+
+struct StdioProcess {
+    stdin: ChildStdin,
+    /// Set once the server's stdout closes.
+    exited: Arc<AtomicBool>,
+    // Kept so the server is killed when it is replaced or dropped
+    _child: Child,
+}
+
+impl StdioTransport {
+    /// Spawns `program` with `args` and starts reading its responses.
+    pub fn spawn(program: &str, args: &[String]) -> Result<Self> {
+        let pending: Arc<PendingResponses> = Arc::new(DashMap::new());
+        let process = spawn_process(program, args, pending.clone())?;
+        Ok(Self {
+            program: program.to_string(),
+            args: args.to_vec(),
+            process: Mutex::new(process),
+            pending,
+            request_timeout: Duration::from_secs(120),
+            tool_timeout: DEFAULT_TOOL_TIMEOUT,
+            handshake: std::sync::Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn with_tool_timeout(mut self, timeout: Duration) -> Self {
+        self.tool_timeout = timeout;
+        self
+    }
+
+    /// Writes one message to the server's stdin, restarting the server first
+    /// if it has exited.
+    async fn write_message(&self, message: &impl Serialize) -> Result<()> {
+        let line = to_line(message)?;
+        let mut process = self.process.lock().await;
+        if process.exited.load(Ordering::SeqCst) {
+            self.restart(&mut process).await?;
+        }
+        write_line(&mut process.stdin, &line).await
+    }
+
+    /// Replaces an exited server with a new process and replays the
+    /// handshake, so requests continue as if nothing happened.
+    async fn restart(&self, process: &mut StdioProcess) -> Result<()> {
+        warn!("MCP server '{}' exited, restarting it", self.program);
+        *process = spawn_process(&self.program, &self.args, self.pending.clone())?;
+        let handshake = self.handshake.lock().unwrap().clone();
+        for (id, line) in handshake {
+            match id {
+                Some(id) => {
+                    let waiter = self.register(id);
+                    write_line(&mut process.stdin, &line).await?;
+                    waiter.wait(self.request_timeout, "initialize").await?;
+                }
+                None => write_line(&mut process.stdin, &line).await?,
+            }
+        }
+        Ok(())
+    }
+
+    fn register(&self, id: u64) -> PendingResponse<'_> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.insert(id, tx);
+        PendingResponse {
+            pending: &self.pending,
+            id,
+            rx,
+        }
+    }
+
+    async fn exchange(&self, request: &McpRequest) -> Result<McpResponse> {
+        if request.method == "initialize" {
+            *self.handshake.lock().unwrap() = vec![(Some(request.id), to_line(request)?)];
+        }
+        let timeout = if is_idempotent(&request.method) {
+            self.request_timeout
+        } else {
+            self.tool_timeout
+        };
+        let waiter = self.register(request.id);
+        self.write_message(request).await?;
+        waiter.wait(timeout, &request.method).await
+    }
+
+    async fn notify(&self, notification: &McpNotification) -> Result<()> {
+        if notification.method == "notifications/initialized" {
+            self.handshake
+                .lock()
+                .unwrap()
+                .push((None, to_line(notification)?));
+        }
+        self.write_message(notification).await
+    }
+}
+
+/// A request's slot in the pending map. Dropping it (on timeout, error or
+/// a cancelled caller) removes the slot.
+struct PendingResponse<'a> {
+    pending: &'a PendingResponses,
+    id: u64,
+    rx: oneshot::Receiver<McpResponse>,
+}
+
+impl PendingResponse<'_> {
+    async fn wait(mut self, timeout: Duration, method: &str) -> Result<McpResponse> {
+        match tokio::time::timeout(timeout, &mut self.rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(AgentError::Upstream(
+                "MCP server process exited before responding".to_string(),
+            )
+            .into()),
+            Err(elapsed) => Err(anyhow::Error::new(elapsed).context(format!(
+                "MCP server did not answer {} within {}s",
+                method,
+                timeout.as_secs()
+            ))),
+        }
+    }
+}
+
+impl Drop for PendingResponse<'_> {
+    fn drop(&mut self) {
+        self.pending.remove(&self.id);
+    }
+}
+
+/// Starts the server and a task routing its stdout to `pending`.
+fn spawn_process(
+    program: &str,
+    args: &[String],
+    pending: Arc<PendingResponses>,
+) -> Result<StdioProcess> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to start MCP server '{}'", program))?;
+    let stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("MCP server stdin unavailable"))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("MCP server stdout unavailable"))?;
+
+    let exited = Arc::new(AtomicBool::new(false));
+    let reader_exited = exited.clone();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stdout).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => route_message(&pending, &line),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Failed to read from MCP server: {}", e);
+                    break;
+                }
+            }
+        }
+        warn!("MCP server closed its stdout");
+        // Dropping the senders fails every request still waiting
+        pending.clear();
+        reader_exited.store(true, Ordering::SeqCst);
+    });
+
+    Ok(StdioProcess {
+        stdin,
+        exited,
+        _child: child,
+    })
+}
+
+fn to_line(message: &impl Serialize) -> Result<Vec<u8>> {
+    // serde_json never emits raw newlines, so one line is one message
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+async fn write_line(stdin: &mut ChildStdin, line: &[u8]) -> Result<()> {
+    let written = match stdin.write_all(line).await {
+        Ok(()) => stdin.flush().await,
+        Err(e) => Err(e),
+    };
+    written.map_err(|e| AgentError::Upstream(format!("MCP server write failed: {}", e)).into())
+}
+
+impl McpTransport for StdioTransport {
+    fn send_request<'a>(&'a self, request: &'a McpRequest) -> BoxFuture<'a, Result<McpResponse>> {
+        Box::pin(self.exchange(request))
+    }
+
+    fn send_notification<'a>(
+        &'a self,
+        notification: &'a McpNotification,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.notify(notification))
+    }
+}
+
+/// Hands a response line to the request waiting for its id. Notifications
+/// and requests from the server are not supported and only logged.
+fn route_message(pending: &PendingResponses, line: &str) {
+    if line.trim().is_empty() {
+        return;
+    }
+    let message: serde_json::Value = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(e) => {
+            warn!("Ignoring malformed MCP server message: {}", e);
+            return;
+        }
+    };
+    if message.get("method").is_some() {
+        debug!("Ignoring MCP server message: {}", line);
+        return;
+    }
+    match serde_json::from_value::<McpResponse>(message) {
+        Ok(response) => match pending.remove(&response.id) {
+            Some((_, waiter)) => {
+                let _ = waiter.send(response);
+            }
+            None => warn!("MCP response for unknown request id {}", response.id),
+        },
+        Err(e) => warn!("Ignoring unparseable MCP response: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(id: u64, method: &str) -> McpRequest {
+        McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id,
+            method: method.to_string(),
+            params: json!({}),
+        }
+    }
+
+    #[tokio::test]
+    async fn stdio_request_times_out_and_frees_its_slot() {
+        // `cat` echoes requests back; they carry a method, so they are
+        // ignored and no response ever arrives
+        let transport = StdioTransport::spawn("cat", &[])
+            .unwrap()
+            .with_request_timeout(Duration::from_millis(100));
+        let error = transport
+            .exchange(&request(1, "tools/list"))
+            .await
+            .unwrap_err();
+        assert!(error
+            .downcast_ref::<tokio::time::error::Elapsed>()
+            .is_some());
+        assert!(transport.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stdio_server_is_restarted_after_exiting() {
+        // Answers one request, then exits
+        let script = r#"read line; echo '{"jsonrpc":"2.0","id":1,"result":{}}'"#;
+        let transport = StdioTransport::spawn("sh", &["-c".to_string(), script.to_string()])
+            .unwrap()
+            .with_request_timeout(Duration::from_secs(5));
+        transport.exchange(&request(1, "tools/list")).await.unwrap();
+        for _ in 0..50 {
+            if transport.process.lock().await.exited.load(Ordering::SeqCst) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // The replacement process answers id 1 again
+        let response = transport.exchange(&request(1, "tools/list")).await.unwrap();
+        assert_eq!(response.id, 1);
+    }
+
+    #[tokio::test]
+    async fn http_tool_call_times_out() {
+        let app = axum::Router::new().route(
+            "/mcp",
+            axum::routing::post(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                "{}"
+            }),
+        );
+        let transport = HttpTransport::new(crate::test_support::serve(app).await)
+            .with_tool_timeout(Duration::from_millis(100));
+        let error = transport
+            .request(&request(1, "tools/call"))
+            .await
+            .unwrap_err();
+        assert!(error
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+            .any(reqwest::Error::is_timeout));
+    }
+
+    #[test]
+    fn only_idempotent_methods_are_retried() {
+        assert!(is_idempotent("initialize"));
+        assert!(is_idempotent("tools/list"));
+        assert!(is_idempotent("resources/read"));
+        assert!(!is_idempotent("tools/call"));
+        assert!(!is_idempotent("prompts/get"));
+    }
+}
@@ -1,35 +1,343 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub role: String,
     pub content: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<ToolCall>>,
+    /// For `tool` messages: the id of the assistant tool call this answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ToolCall {
     pub id: String,
     pub r#type: String,
     pub function: FunctionCall,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FunctionCall {
     pub name: String,
     pub arguments: serde_json::Value,
 }
 
+/// Token counts reported by the LLM provider, summed across tool-call rounds.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    pub fn add(&mut self, other: TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// Dollar prices of a model, per 1000 tokens.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ModelPricing {
+    pub input_cost_per_1k: f64,
+    pub output_cost_per_1k: f64,
+}
+
+impl ModelPricing {
+    pub fn cost(&self, usage: &TokenUsage) -> f64 {
+        (usage.prompt_tokens as f64 * self.input_cost_per_1k
+            + usage.completion_tokens as f64 * self.output_cost_per_1k)
+            / 1000.0
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatRequest {
     pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub session_id: Option<String>,
+    /// Merged into the conversation's stored metadata. A `tags` string array
+    /// selects the retention rule applied to the conversation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Return the retrieved RAG snippets in `ChatResponse::context`.
+    #[serde(default)]
+    pub include_context: bool,
+    /// Return debug details of the turn in `ChatResponse::trace`.
+    #[serde(default)]
+    pub include_trace: bool,
+    /// `concise` or `detailed`; guides answer length (unlike `max_tokens`,
+    /// which hard-cuts it).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_style: Option<String>,
+    /// `false` processes the turn with stored history and RAG but saves
+    /// nothing from it: no messages, embeddings or turn stats, and no
+    /// webhook. Tool calls awaiting confirmation are still stored, since
+    /// they can't be confirmed otherwise. Defaults to `true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persist: Option<bool>,
+    /// `text` (default) or `json`. With `json` the reply is guaranteed to be
+    /// valid JSON, or the request fails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<String>,
+    /// Groq `reasoning_format` override (`parsed`, `hidden` or `raw`); only
+    /// used with reasoning models.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_format: Option<String>,
+    /// `auto` or `required`; with `required` the model must call at least one
+    /// tool before answering. Defaults to `LLM_TOOL_CHOICE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A stored snippet retrieved for RAG, with its similarity to the query.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RagHit {
+    pub text: String,
+    pub score: f64,
+    /// Role of the message the snippet came from.
+    pub role: String,
+    pub session_id: Option<String>,
+    pub created_at: Option<chrono::NaiveDateTime>,
+}
+
+/// A tool call the model requested that waits for `POST /api/confirm`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingAction {
+    pub token: String,
+    pub tool: String,
+    pub arguments: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatResponse {
     pub response: String,
     pub session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Vec<RagHit>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending_actions: Vec<PendingAction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<ChatTrace>,
+    /// Tool calls the model made, in order (including ones awaiting
+    /// confirmation); empty when `RETURN_TOOLS_USED` is off.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools_used: Vec<ToolInvocation>,
+    /// Where the turn's time went; returned with `include_trace`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<TurnTimings>,
+    /// Tokens the turn used, summed across tool rounds; null when the
+    /// provider didn't report usage.
+    #[serde(default)]
+    pub usage: Option<TokenUsage>,
+    /// Dollar cost of `usage`; returned with `include_trace` when the model
+    /// has a pricing entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+}
+
+/// A tool call the model made during a turn.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolInvocation {
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+/// How a reply was produced, for debugging tool selection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatTrace {
+    /// Tools offered to the model (empty when MCP was unavailable).
+    pub available_tools: Vec<String>,
+    /// Tools the model called, in order, including ones awaiting
+    /// confirmation and names it made up.
+    pub called_tools: Vec<String>,
+}
+
+/// Latency breakdown of a chat turn, in milliseconds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TurnTimings {
+    /// Embedding the message for RAG (0 when RAG is off).
+    pub embedding_ms: u64,
+    /// Vector search for similar past messages.
+    pub rag_ms: u64,
+    /// Model calls, summed across tool rounds and JSON retries.
+    pub llm_ms: u64,
+    /// Tool calls, summed across rounds (concurrent calls count once).
+    pub tool_ms: u64,
+    /// Storing the turn and its embeddings. Absent when the turn was not
+    /// stored or the reply was sent before storing it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persistence_ms: Option<u64>,
+    /// The whole turn, including the phases above.
+    pub total_ms: u64,
+}
+
+/// Outcome of one item of `POST /api/chat/batch`; items fail independently.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchChatResult {
+    Ok(ChatResponse),
+    Error {
+        session_id: String,
+        code: String,
+        message: String,
+    },
+}
+
+/// Body of `POST /api/prompts/:name`.
+#[derive(Debug, Default, Deserialize)]
+pub struct RenderPromptRequest {
+    #[serde(default)]
+    pub arguments: HashMap<String, String>,
+}
+
+/// Query of `GET /api/resources/read`.
+#[derive(Debug, Deserialize)]
+pub struct ReadResourceQuery {
+    pub uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfirmRequest {
+    pub session_id: String,
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfirmResponse {
+    pub session_id: String,
+    pub tool: String,
+    pub result: String,
+}
+
+/// Body of `GET /api/version`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: String,
+    pub git_commit: String,
+    /// RFC 3339 build time.
+    pub built_at: String,
+}
+
+/// Body of `GET /api/health`.
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    /// `ok`; `degraded` while the MCP server is unreachable but degraded mode
+    /// keeps chats working; otherwise `unavailable` (served with a 503).
+    pub status: String,
+    pub database: DependencyHealth,
+    pub mcp: DependencyHealth,
+}
+
+/// Outcome of probing one dependency.
+#[derive(Debug, Serialize)]
+pub struct DependencyHealth {
+    /// `ok` or `unreachable`.
+    pub status: String,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Body of `GET /api/status`.
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    /// `ok`, or `degraded` while the MCP server is unreachable or stored
+    /// embeddings need a reindex.
+    pub status: String,
+    pub version: String,
+    pub uptime_secs: u64,
+    pub database: DatabaseStatus,
+    pub mcp: McpStatus,
+    pub llm: LlmStatus,
+    pub embeddings: EmbeddingStatus,
+    pub requests: OutcomeSummary,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatabaseStatus {
+    pub pool_size: u32,
+    pub active: u32,
+    pub idle: u32,
+    pub max_connections: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct McpStatus {
+    pub healthy: bool,
+    /// Version agreed in `initialize`; `None` until it succeeds.
+    pub protocol_version: Option<String>,
+    pub degraded_mode: bool,
+    pub tools_cache_hits: u64,
+    pub tools_cache_misses: u64,
+    /// Hits over lookups, `None` before the first lookup.
+    pub tools_cache_hit_rate: Option<f64>,
+}
+
+/// Chat turns finished within the last `window_secs`.
+#[derive(Debug, Serialize)]
+pub struct OutcomeSummary {
+    pub window_secs: u64,
+    pub total: usize,
+    pub errors: usize,
+    /// `errors / total`, 0 when there were no requests.
+    pub error_rate: f64,
+    pub errors_by_code: BTreeMap<&'static str, usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LlmStatus {
+    pub provider: String,
+    pub model: String,
+    /// Dollar cost of all priced turns since startup.
+    pub total_cost_usd: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmbeddingStatus {
+    pub deferred: bool,
+    /// An embedding didn't fit the stored vector dimension.
+    pub reindex_needed: bool,
+}
+
+/// Body of `POST /api/session/import`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportRequest {
+    pub session_id: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Queue the imported messages in `RAG_EMBED_ROLES` for embedding so they
+    /// become searchable by RAG.
+    #[serde(default)]
+    pub embed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportResponse {
+    pub session_id: String,
+    pub imported: usize,
+    pub embedded: usize,
+}
+
+/// Body of `POST /api/sessions/merge`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MergeSessionsRequest {
+    /// Session that receives the merged history; it must already exist.
+    pub target: String,
+    /// Sessions folded into `target` and then deleted.
+    pub sources: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MergeSessionsResponse {
+    pub session_id: String,
+    pub merged: usize,
+    /// Messages in the merged history.
+    pub messages: usize,
 }
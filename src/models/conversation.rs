@@ -5,6 +5,8 @@ use serde::{Deserialize, Serialize};
 pub struct ConversationContext {
     pub session_id: String,
     pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub metadata: serde_json::Value,
 }
 
 impl ConversationContext {
@@ -12,10 +14,84 @@ impl ConversationContext {
         Self {
             session_id,
             messages: Vec::new(),
+            metadata: serde_json::json!({}),
+        }
+    }
+
+    /// Shallow-merges `update` into the stored metadata, overwriting keys.
+    pub fn merge_metadata(&mut self, update: &serde_json::Value) {
+        if !self.metadata.is_object() {
+            self.metadata = serde_json::json!({});
+        }
+        if let (Some(current), Some(update)) = (self.metadata.as_object_mut(), update.as_object()) {
+            for (key, value) in update {
+                current.insert(key.clone(), value.clone());
+            }
         }
     }
 
     pub fn add_message(&mut self, message: ChatMessage) {
         self.messages.push(message);
     }
+
+    /// Collapses runs of identical consecutive messages into one, repairing
+    /// histories duplicated by the old insert-per-turn storage. Returns the
+    /// number of messages removed.
+    pub fn dedup_messages(&mut self) -> usize {
+        let before = self.messages.len();
+        self.messages.dedup();
+        before - self.messages.len()
+    }
+}
+
+/// Aggregated per-session metrics, built from the recorded turn stats.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionStats {
+    pub session_id: String,
+    pub total_turns: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    pub avg_latency_ms: f64,
+    pub tool_calls: i64,
+    /// Summed over turns with a known price; `None` if none had one.
+    pub cost_usd: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    #[test]
+    fn dedup_collapses_consecutive_duplicates_only() {
+        let mut context = ConversationContext::new("s".to_string());
+        context.messages = vec![
+            message("user", "Hi"),
+            message("user", "Hi"),
+            message("assistant", "Hello!"),
+            message("assistant", "Hello!"),
+            message("assistant", "Hello!"),
+            message("user", "Hi"),
+        ];
+
+        assert_eq!(context.dedup_messages(), 3);
+        assert_eq!(
+            context.messages,
+            vec![
+                message("user", "Hi"),
+                message("assistant", "Hello!"),
+                message("user", "Hi"),
+            ]
+        );
+        assert_eq!(context.dedup_messages(), 0);
+    }
 }
@@ -0,0 +1,155 @@
+use dashmap::DashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A bucket idle this long has refilled completely, so dropping it loses
+/// nothing; idle buckets are swept this often.
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(60);
+
+/// Token bucket per client: up to `per_minute` requests in a burst, refilled
+/// at `per_minute` a minute. State is in memory, so each instance limits on
+/// its own.
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: DashMap<String, Bucket>,
+    last_sweep: Mutex<Instant>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute: per_minute.max(1),
+            buckets: DashMap::new(),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Takes `cost` tokens for `client`, or returns how long until they are
+    /// free. A cost above the burst size takes a full bucket.
+    pub fn check(&self, client: &str, cost: u32) -> Result<(), Duration> {
+        self.check_at(client, cost, Instant::now())
+    }
+
+    fn check_at(&self, client: &str, cost: u32, now: Instant) -> Result<(), Duration> {
+        self.sweep_idle(now);
+
+        let capacity = f64::from(self.per_minute);
+        let cost = f64::from(cost.max(1)).min(capacity);
+        let per_second = capacity / 60.0;
+        let mut bucket = self.buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((cost - bucket.tokens) / per_second))
+        }
+    }
+
+    /// Drops buckets idle for `IDLE_BUCKET_TTL`, at most once per TTL, so the
+    /// map holds roughly the clients seen in the last two minutes and the
+    /// scan isn't paid on every request.
+    fn sweep_idle(&self, now: Instant) {
+        let Ok(mut last_sweep) = self.last_sweep.try_lock() else {
+            return;
+        };
+        if now.saturating_duration_since(*last_sweep) < IDLE_BUCKET_TTL {
+            return;
+        }
+        *last_sweep = now;
+        drop(last_sweep);
+        self.buckets
+            .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < IDLE_BUCKET_TTL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_is_capped_and_retry_after_covers_one_token() {
+        let limiter = RateLimiter::new(6);
+        let start = Instant::now();
+        for _ in 0..6 {
+            assert!(limiter.check_at("a", 1, start).is_ok());
+        }
+
+        // 6 a minute refills one token every 10s
+        let wait = limiter.check_at("a", 1, start).unwrap_err();
+        assert_eq!(wait.as_secs_f64().round(), 10.0);
+        let wait = limiter
+            .check_at("a", 1, start + Duration::from_secs(4))
+            .unwrap_err();
+        assert_eq!(wait.as_secs_f64().round(), 6.0);
+
+        // Other clients have their own bucket
+        assert!(limiter.check_at("b", 1, start).is_ok());
+    }
+
+    #[test]
+    fn tokens_refill_over_time_up_to_capacity() {
+        let limiter = RateLimiter::new(60);
+        let start = Instant::now();
+        for _ in 0..60 {
+            assert!(limiter.check_at("a", 1, start).is_ok());
+        }
+        assert!(limiter.check_at("a", 1, start).is_err());
+
+        // One token a second
+        let later = start + Duration::from_secs(3);
+        for _ in 0..3 {
+            assert!(limiter.check_at("a", 1, later).is_ok());
+        }
+        assert!(limiter.check_at("a", 1, later).is_err());
+
+        // A long pause refills to capacity, not beyond
+        let much_later = later + Duration::from_secs(600);
+        for _ in 0..60 {
+            assert!(limiter.check_at("a", 1, much_later).is_ok());
+        }
+        assert!(limiter.check_at("a", 1, much_later).is_err());
+    }
+
+    #[test]
+    fn idle_buckets_are_swept() {
+        let limiter = RateLimiter::new(10);
+        let start = Instant::now();
+        limiter.check_at("a", 1, start).unwrap();
+        limiter
+            .check_at("b", 1, start + Duration::from_secs(50))
+            .unwrap();
+
+        limiter
+            .check_at("c", 1, start + Duration::from_secs(70))
+            .unwrap();
+        assert!(!limiter.buckets.contains_key("a"));
+        assert!(limiter.buckets.contains_key("b"));
+        assert!(limiter.buckets.contains_key("c"));
+    }
+
+    #[test]
+    fn batch_costs_one_token_per_item() {
+        let limiter = RateLimiter::new(10);
+        let start = Instant::now();
+        assert!(limiter.check_at("a", 4, start).is_ok());
+        assert!(limiter.check_at("a", 6, start).is_ok());
+        assert!(limiter.check_at("a", 1, start).is_err());
+
+        // Larger than the burst: takes a full bucket, then waits for one
+        assert!(limiter.check_at("b", 50, start).is_ok());
+        let wait = limiter.check_at("b", 50, start).unwrap_err();
+        assert_eq!(wait.as_secs_f64().round(), 60.0);
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::Result;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+tokio::task_local! {
+    static RETRY_BUDGET: RetryBudget;
+}
+
+/// Total time one request may spend retrying (failed attempts plus backoff),
+/// shared by every subsystem it calls. Only retries running inside
+/// `RetryBudget::scope` are charged; elsewhere they are unbounded.
+#[derive(Debug)]
+pub struct RetryBudget {
+    remaining_ms: AtomicU64,
+}
+
+impl RetryBudget {
+    pub fn new(budget: Duration) -> Self {
+        Self {
+            remaining_ms: AtomicU64::new(budget.as_millis() as u64),
+        }
+    }
+
+    /// Runs `future` with this budget applying to all retries inside it.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        RETRY_BUDGET.scope(self, future).await
+    }
+
+    /// Charges a retry to the current request's budget. Returns `false` once
+    /// the budget can't cover it; from then on every retry is refused, so the
+    /// rest of the request fails fast.
+    fn try_spend(cost: Duration) -> bool {
+        RETRY_BUDGET
+            .try_with(|budget| {
+                let cost = cost.as_millis() as u64;
+                let spent = budget
+                    .remaining_ms
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+                        remaining.checked_sub(cost)
+                    })
+                    .is_ok();
+                if !spent {
+                    budget.remaining_ms.store(0, Ordering::Relaxed);
+                }
+                spent
+            })
+            .unwrap_or(true)
+    }
+}
+
+/// Retry settings for one subsystem (LLM, embeddings, MCP, database).
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            base_delay: Duration::from_millis(500),
+        }
+    }
+
+    /// Delay before the first retry; later ones double it, up to 30s.
+    pub fn with_base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// Exponential backoff for the given (zero-based) retry attempt, with
+    /// jitter (between half and all of the exponential delay) so clients
+    /// that failed together don't retry in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_BACKOFF);
+        delay.mul_f64(0.5 + fastrand::f64() * 0.5)
+    }
+
+    /// Sends an HTTP request, retrying transport failures and transient
+    /// statuses (429, 500, 502, 503, 504). A `Retry-After` header overrides
+    /// the backoff; one asking for more than the maximum backoff isn't
+    /// retried. The final response is returned as-is, so callers still
+    /// handle non-success statuses themselves.
+    pub async fn send<F>(&self, label: &str, build: F) -> Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let delay = match build().send().await {
+                Ok(response)
+                    if attempt < self.max_retries && is_retryable_status(response.status()) =>
+                {
+                    let delay = match retry_after(&response) {
+                        Some(wait) if wait > MAX_BACKOFF => {
+                            warn!(
+                                "{} returned {} with Retry-After of {}s, not retrying",
+                                label,
+                                response.status(),
+                                wait.as_secs()
+                            );
+                            return Ok(response);
+                        }
+                        Some(wait) => wait,
+                        None => self.backoff(attempt),
+                    };
+                    if !RetryBudget::try_spend(started.elapsed() + delay) {
+                        warn!(
+                            "{} returned {}, retry budget exhausted",
+                            label,
+                            response.status()
+                        );
+                        return Ok(response);
+                    }
+                    warn!(
+                        "{} returned {}, retrying in {}ms ({}/{})",
+                        label,
+                        response.status(),
+                        delay.as_millis(),
+                        attempt + 1,
+                        self.max_retries
+                    );
+                    delay
+                }
+                Ok(response) => return Ok(response),
+                Err(e) if attempt < self.max_retries && (e.is_connect() || e.is_timeout()) => {
+                    let delay = self.backoff(attempt);
+                    if !RetryBudget::try_spend(started.elapsed() + delay) {
+                        warn!("{} request failed: {}, retry budget exhausted", label, e);
+                        return Err(e.into());
+                    }
+                    warn!(
+                        "{} request failed: {}, retrying ({}/{})",
+                        label,
+                        e,
+                        attempt + 1,
+                        self.max_retries
+                    );
+                    delay
+                }
+                Err(e) => return Err(e.into()),
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Runs an arbitrary fallible operation, retrying every error.
+    pub async fn run<T, F, Fut>(&self, label: &str, mut op: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let delay = self.backoff(attempt);
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.max_retries => {
+                    if !RetryBudget::try_spend(started.elapsed() + delay) {
+                        warn!("{} failed: {}, retry budget exhausted", label, e);
+                        return Err(e);
+                    }
+                    warn!(
+                        "{} failed: {}, retrying ({}/{})",
+                        label,
+                        e,
+                        attempt + 1,
+                        self.max_retries
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// The wait requested by a `Retry-After` header, given either in seconds or
+/// as an HTTP date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (at.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+        .or(Some(Duration::ZERO))
+}
@@ -0,0 +1,45 @@
+use anyhow::{Context, Result};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client;
+
+/// Uploads expired conversations to an S3-compatible bucket before they are
+/// deleted. Credentials and region come from the standard AWS environment
+/// (`AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, `AWS_REGION`, ...).
+pub struct ConversationArchive {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl ConversationArchive {
+    /// `endpoint` points the client at a non-AWS store such as MinIO or R2,
+    /// which also switches to path-style addressing.
+    pub async fn new(bucket: String, prefix: String, endpoint: Option<String>) -> Self {
+        let config = aws_config::load_from_env().await;
+        let mut builder = aws_sdk_s3::config::Builder::from(&config);
+        if let Some(endpoint) = endpoint {
+            builder = builder.endpoint_url(endpoint).force_path_style(true);
+        }
+        Self {
+            client: Client::from_conf(builder.build()),
+            bucket,
+            prefix,
+        }
+    }
+
+    /// Stores `conversation` as `<prefix><session_id>.json`, replacing any
+    /// earlier archive of the same session.
+    pub async fn upload(&self, session_id: &str, conversation: &serde_json::Value) -> Result<()> {
+        let key = format!("{}{}.json", self.prefix, session_id);
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .content_type("application/json")
+            .body(ByteStream::from(serde_json::to_vec(conversation)?))
+            .send()
+            .await
+            .with_context(|| format!("Failed to archive s3://{}/{}", self.bucket, key))?;
+        Ok(())
+    }
+}
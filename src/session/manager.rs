@@ -1,43 +1,165 @@
-use crate::models::{ChatMessage, ConversationContext};
+use crate::error::AgentError;
+use crate::models::{
+    ChatMessage, ConversationContext, DatabaseStatus, FunctionCall, PendingAction, SessionStats,
+    TokenUsage,
+};
+use crate::session::ConversationArchive;
 use anyhow::Result;
 use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, warn};
 use uuid::Uuid;
 
 pub struct SessionManager {
     pool: PgPool,
+    /// Pool for read-only queries; the primary unless a replica is set.
+    read_pool: PgPool,
+    dedup_on_load: bool,
+    /// Where expired conversations are uploaded before deletion.
+    archive: Option<Arc<ConversationArchive>>,
+    /// `(default_minutes, retention_rules)` applied when a session is loaded.
+    expiry: Option<(u64, HashMap<String, u64>)>,
 }
 
 impl SessionManager {
     pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+        Self {
+            read_pool: pool.clone(),
+            pool,
+            dedup_on_load: false,
+            archive: None,
+            expiry: None,
+        }
+    }
+
+    /// Start over instead of resuming a session idle for longer than its
+    /// retention period (see `delete_expired_sessions`): the stale
+    /// conversation is removed (archived first, if configured) when it is
+    /// next loaded, rather than waiting for the sweep.
+    pub fn with_expiry(
+        mut self,
+        default_minutes: u64,
+        retention_rules: HashMap<String, u64>,
+    ) -> Self {
+        self.expiry = Some((default_minutes, retention_rules));
+        self
+    }
+
+    /// Serve read-only queries (loading a session for a chat turn, history,
+    /// stats) from a read replica. Read-modify-write paths keep using the
+    /// primary, so replica lag never causes stored messages to be lost.
+    pub fn with_read_pool(mut self, pool: PgPool) -> Self {
+        self.read_pool = pool;
+        self
+    }
+
+    /// Archive expired conversations before the expiry sweep deletes them.
+    pub fn with_archive(mut self, archive: Arc<ConversationArchive>) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// Collapse identical consecutive messages when loading a session; the
+    /// cleaned history is written back on the session's next save.
+    pub fn with_history_dedup(mut self, enabled: bool) -> Self {
+        self.dedup_on_load = enabled;
+        self
+    }
+
+    /// Runs `SELECT 1` on the primary, for health checks.
+    pub async fn ping_database(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
     }
 
+    /// Connection counts of the database pool.
+    pub fn pool_status(&self) -> DatabaseStatus {
+        let size = self.pool.size();
+        let idle = self.pool.num_idle() as u32;
+        DatabaseStatus {
+            pool_size: size,
+            active: size.saturating_sub(idle),
+            idle,
+            max_connections: self.pool.options().get_max_connections(),
+        }
+    }
+
+    /// Loads a session for a chat turn (from the read pool), or starts an
+    /// empty one.
     pub async fn get_or_create_session(&self, session_id: &str) -> Result<ConversationContext> {
-        let session_uuid = Uuid::parse_str(session_id).unwrap_or_else(|_| Uuid::new_v4());
+        self.get_or_create_from(session_id, &self.read_pool).await
+    }
 
-        let row = sqlx::query_as::<_, (String, serde_json::Value)>(
+    async fn get_or_create_from(
+        &self,
+        session_id: &str,
+        pool: &PgPool,
+    ) -> Result<ConversationContext> {
+        let session_uuid = parse_session_id(session_id)?;
+        if let Some((default_minutes, retention_rules)) = &self.expiry {
+            // If it can't be removed (e.g. archiving failed) the stale session
+            // is resumed, rather than losing its history or failing the chat
+            match self
+                .delete_expired(*default_minutes, retention_rules, Some(session_uuid))
+                .await
+            {
+                Ok(0) => {}
+                Ok(_) => {
+                    info!(
+                        "Session {} expired, starting a new conversation",
+                        session_id
+                    );
+                    // Don't read it back from a replica that hasn't caught up
+                    return Ok(ConversationContext::new(session_id.to_string()));
+                }
+                Err(e) => warn!("Failed to expire session {}: {}", session_id, e),
+            }
+        }
+        Ok(self
+            .load_session(session_uuid, pool)
+            .await?
+            .unwrap_or_else(|| ConversationContext::new(session_id.to_string())))
+    }
+
+    /// Loads a stored session; `None` if it doesn't exist.
+    pub async fn get_session(&self, session_id: &str) -> Result<Option<ConversationContext>> {
+        self.load_session(parse_session_id(session_id)?, &self.read_pool)
+            .await
+    }
+
+    async fn load_session(
+        &self,
+        session_uuid: Uuid,
+        pool: &PgPool,
+    ) -> Result<Option<ConversationContext>> {
+        let row = sqlx::query_as::<_, (String, serde_json::Value, serde_json::Value)>(
             r#"
             SELECT 
                 session_id::text as session_id,
-                messages::jsonb as messages
+                messages::jsonb as messages,
+                metadata
             FROM conversations
             WHERE session_id = $1
-            ORDER BY created_at DESC
-            LIMIT 1
             "#,
         )
         .bind(session_uuid)
-        .fetch_optional(&self.pool)
+        .fetch_optional(pool)
         .await?;
 
-        if let Some((session_id_text, messages_json)) = row {
+        if let Some((session_id_text, messages_json, metadata)) = row {
             let messages: Vec<ChatMessage> = serde_json::from_value(messages_json)?;
-            Ok(ConversationContext {
+            let mut context = ConversationContext {
                 session_id: session_id_text,
                 messages,
-            })
+                metadata,
+            };
+            if self.dedup_on_load {
+                context.dedup_messages();
+            }
+            Ok(Some(context))
         } else {
-            Ok(ConversationContext::new(session_id.to_string()))
+            Ok(None)
         }
     }
 
@@ -45,37 +167,788 @@ impl SessionManager {
         &self,
         session_id: &str,
         user_message: &str,
-        assistant_message: &str,
-    ) -> Result<()> {
-        let session_uuid = Uuid::parse_str(session_id).unwrap_or_else(|_| Uuid::new_v4());
-
-        let mut context = self.get_or_create_session(session_id).await?;
+        assistant_message: Option<&str>,
+        metadata: Option<&serde_json::Value>,
+    ) -> Result<ConversationContext> {
+        let mut context = self.get_or_create_from(session_id, &self.pool).await?;
 
         context.add_message(ChatMessage {
             role: "user".to_string(),
             content: user_message.to_string(),
             tool_calls: None,
+            tool_call_id: None,
         });
 
-        context.add_message(ChatMessage {
-            role: "assistant".to_string(),
-            content: assistant_message.to_string(),
-            tool_calls: None,
-        });
+        if let Some(assistant_message) = assistant_message {
+            context.add_message(ChatMessage {
+                role: "assistant".to_string(),
+                content: assistant_message.to_string(),
+                tool_calls: None,
+                tool_call_id: None,
+            });
+        }
+
+        if let Some(metadata) = metadata {
+            context.merge_metadata(metadata);
+        }
+
+        self.save_context(session_id, &context).await?;
+        Ok(context)
+    }
+
+    /// Overwrites a session's stored history, keeping its metadata.
+    pub async fn replace_messages(
+        &self,
+        session_id: &str,
+        messages: Vec<ChatMessage>,
+    ) -> Result<ConversationContext> {
+        let mut context = self.get_or_create_from(session_id, &self.pool).await?;
+        context.messages = messages;
+        self.save_context(session_id, &context).await?;
+        Ok(context)
+    }
+
+    /// Creates a session from imported messages, replacing any history (and,
+    /// through the cascade, embeddings) already stored under the same id.
+    /// `embed_roles` messages are queued, in the same transaction, for the
+    /// background indexer to embed. Returns the context and how many
+    /// messages were queued.
+    pub async fn import_session(
+        &self,
+        session_id: &str,
+        messages: Vec<ChatMessage>,
+        metadata: Option<&serde_json::Value>,
+        embed_roles: &[&str],
+    ) -> Result<(ConversationContext, usize)> {
+        let session_uuid = parse_session_id(session_id)?;
+        let mut context = ConversationContext::new(session_id.to_string());
+        context.messages = messages;
+        if let Some(metadata) = metadata {
+            context.merge_metadata(metadata);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM conversations WHERE session_id = $1")
+            .bind(session_uuid)
+            .execute(&mut *tx)
+            .await?;
+        let conversation_id = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            INSERT INTO conversations (session_id, messages, metadata, updated_at)
+            VALUES ($1, $2, $3, NOW())
+            RETURNING id
+            "#,
+        )
+        .bind(session_uuid)
+        .bind(serde_json::to_value(&context.messages)?)
+        .bind(&context.metadata)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let (texts, roles): (Vec<&str>, Vec<&str>) = context
+            .messages
+            .iter()
+            .filter(|m| embed_roles.contains(&m.role.as_str()) && !m.content.trim().is_empty())
+            .map(|m| (m.content.as_str(), m.role.as_str()))
+            .unzip();
+        if !texts.is_empty() {
+            // A NULL embedding queues the message for the indexer
+            sqlx::query(
+                r#"
+                INSERT INTO conversation_embeddings (conversation_id, message_text, role)
+                SELECT $1, doc.text, doc.role
+                FROM UNNEST($2::text[], $3::text[]) AS doc(text, role)
+                "#,
+            )
+            .bind(conversation_id)
+            .bind(&texts)
+            .bind(&roles)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        let queued = texts.len();
+        Ok((context, queued))
+    }
 
+    /// Folds the `sources` sessions into `target`: histories are
+    /// concatenated oldest session first (by creation time, each keeping its
+    /// own message order), metadata is merged with the target's keys winning,
+    /// and embeddings, turn stats and pending actions are re-pointed before
+    /// the source rows are deleted. Fails with `NotFound` if any session
+    /// doesn't exist; nothing changes unless every step succeeds.
+    pub async fn merge_sessions(
+        &self,
+        target: &str,
+        sources: &[String],
+    ) -> Result<ConversationContext> {
+        let target_uuid = Uuid::parse_str(target)?;
+        let source_uuids = sources
+            .iter()
+            .map(|id| Uuid::parse_str(id))
+            .collect::<Result<Vec<_>, _>>()?;
+        let all: Vec<Uuid> = std::iter::once(target_uuid)
+            .chain(source_uuids.iter().copied())
+            .collect();
+
+        let mut tx = self.pool.begin().await?;
+        let rows = sqlx::query_as::<_, (Uuid, Uuid, serde_json::Value, serde_json::Value)>(
+            r#"
+            SELECT id, session_id, messages::jsonb, metadata
+            FROM conversations
+            WHERE session_id = ANY($1)
+            ORDER BY created_at, id
+            FOR UPDATE
+            "#,
+        )
+        .bind(&all)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        if let Some(missing) = all
+            .iter()
+            .find(|uuid| !rows.iter().any(|(_, session, _, _)| session == *uuid))
+        {
+            return Err(AgentError::NotFound(format!("No session {}", missing)).into());
+        }
+
+        let mut context = ConversationContext::new(target.to_string());
+        let mut target_metadata = serde_json::json!({});
+        let mut source_ids = Vec::new();
+        for (id, session_uuid, messages_json, metadata) in rows {
+            let messages: Vec<ChatMessage> = serde_json::from_value(messages_json)?;
+            context.messages.extend(messages);
+            if session_uuid == target_uuid {
+                target_metadata = metadata;
+            } else {
+                context.merge_metadata(&metadata);
+                source_ids.push(id);
+            }
+        }
+        context.merge_metadata(&target_metadata);
+
+        sqlx::query(
+            r#"
+            UPDATE conversation_embeddings
+            SET conversation_id = (SELECT id FROM conversations WHERE session_id = $1)
+            WHERE conversation_id = ANY($2)
+            "#,
+        )
+        .bind(target_uuid)
+        .bind(&source_ids)
+        .execute(&mut *tx)
+        .await?;
+        for table in ["conversation_turn_stats", "pending_actions"] {
+            sqlx::query(&format!(
+                "UPDATE {} SET session_id = $1 WHERE session_id = ANY($2)",
+                table
+            ))
+            .bind(target_uuid)
+            .bind(&source_uuids)
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query("DELETE FROM conversations WHERE id = ANY($1)")
+            .bind(&source_ids)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            UPDATE conversations
+            SET messages = $2, metadata = $3, updated_at = NOW()
+            WHERE session_id = $1
+            "#,
+        )
+        .bind(target_uuid)
+        .bind(serde_json::to_value(&context.messages)?)
+        .bind(&context.metadata)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(context)
+    }
+
+    /// Appends a single message to a session's stored history.
+    pub async fn append_message(&self, session_id: &str, message: ChatMessage) -> Result<()> {
+        let mut context = self.get_or_create_from(session_id, &self.pool).await?;
+        context.add_message(message);
+        self.save_context(session_id, &context).await
+    }
+
+    /// One-time cleanup for data written before conversations were upserted:
+    /// collapses identical consecutive messages in every stored history,
+    /// leaving `updated_at` (and so retention) untouched. Returns the number
+    /// of conversations rewritten.
+    pub async fn dedup_stored_histories(&self) -> Result<u64> {
+        let rows = sqlx::query_as::<_, (Uuid, serde_json::Value)>(
+            "SELECT session_id, messages::jsonb FROM conversations",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut rewritten = 0;
+        for (session_uuid, messages_json) in rows {
+            let mut context = ConversationContext::new(session_uuid.to_string());
+            context.messages = serde_json::from_value(messages_json)?;
+            if context.dedup_messages() == 0 {
+                continue;
+            }
+            sqlx::query("UPDATE conversations SET messages = $2 WHERE session_id = $1")
+                .bind(session_uuid)
+                .bind(serde_json::to_value(&context.messages)?)
+                .execute(&self.pool)
+                .await?;
+            rewritten += 1;
+        }
+        Ok(rewritten)
+    }
+
+    async fn save_context(&self, session_id: &str, context: &ConversationContext) -> Result<()> {
+        let session_uuid = parse_session_id(session_id)?;
         let messages_json = serde_json::to_value(&context.messages)?;
 
         sqlx::query(
             r#"
-            INSERT INTO conversations (session_id, messages, updated_at)
-            VALUES ($1, $2, NOW())
+            INSERT INTO conversations (session_id, messages, metadata, updated_at)
+            VALUES ($1, $2, $3, NOW())
+            ON CONFLICT (session_id) DO UPDATE
+            SET messages = EXCLUDED.messages,
+                metadata = EXCLUDED.metadata,
+                updated_at = NOW()
             "#,
         )
         .bind(session_uuid)
         .bind(messages_json)
+        .bind(&context.metadata)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn record_turn_stats(
+        &self,
+        session_id: &str,
+        latency_ms: u64,
+        usage: &TokenUsage,
+        tool_calls: u32,
+        cost_usd: Option<f64>,
+    ) -> Result<()> {
+        let session_uuid = parse_session_id(session_id)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO conversation_turn_stats
+                (session_id, latency_ms, prompt_tokens, completion_tokens, total_tokens, tool_calls,
+                 cost_usd)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(session_uuid)
+        .bind(latency_ms as i64)
+        .bind(usage.prompt_tokens as i64)
+        .bind(usage.completion_tokens as i64)
+        .bind(usage.total_tokens as i64)
+        .bind(tool_calls as i32)
+        .bind(cost_usd)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Aggregates the recorded turn stats for a session. Returns `None` when
+    /// no turns have been recorded.
+    pub async fn get_session_stats(&self, session_id: &str) -> Result<Option<SessionStats>> {
+        let session_uuid = parse_session_id(session_id)?;
+
+        let (
+            total_turns,
+            prompt_tokens,
+            completion_tokens,
+            total_tokens,
+            avg_latency_ms,
+            tool_calls,
+            cost_usd,
+        ) = sqlx::query_as::<_, (i64, i64, i64, i64, f64, i64, Option<f64>)>(
+            r#"
+                SELECT
+                    COUNT(*),
+                    COALESCE(SUM(prompt_tokens), 0)::bigint,
+                    COALESCE(SUM(completion_tokens), 0)::bigint,
+                    COALESCE(SUM(total_tokens), 0)::bigint,
+                    COALESCE(AVG(latency_ms), 0)::float8,
+                    COALESCE(SUM(tool_calls), 0)::bigint,
+                    SUM(cost_usd)
+                FROM conversation_turn_stats
+                WHERE session_id = $1
+                "#,
+        )
+        .bind(session_uuid)
+        .fetch_one(&self.read_pool)
+        .await?;
+
+        if total_turns == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(SessionStats {
+            session_id: session_id.to_string(),
+            total_turns,
+            prompt_tokens,
+            completion_tokens,
+            total_tokens,
+            avg_latency_ms,
+            tool_calls,
+            cost_usd,
+        }))
+    }
+
+    /// Deletes a session with everything stored for it: its embeddings, turn
+    /// stats and pending actions. Returns `false` if the session didn't
+    /// exist.
+    pub async fn delete_session(&self, session_id: &str) -> Result<bool> {
+        let session_uuid = parse_session_id(session_id)?;
+
+        let deleted = sqlx::query_scalar::<_, i64>(
+            r#"
+            WITH doomed AS (
+                SELECT id FROM conversations WHERE session_id = $1
+            ),
+            deleted_embeddings AS (
+                DELETE FROM conversation_embeddings
+                WHERE conversation_id IN (SELECT id FROM doomed)
+            ),
+            deleted_stats AS (
+                DELETE FROM conversation_turn_stats WHERE session_id = $1
+            ),
+            deleted_actions AS (
+                DELETE FROM pending_actions WHERE session_id = $1
+            ),
+            deleted AS (
+                DELETE FROM conversations
+                WHERE id IN (SELECT id FROM doomed)
+                RETURNING id
+            )
+            SELECT COUNT(*) FROM deleted
+            "#,
+        )
+        .bind(session_uuid)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Deletes sessions idle for longer than their retention period, along
+    /// with their turn stats (embeddings cascade). A session's retention is the
+    /// longest matching rule for its metadata tags, or `default_minutes` when
+    /// no tag has a rule. Returns the number of conversation rows removed.
+    ///
+    /// With an archive configured, each expired session is uploaded first and
+    /// only deleted once the upload succeeded; a failed upload stops the
+    /// sweep, leaving that session and the rest for the next one.
+    pub async fn delete_expired_sessions(
+        &self,
+        default_minutes: u64,
+        retention_rules: &HashMap<String, u64>,
+    ) -> Result<u64> {
+        self.delete_expired(default_minutes, retention_rules, None)
+            .await
+    }
+
+    /// `delete_expired_sessions`, optionally limited to one session.
+    async fn delete_expired(
+        &self,
+        default_minutes: u64,
+        retention_rules: &HashMap<String, u64>,
+        only: Option<Uuid>,
+    ) -> Result<u64> {
+        let (tags, minutes): (Vec<String>, Vec<f64>) = retention_rules
+            .iter()
+            .map(|(tag, minutes)| (tag.clone(), *minutes as f64))
+            .unzip();
+
+        if let Some(archive) = &self.archive {
+            return self
+                .archive_expired_sessions(archive, tags, minutes, default_minutes, only)
+                .await;
+        }
+
+        let result = sqlx::query(
+            r#"
+            WITH rules AS (
+                SELECT * FROM UNNEST($1::text[], $2::float8[]) AS r(tag, minutes)
+            ),
+            expired AS (
+                SELECT l.session_id
+                FROM conversations l
+                WHERE l.updated_at < NOW() - INTERVAL '1 minute' * COALESCE(
+                    (SELECT MAX(r.minutes) FROM rules r WHERE l.metadata -> 'tags' ? r.tag),
+                    $3
+                )
+                  AND ($4::uuid IS NULL OR l.session_id = $4)
+            ),
+            deleted_stats AS (
+                DELETE FROM conversation_turn_stats
+                WHERE session_id IN (SELECT session_id FROM expired)
+            )
+            DELETE FROM conversations
+            WHERE session_id IN (SELECT session_id FROM expired)
+            "#,
+        )
+        .bind(tags)
+        .bind(minutes)
+        .bind(default_minutes as f64)
+        .bind(only)
         .execute(&self.pool)
         .await?;
 
+        Ok(result.rows_affected())
+    }
+
+    async fn archive_expired_sessions(
+        &self,
+        archive: &ConversationArchive,
+        tags: Vec<String>,
+        minutes: Vec<f64>,
+        default_minutes: u64,
+        only: Option<Uuid>,
+    ) -> Result<u64> {
+        let expired = sqlx::query_as::<
+            _,
+            (
+                String,
+                serde_json::Value,
+                serde_json::Value,
+                Option<chrono::NaiveDateTime>,
+                Option<chrono::NaiveDateTime>,
+            ),
+        >(
+            r#"
+            WITH rules AS (
+                SELECT * FROM UNNEST($1::text[], $2::float8[]) AS r(tag, minutes)
+            )
+            SELECT l.session_id::text, l.messages, l.metadata, l.created_at, l.updated_at
+            FROM conversations l
+            WHERE l.updated_at < NOW() - INTERVAL '1 minute' * COALESCE(
+                (SELECT MAX(r.minutes) FROM rules r WHERE l.metadata -> 'tags' ? r.tag),
+                $3
+            )
+              AND ($4::uuid IS NULL OR l.session_id = $4)
+            "#,
+        )
+        .bind(tags)
+        .bind(minutes)
+        .bind(default_minutes as f64)
+        .bind(only)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut deleted = 0;
+        for (session_id, messages, metadata, created_at, updated_at) in expired {
+            archive
+                .upload(
+                    &session_id,
+                    &serde_json::json!({
+                        "session_id": session_id,
+                        "messages": messages,
+                        "metadata": metadata,
+                        "created_at": created_at,
+                        "updated_at": updated_at,
+                        "archived_at": chrono::Utc::now(),
+                    }),
+                )
+                .await?;
+
+            // Skip the delete if the session was written to after the upload
+            // was read, so no message is lost without being archived.
+            let removed = sqlx::query_scalar::<_, i64>(
+                r#"
+                WITH deleted AS (
+                    DELETE FROM conversations
+                    WHERE session_id::text = $1 AND updated_at IS NOT DISTINCT FROM $2
+                    RETURNING session_id
+                ),
+                deleted_stats AS (
+                    DELETE FROM conversation_turn_stats
+                    WHERE session_id IN (SELECT session_id FROM deleted)
+                )
+                SELECT COUNT(*) FROM deleted
+                "#,
+            )
+            .bind(&session_id)
+            .bind(updated_at)
+            .fetch_one(&self.pool)
+            .await?;
+            deleted += removed as u64;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Persists a tool call awaiting user confirmation and returns it with its
+    /// confirmation token.
+    pub async fn create_pending_action(
+        &self,
+        session_id: &str,
+        call: &FunctionCall,
+    ) -> Result<PendingAction> {
+        let session_uuid = parse_session_id(session_id)?;
+
+        let token = sqlx::query_scalar::<_, String>(
+            r#"
+            INSERT INTO pending_actions (session_id, tool_name, arguments)
+            VALUES ($1, $2, $3)
+            RETURNING token::text
+            "#,
+        )
+        .bind(session_uuid)
+        .bind(&call.name)
+        .bind(&call.arguments)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(PendingAction {
+            token,
+            tool: call.name.clone(),
+            arguments: call.arguments.clone(),
+        })
+    }
+
+    /// Atomically claims a pending action so it can only be executed once.
+    /// Returns `None` if the token is unknown, belongs to another session, was
+    /// already used, or is older than `ttl_minutes`.
+    pub async fn claim_pending_action(
+        &self,
+        session_id: &str,
+        token: &str,
+        ttl_minutes: u64,
+    ) -> Result<Option<FunctionCall>> {
+        let session_uuid = parse_session_id(session_id)?;
+        let token_uuid = Uuid::parse_str(token)?;
+
+        let row = sqlx::query_as::<_, (String, serde_json::Value)>(
+            r#"
+            UPDATE pending_actions
+            SET status = 'executing'
+            WHERE token = $1
+              AND session_id = $2
+              AND status = 'pending'
+              AND created_at > NOW() - INTERVAL '1 minute' * $3
+            RETURNING tool_name, arguments
+            "#,
+        )
+        .bind(token_uuid)
+        .bind(session_uuid)
+        .bind(ttl_minutes as f64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|(name, arguments)| FunctionCall { name, arguments }))
+    }
+
+    /// Records the outcome of a claimed action.
+    pub async fn resolve_pending_action(
+        &self,
+        token: &str,
+        status: &str,
+        result: &str,
+    ) -> Result<()> {
+        let token_uuid = Uuid::parse_str(token)?;
+
+        sqlx::query(
+            r#"
+            UPDATE pending_actions
+            SET status = $2, result = $3, resolved_at = NOW()
+            WHERE token = $1
+            "#,
+        )
+        .bind(token_uuid)
+        .bind(status)
+        .bind(result)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Deletes pending actions older than the TTL, whatever their status.
+    pub async fn delete_expired_pending_actions(&self, ttl_minutes: u64) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM pending_actions
+            WHERE created_at < NOW() - INTERVAL '1 minute' * $1
+            "#,
+        )
+        .bind(ttl_minutes as f64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+/// Session ids are UUIDs; anything else is the client's mistake (a 400), not
+/// a reason to start a new session under a random id.
+fn parse_session_id(session_id: &str) -> Result<Uuid> {
+    Uuid::parse_str(session_id).map_err(|_| {
+        AgentError::BadRequest(format!("session_id '{}' is not a valid UUID", session_id)).into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn seed(manager: &SessionManager, session_id: Uuid) -> Result<()> {
+        manager
+            .add_message(&session_id.to_string(), "Hi", Some("Hello!"), None)
+            .await?;
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres with pgvector at DATABASE_URL"]
+    async fn turns_update_the_session_row_in_place(pool: PgPool) -> Result<()> {
+        let manager = SessionManager::new(pool.clone());
+        let session_id = Uuid::new_v4();
+        for n in 1..=3 {
+            manager
+                .add_message(
+                    &session_id.to_string(),
+                    &format!("Message {}", n),
+                    Some("Reply"),
+                    None,
+                )
+                .await?;
+        }
+
+        let rows: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM conversations WHERE session_id = $1")
+                .bind(session_id)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(rows, 1);
+        let context = manager
+            .get_or_create_session(&session_id.to_string())
+            .await?;
+        assert_eq!(context.messages.len(), 6);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres with pgvector at DATABASE_URL"]
+    async fn duplicated_history_reloads_clean(pool: PgPool) -> Result<()> {
+        let session_id = Uuid::new_v4();
+        seed(&SessionManager::new(pool.clone()), session_id).await?;
+        // What the old insert-per-turn storage could leave behind
+        sqlx::query(
+            r#"
+            UPDATE conversations
+            SET messages = jsonb_build_array(
+                messages->0, messages->0, messages->1, messages->1, messages->1
+            )
+            WHERE session_id = $1
+            "#,
+        )
+        .bind(session_id)
+        .execute(&pool)
+        .await?;
+
+        let deduping = SessionManager::new(pool.clone()).with_history_dedup(true);
+        let context = deduping
+            .get_or_create_session(&session_id.to_string())
+            .await?;
+        assert_eq!(context.messages.len(), 2);
+
+        // The one-time cleanup rewrites the stored row too
+        let plain = SessionManager::new(pool.clone());
+        assert_eq!(plain.dedup_stored_histories().await?, 1);
+        let context = plain.get_or_create_session(&session_id.to_string()).await?;
+        assert_eq!(context.messages.len(), 2);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres with pgvector at DATABASE_URL"]
+    async fn import_queues_messages_for_the_indexer(pool: PgPool) -> Result<()> {
+        let manager = SessionManager::new(pool.clone());
+        let session_id = Uuid::new_v4();
+        let messages: Vec<ChatMessage> = serde_json::from_value(serde_json::json!([
+            {"role": "system", "content": "Be brief"},
+            {"role": "user", "content": "Any salons open on Sunday?"},
+            {"role": "assistant", "content": "Yes, two."}
+        ]))?;
+
+        let (context, queued) = manager
+            .import_session(
+                &session_id.to_string(),
+                messages,
+                None,
+                &["user", "assistant"],
+            )
+            .await?;
+        assert_eq!(context.messages.len(), 3);
+        assert_eq!(queued, 2);
+
+        let roles: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT ce.role
+            FROM conversation_embeddings ce
+            JOIN conversations c ON c.id = ce.conversation_id
+            WHERE c.session_id = $1 AND ce.embedding IS NULL
+            ORDER BY ce.role
+            "#,
+        )
+        .bind(session_id)
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(roles, vec!["assistant", "user"]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres with pgvector at DATABASE_URL"]
+    async fn expiry_on_load_starts_stale_sessions_over(pool: PgPool) -> Result<()> {
+        let manager = SessionManager::new(pool.clone()).with_expiry(30, HashMap::new());
+        let stale = Uuid::new_v4();
+        let recent = Uuid::new_v4();
+        seed(&manager, stale).await?;
+        seed(&manager, recent).await?;
+        sqlx::query(
+            "UPDATE conversations SET updated_at = NOW() - INTERVAL '2 hours' \
+             WHERE session_id = $1",
+        )
+        .bind(stale)
+        .execute(&pool)
+        .await?;
+
+        let context = manager.get_or_create_session(&stale.to_string()).await?;
+        assert!(context.messages.is_empty());
+        let context = manager.get_or_create_session(&recent.to_string()).await?;
+        assert_eq!(context.messages.len(), 2);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres with pgvector at DATABASE_URL"]
+    async fn stale_sessions_resume_without_expiry(pool: PgPool) -> Result<()> {
+        let manager = SessionManager::new(pool.clone());
+        let stale = Uuid::new_v4();
+        seed(&manager, stale).await?;
+        sqlx::query(
+            "UPDATE conversations SET updated_at = NOW() - INTERVAL '2 hours' \
+             WHERE session_id = $1",
+        )
+        .bind(stale)
+        .execute(&pool)
+        .await?;
+
+        let context = manager.get_or_create_session(&stale.to_string()).await?;
+        assert_eq!(context.messages.len(), 2);
         Ok(())
     }
 }
@@ -1,3 +1,5 @@
+pub mod archive;
 pub mod manager;
 
+pub use archive::ConversationArchive;
 pub use manager::SessionManager;
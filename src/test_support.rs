@@ -0,0 +1,99 @@
+//! Fakes of the agent's upstreams for tests: an in-process MCP server and
+//! local HTTP stand-ins for the LLM and embedding APIs.
+
+use crate::mcp::models::{McpNotification, McpRequest, McpResponse};
+use crate::mcp::transport::McpTransport;
+use anyhow::Result;
+use axum::response::IntoResponse;
+use futures::future::BoxFuture;
+use serde_json::json;
+use std::time::Duration;
+
+/// Serves `app` on a free local port and returns its base URL.
+pub async fn serve(app: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    base_url
+}
+
+/// In-process MCP server with one `lookup` tool whose calls take `delay`
+/// and echo their arguments.
+pub struct FakeMcp {
+    pub delay: Duration,
+}
+
+impl McpTransport for FakeMcp {
+    fn send_request<'a>(&'a self, request: &'a McpRequest) -> BoxFuture<'a, Result<McpResponse>> {
+        Box::pin(async move {
+            let result = match request.method.as_str() {
+                "tools/list" => json!({
+                    "tools": [{
+                        "name": "lookup",
+                        "description": "Looks something up",
+                        "inputSchema": {"type": "object"}
+                    }]
+                }),
+                "tools/call" => {
+                    tokio::time::sleep(self.delay).await;
+                    let text = format!("found {}", request.params["arguments"]);
+                    json!({ "content": [{"type": "text", "text": text}] })
+                }
+                _ => json!({}),
+            };
+            Ok(serde_json::from_value(json!({
+                "jsonrpc": "2.0",
+                "id": request.id,
+                "result": result
+            }))?)
+        })
+    }
+
+    fn send_notification<'a>(
+        &'a self,
+        _notification: &'a McpNotification,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// An OpenAI-compatible endpoint that answers every request with `reply`,
+/// as a server-sent event stream when the request asks for one.
+pub fn openai_reply(reply: &'static str) -> axum::Router {
+    axum::Router::new().fallback(
+        move |axum::Json(request): axum::Json<serde_json::Value>| async move {
+            if request["stream"].as_bool().unwrap_or(false) {
+                let chunk = json!({
+                    "choices": [{"delta": {"content": reply}, "finish_reason": "stop"}]
+                });
+                let body = format!("data: {}\n\ndata: [DONE]\n\n", chunk);
+                ([("content-type", "text/event-stream")], body).into_response()
+            } else {
+                axum::Json(json!({
+                    "choices": [{
+                        "message": {"content": reply},
+                        "finish_reason": "stop"
+                    }],
+                    "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}
+                }))
+                .into_response()
+            }
+        },
+    )
+}
+
+/// A Google embeddings endpoint returning `dimensions`-long vectors for both
+/// single and batch requests.
+pub fn google_embeddings(dimensions: usize) -> axum::Router {
+    axum::Router::new().fallback(
+        move |uri: axum::http::Uri, axum::Json(request): axum::Json<serde_json::Value>| async move {
+            let values = vec![0.1_f32; dimensions];
+            if uri.path().ends_with(":batchEmbedContents") {
+                let count = request["requests"].as_array().map_or(0, Vec::len);
+                axum::Json(json!({ "embeddings": vec![json!({"values": values}); count] }))
+            } else {
+                axum::Json(json!({ "embedding": {"values": values} }))
+            }
+        },
+    )
+}
@@ -1,78 +1,394 @@
-use anyhow::Result;
+use crate::models::RagHit;
+use anyhow::{anyhow, Result};
 use sqlx::PgPool;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use tracing::warn;
+
+/// How many nearest neighbours are re-ranked per result when recency
+/// weighting is on.
+const RECENCY_CANDIDATE_FACTOR: usize = 4;
 
 pub struct VectorService {
     pool: PgPool,
+    /// Pool for RAG retrieval; the primary unless a replica is set.
+    read_pool: PgPool,
+    recency_half_life_hours: Option<f64>,
+    /// Skip (rather than fail) vector operations whose dimension doesn't
+    /// match the `embedding` column.
+    skip_dimension_mismatch: bool,
+    /// Cached `embedding` column dimension; `None` until looked up.
+    column_dimensions: RwLock<Option<Option<usize>>>,
+    /// Dimension embeddings must have when the column doesn't constrain it.
+    expected_dimensions: Option<usize>,
+    reindex_needed: AtomicBool,
 }
 
 impl VectorService {
     pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+        Self {
+            read_pool: pool.clone(),
+            pool,
+            recency_half_life_hours: None,
+            skip_dimension_mismatch: true,
+            column_dimensions: RwLock::new(None),
+            expected_dimensions: None,
+            reindex_needed: AtomicBool::new(false),
+        }
+    }
+
+    /// Run RAG retrieval against a read replica.
+    pub fn with_read_pool(mut self, pool: PgPool) -> Self {
+        self.read_pool = pool;
+        self
+    }
+
+    pub fn with_dimension_mismatch_skip(mut self, skip: bool) -> Self {
+        self.skip_dimension_mismatch = skip;
+        self
+    }
+
+    /// Validates embeddings against this dimension (the embedding model's)
+    /// when the `embedding` column is declared without one.
+    pub fn with_expected_dimensions(mut self, dimensions: usize) -> Self {
+        self.expected_dimensions = Some(dimensions);
+        self
+    }
+
+    /// Compares the column dimension with the expected one, so a model
+    /// change is reported at startup rather than on the first insert.
+    /// Returns an error on a mismatch unless mismatches are skipped, in
+    /// which case it warns and flags a reindex.
+    pub async fn check_dimensions(&self) -> Result<()> {
+        let (Some(column), Some(expected)) =
+            (self.column_dimensions().await?, self.expected_dimensions)
+        else {
+            return Ok(());
+        };
+        if column == expected {
+            return Ok(());
+        }
+        self.reindex_needed.store(true, Ordering::Relaxed);
+        let message = format!(
+            "The embedding model produces {} dimensions but conversation_embeddings.embedding \
+             stores {}; migrate the column or switch EMBEDDING_MODEL back",
+            expected, column
+        );
+        if self.skip_dimension_mismatch {
+            warn!("{}", message);
+            Ok(())
+        } else {
+            Err(anyhow!(message))
+        }
+    }
+
+    /// Set once an embedding didn't fit the `embedding` column, which usually
+    /// means the embedding model changed and stored vectors need rebuilding.
+    pub fn reindex_needed(&self) -> bool {
+        self.reindex_needed.load(Ordering::Relaxed)
+    }
+
+    /// Looks the column dimension up again and clears the reindex flag once
+    /// embeddings fit it, e.g. after the column was migrated to the new
+    /// model's dimension. Returns whether a reindex is still needed.
+    pub async fn recheck_dimensions(&self) -> Result<bool> {
+        let column = self.query_column_dimensions().await?;
+        *self.column_dimensions.write().unwrap() = Some(column);
+        let fits = match (column, self.expected_dimensions) {
+            (Some(column), Some(expected)) => column == expected,
+            _ => true,
+        };
+        if fits {
+            self.reindex_needed.store(false, Ordering::Relaxed);
+        }
+        Ok(!fits)
+    }
+
+    /// Dimension of the `embedding` column (`vector(N)`), looked up once.
+    /// `None` if the column is unconstrained.
+    async fn column_dimensions(&self) -> Result<Option<usize>> {
+        if let Some(dimensions) = *self.column_dimensions.read().unwrap() {
+            return Ok(dimensions);
+        }
+
+        let dimensions = self.query_column_dimensions().await?;
+        *self.column_dimensions.write().unwrap() = Some(dimensions);
+        Ok(dimensions)
+    }
+
+    async fn query_column_dimensions(&self) -> Result<Option<usize>> {
+        let typmod = sqlx::query_scalar::<_, i32>(
+            r#"
+            SELECT atttypmod
+            FROM pg_attribute
+            WHERE attrelid = 'conversation_embeddings'::regclass
+              AND attname = 'embedding'
+            "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(usize::try_from(typmod).ok().filter(|dims| *dims > 0))
+    }
+
+    /// Checks an embedding against the column dimension. On a mismatch it
+    /// flags a reindex and, depending on configuration, returns `false` (skip
+    /// the operation) or an error.
+    async fn fits_column(&self, embedding: &[f32]) -> Result<bool> {
+        let Some(expected) = self.column_dimensions().await?.or(self.expected_dimensions) else {
+            return Ok(true);
+        };
+        if embedding.len() == expected {
+            return Ok(true);
+        }
+
+        self.reindex_needed.store(true, Ordering::Relaxed);
+        let message = format!(
+            "Embedding has {} dimensions but conversation_embeddings.embedding expects {}; \
+             the embedding model probably changed and stored vectors need reindexing",
+            embedding.len(),
+            expected
+        );
+        if self.skip_dimension_mismatch {
+            warn!("{}", message);
+            Ok(false)
+        } else {
+            Err(anyhow!(message))
+        }
+    }
+
+    /// Discounts older snippets: a snippet's similarity is halved for every
+    /// `half_life_hours` of age.
+    pub fn with_recency_weighting(mut self, half_life_hours: f64) -> Self {
+        self.recency_half_life_hours = Some(half_life_hours);
+        self
+    }
+
+    /// Stores a message without its embedding, for the background indexer to
+    /// fill in later. Until then it is invisible to RAG. `role` is the role of
+    /// the message the text came from (`user`, `assistant` or `tool`).
+    pub async fn queue_conversation_embedding(
+        &self,
+        conversation_id: &str,
+        role: &str,
+        message_text: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO conversation_embeddings (conversation_id, message_text, role)
+            VALUES (
+                (SELECT id FROM conversations WHERE session_id::text = $1 LIMIT 1),
+                $2,
+                $3
+            )
+            "#,
+        )
+        .bind(conversation_id)
+        .bind(message_text)
+        .bind(role)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Messages still waiting for an embedding that have failed fewer than
+    /// `max_attempts` times, least-tried first so earlier failures don't keep
+    /// blocking new messages.
+    pub async fn unindexed_embeddings(
+        &self,
+        limit: usize,
+        max_attempts: u32,
+    ) -> Result<Vec<(String, String)>> {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            r#"
+            SELECT id::text, message_text
+            FROM conversation_embeddings
+            WHERE embedding IS NULL AND index_attempts < $2
+            ORDER BY index_attempts, created_at
+            LIMIT $1
+            "#,
+        )
+        .bind(limit as i64)
+        .bind(max_attempts as i32)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Counts a failed indexing attempt for each message. Returns how many
+    /// reached `max_attempts` and won't be picked up again.
+    pub async fn record_index_failure(&self, ids: &[String], max_attempts: u32) -> Result<u64> {
+        let exhausted = sqlx::query_scalar::<_, i64>(
+            r#"
+            WITH updated AS (
+                UPDATE conversation_embeddings
+                SET index_attempts = index_attempts + 1
+                WHERE id::text = ANY($1)
+                RETURNING index_attempts
+            )
+            SELECT COUNT(*) FROM updated WHERE index_attempts >= $2
+            "#,
+        )
+        .bind(ids)
+        .bind(max_attempts as i32)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(exhausted as u64)
+    }
+
+    pub async fn set_embedding(&self, id: &str, embedding: &[f32]) -> Result<()> {
+        if !self.fits_column(embedding).await? {
+            return Ok(());
+        }
+        sqlx::query(
+            "UPDATE conversation_embeddings SET embedding = $2::vector WHERE id::text = $1",
+        )
+        .bind(id)
+        .bind(to_pgvector(embedding))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
     }
 
     pub async fn store_conversation_embedding(
         &self,
         conversation_id: &str,
+        role: &str,
         message_text: &str,
         embedding: &[f32],
     ) -> Result<()> {
-        // Convert f32 slice to pgvector format
-        let embedding_str = format!(
-            "[{}]",
-            embedding
-                .iter()
-                .map(|v| v.to_string())
-                .collect::<Vec<_>>()
-                .join(",")
-        );
+        if !self.fits_column(embedding).await? {
+            // Keep the text so a reindex can embed it later
+            return self
+                .queue_conversation_embedding(conversation_id, role, message_text)
+                .await;
+        }
+        let embedding_str = to_pgvector(embedding);
 
         sqlx::query(
             r#"
-            INSERT INTO conversation_embeddings (conversation_id, message_text, embedding)
+            INSERT INTO conversation_embeddings (conversation_id, message_text, embedding, role)
             VALUES (
                 (SELECT id FROM conversations WHERE session_id::text = $1 LIMIT 1),
                 $2,
-                $3::vector
+                $3::vector,
+                $4
             )
             "#,
         )
         .bind(conversation_id)
         .bind(message_text)
         .bind(embedding_str)
+        .bind(role)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
+    /// Returns the stored messages most similar to `query_embedding`, best
+    /// match first (nothing if its dimension doesn't fit the column and
+    /// mismatches are skipped). Messages with a cosine similarity below
+    /// `min_similarity` are left out, however few remain. When `session_id`
+    /// is set, only that session's embeddings are searched; a non-empty
+    /// `roles` limits the search to messages with those roles. With recency
+    /// weighting, the nearest candidates are re-ranked by
+    /// `similarity * 0.5^(age / half_life)`, which is also the reported score.
     pub async fn retrieve_context_for_rag(
         &self,
         query_embedding: &[f32],
         limit: usize,
-    ) -> Result<Vec<String>> {
-        let embedding_str = format!(
-            "[{}]",
-            query_embedding
-                .iter()
-                .map(|v| v.to_string())
-                .collect::<Vec<_>>()
-                .join(",")
-        );
+        session_id: Option<&str>,
+        roles: &[String],
+        min_similarity: f32,
+    ) -> Result<Vec<RagHit>> {
+        if !self.fits_column(query_embedding).await? {
+            return Ok(Vec::new());
+        }
+        let embedding_str = to_pgvector(query_embedding);
 
-        let rows = sqlx::query_as::<_, (String, f64)>(
+        let half_life_secs = self.recency_half_life_hours.map(|hours| hours * 3600.0);
+        let candidates = if half_life_secs.is_some() {
+            limit * RECENCY_CANDIDATE_FACTOR
+        } else {
+            limit
+        };
+
+        let rows = sqlx::query_as::<
+            _,
+            (
+                String,
+                f64,
+                String,
+                Option<String>,
+                Option<chrono::NaiveDateTime>,
+            ),
+        >(
             r#"
-            SELECT message_text, 
-                   1 - (embedding <=> $1::vector) as similarity
-            FROM conversation_embeddings
-            ORDER BY embedding <=> $1::vector
+            SELECT message_text,
+                   CASE
+                       WHEN $4::float8 IS NULL THEN similarity
+                       ELSE similarity * power(
+                           0.5,
+                           EXTRACT(EPOCH FROM (NOW() - COALESCE(created_at, NOW())))::float8 / $4
+                       )
+                   END AS score,
+                   role,
+                   session_id,
+                   created_at
+            FROM (
+                SELECT ce.message_text,
+                       1 - (ce.embedding <=> $1::vector) as similarity,
+                       ce.role,
+                       c.session_id::text AS session_id,
+                       ce.created_at
+                FROM conversation_embeddings ce
+                JOIN conversations c ON c.id = ce.conversation_id
+                WHERE ce.embedding IS NOT NULL
+                  AND ($3::text IS NULL OR c.session_id::text = $3)
+                  AND (cardinality($6::text[]) = 0 OR ce.role = ANY($6))
+                  AND 1 - (ce.embedding <=> $1::vector) >= $7
+                ORDER BY ce.embedding <=> $1::vector
+                LIMIT $5
+            ) nearest
+            ORDER BY score DESC
             LIMIT $2
             "#,
         )
         .bind(embedding_str)
         .bind(limit as i64)
-        .fetch_all(&self.pool)
+        .bind(session_id)
+        .bind(half_life_secs)
+        .bind(candidates as i64)
+        .bind(roles)
+        .bind(min_similarity as f64)
+        .fetch_all(&self.read_pool)
         .await?;
 
-        Ok(rows.into_iter().map(|(text, _)| text).collect())
+        Ok(rows
+            .into_iter()
+            .map(|(text, score, role, session_id, created_at)| RagHit {
+                text,
+                score,
+                role,
+                session_id,
+                created_at,
+            })
+            .collect())
     }
 }
+
+/// Formats an embedding as a pgvector literal, e.g. `[0.1,0.2]`.
+fn to_pgvector(embedding: &[f32]) -> String {
+    format!(
+        "[{}]",
+        embedding
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}